@@ -21,3 +21,19 @@ pub fn handle_hdel(key: &str, field: &str) {
         field: field.to_string(),
     });
 }
+
+pub fn handle_hincrby(key: &str, field: &str, by: i64) {
+    send_request(ClientCommand::HIncrBy {
+        key: key.to_string(),
+        field: field.to_string(),
+        by,
+    });
+}
+
+pub fn handle_hincrbyfloat(key: &str, field: &str, by: f64) {
+    send_request(ClientCommand::HIncrByFloat {
+        key: key.to_string(),
+        field: field.to_string(),
+        by,
+    });
+}
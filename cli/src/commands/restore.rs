@@ -0,0 +1,72 @@
+use chrono::{DateTime, Local, NaiveDateTime, NaiveTime, TimeZone};
+use core::backup::RecoveryTarget;
+use core::db::VaporDB;
+use core::options::VaporDBOptions;
+use std::path::{Path, PathBuf};
+
+/// Parses `--until`: `seq:N` for a sequence number, or a time given as an
+/// RFC 3339 timestamp, `YYYY-MM-DD HH:MM[:SS]` or today's `HH:MM[:SS]`, the
+/// last two in local time.
+pub fn parse_until(until: &str) -> Result<RecoveryTarget, String> {
+    if let Some(seq) = until.strip_prefix("seq:") {
+        return seq
+            .parse()
+            .map(RecoveryTarget::Sequence)
+            .map_err(|e| format!("bad sequence number '{seq}': {e}"));
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(until) {
+        return Ok(RecoveryTarget::Time(time.timestamp_millis() as u64));
+    }
+
+    let mut local = None;
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(until, format) {
+            local = Some(time);
+        }
+    }
+    for format in ["%H:%M:%S", "%H:%M"] {
+        if let Ok(time) = NaiveTime::parse_from_str(until, format) {
+            local = Some(Local::now().date_naive().and_time(time));
+        }
+    }
+    let time = local
+        .and_then(|time| Local.from_local_datetime(&time).earliest())
+        .ok_or_else(|| format!("cannot read '{until}' as seq:N or a time"))?;
+    Ok(RecoveryTarget::Time(time.timestamp_millis() as u64))
+}
+
+pub fn handle_restore(
+    backup: &Path,
+    until: RecoveryTarget,
+    archive: Option<&Path>,
+    wal: &Path,
+    target: &Path,
+) {
+    // Segments are named after the log file, in the archive as in place
+    let mut logs = vec![wal.to_path_buf()];
+    if let (Some(archive), Some(name)) = (archive, wal.file_name()) {
+        logs.insert(0, archive.join(name));
+    }
+
+    let options = VaporDBOptions {
+        sst_dir: target.join("sstables"),
+        ..Default::default()
+    };
+    let target_wal: PathBuf = target.join("vapordb.wal");
+    match VaporDB::restore(
+        backup,
+        &logs,
+        until,
+        target_wal.to_str().expect("target path is not UTF-8"),
+        options,
+    ) {
+        Ok((_, report)) => println!(
+            "Restored {} writes on top of the backup at sequence {}; now at sequence {}",
+            report.replayed, report.base_sequence, report.last_sequence
+        ),
+        Err(e) => {
+            eprintln!("Restore failed: {e}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -1,8 +1,11 @@
 use std::sync::{Arc, Mutex};
-use warp::Filter;
+use warp::{http::StatusCode, Filter};
 use core::db::VaporDB;
-use core::command::Command;
+use core::error::VaporDBError;
+use core::command::{Command, ZRange};
 use cli::utils::{ClientCommand, Response};
+use core::storage::sorted_set::parse_scored_members;
+use core::storage::stream::{parse_ids, parse_range_end, parse_range_start, StreamId};
 
 pub fn start_server() {
     tokio_main();
@@ -86,12 +89,133 @@ async fn handle_command(
             None
         }
         ClientCommand::SMembers { key } => db.execute(Command::SMembers(key.to_string())).unwrap_or(None),
-    };
 
-    let resp = Response {
-        result,
-        error: None,
+        // === Expiration ===
+        // A failed write must not read as a key without an expiry
+        ClientCommand::PSetEx { key, value, ttl_ms } => {
+            let result = db.execute(Command::PSetEx(key, ttl_ms, value)).map(|_| None);
+            return Ok(reply(result));
+        }
+        ClientCommand::Expire { key, ttl_secs, condition } => {
+            return Ok(reply(db.execute(Command::Expire(key, ttl_secs, condition))));
+        }
+        ClientCommand::PExpire { key, ttl_ms, condition } => {
+            return Ok(reply(db.execute(Command::PExpire(key, ttl_ms, condition))));
+        }
+        ClientCommand::ExpireAt { key, timestamp, condition } => {
+            return Ok(reply(db.execute(Command::ExpireAt(key, timestamp, condition))));
+        }
+        ClientCommand::PExpireAt { key, timestamp_ms, condition } => {
+            return Ok(reply(db.execute(Command::PExpireAt(key, timestamp_ms, condition))));
+        }
+        ClientCommand::Ttl { key } => return Ok(reply(db.execute(Command::Ttl(key)))),
+        ClientCommand::PTtl { key } => return Ok(reply(db.execute(Command::PTtl(key)))),
+        ClientCommand::Persist { key } => return Ok(reply(db.execute(Command::Persist(key)))),
+
+        // === Sorted set ===
+        // Malformed members or a key of another type are reported, not
+        // read as an empty set
+        ClientCommand::ZAdd { key, members, flags } => {
+            let result = parse_scored_members(members)
+                .and_then(|members| db.execute(Command::ZAdd(key, members, flags)));
+            return Ok(reply(result));
+        }
+        ClientCommand::ZRem { key, members } => return Ok(reply(db.execute(Command::ZRem(key, members)))),
+        ClientCommand::ZScore { key, member } => {
+            return Ok(reply(db.execute(Command::ZScore(key, member))));
+        }
+        ClientCommand::ZRank { key, member } => return Ok(reply(db.execute(Command::ZRank(key, member)))),
+        ClientCommand::ZRange { key, by, rev, limit, with_scores } => {
+            let range = ZRange { by, rev, limit, with_scores };
+            return Ok(reply(db.execute(Command::ZRange(key, range))));
+        }
+        ClientCommand::ZPopMin { key, count } => {
+            return Ok(reply(db.execute(Command::ZPopMin(key, count.unwrap_or(1)))));
+        }
+        ClientCommand::ZPopMax { key, count } => {
+            return Ok(reply(db.execute(Command::ZPopMax(key, count.unwrap_or(1)))));
+        }
+        ClientCommand::ZCard { key } => return Ok(reply(db.execute(Command::ZCard(key)))),
+
+        // === Stream ===
+        // A malformed ID or a missing group is the client's error
+        ClientCommand::XAdd { key, id, fields, max_len } => {
+            let result = id.as_deref().map(str::parse::<StreamId>).transpose()
+                .and_then(|id| db.execute(Command::XAdd(key, id, fields, max_len)));
+            return Ok(reply(result));
+        }
+        ClientCommand::XRange { key, start, end, rev, count } => {
+            let result = parse_range_start(&start).and_then(|start| {
+                let end = parse_range_end(&end)?;
+                if rev {
+                    db.execute(Command::XRevRange(key, start, end, count))
+                } else {
+                    db.execute(Command::XRange(key, start, end, count))
+                }
+            });
+            return Ok(reply(result));
+        }
+        ClientCommand::XLen { key } => return Ok(reply(db.execute(Command::XLen(key)))),
+        ClientCommand::XGroupCreate { key, group, start, mkstream } => {
+            let result = start.as_deref().map(str::parse::<StreamId>).transpose()
+                .and_then(|start| db.execute(Command::XGroupCreate(key, group, start, mkstream)));
+            return Ok(reply(result));
+        }
+        ClientCommand::XReadGroup { key, group, consumer, id, count } => {
+            let result = id.as_deref().map(str::parse::<StreamId>).transpose()
+                .and_then(|id| db.execute(Command::XReadGroup(key, group, consumer, id, count)));
+            return Ok(reply(result));
+        }
+        ClientCommand::XAck { key, group, ids } => {
+            let result = parse_ids(&ids).and_then(|ids| db.execute(Command::XAck(key, group, ids)));
+            return Ok(reply(result));
+        }
+        ClientCommand::XPending { key, group } => {
+            return Ok(reply(db.execute(Command::XPending(key, group))));
+        }
+        ClientCommand::XClaim { key, group, consumer, min_idle_ms, ids } => {
+            let result = parse_ids(&ids)
+                .and_then(|ids| db.execute(Command::XClaim(key, group, consumer, min_idle_ms, ids)));
+            return Ok(reply(result));
+        }
+
+        // === Counters ===
+        // A value that is not a number, or a result that overflows, has to
+        // reach the client rather than read as a missing key
+        ClientCommand::Incr { key } => return Ok(reply(db.execute(Command::Incr(key)))),
+        ClientCommand::Decr { key } => return Ok(reply(db.execute(Command::Decr(key)))),
+        ClientCommand::IncrBy { key, by } => return Ok(reply(db.execute(Command::IncrBy(key, by)))),
+        ClientCommand::IncrByFloat { key, by } => {
+            return Ok(reply(db.execute(Command::IncrByFloat(key, by))));
+        }
+        ClientCommand::HIncrBy { key, field, by } => {
+            return Ok(reply(db.execute(Command::HIncrBy(key, field, by))));
+        }
+        ClientCommand::HIncrByFloat { key, field, by } => {
+            return Ok(reply(db.execute(Command::HIncrByFloat(key, field, by))));
+        }
     };
 
-    Ok(warp::reply::json(&resp))
+    Ok(reply(Ok(result)))
+}
+
+fn reply(result: Result<Option<String>, VaporDBError>) -> warp::reply::WithStatus<warp::reply::Json> {
+    match result {
+        Ok(result) => {
+            let json = warp::reply::json(&Response { result, error: None });
+            warp::reply::with_status(json, StatusCode::OK)
+        }
+        Err(e) => {
+            let json = warp::reply::json(&Response {
+                result: None,
+                error: Some(format!("VaporDB error: {:?}", e)),
+            });
+            let status = if e.is_client_error() {
+                StatusCode::BAD_REQUEST
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            warp::reply::with_status(json, status)
+        }
+    }
 }
@@ -0,0 +1,84 @@
+use crate::utils::{send_request, ClientCommand};
+
+pub fn handle_xadd(key: &str, field_values: &[String], id: Option<String>, max_len: Option<usize>) {
+    if !field_values.len().is_multiple_of(2) {
+        eprintln!("xadd takes field value pairs");
+        return;
+    }
+    let fields = field_values
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect();
+    send_request(ClientCommand::XAdd {
+        key: key.to_string(),
+        id,
+        fields,
+        max_len,
+    });
+}
+
+pub fn handle_xrange(key: &str, start: &str, end: &str, rev: bool, count: Option<usize>) {
+    send_request(ClientCommand::XRange {
+        key: key.to_string(),
+        start: start.to_string(),
+        end: end.to_string(),
+        rev,
+        count,
+    });
+}
+
+pub fn handle_xlen(key: &str) {
+    send_request(ClientCommand::XLen {
+        key: key.to_string(),
+    });
+}
+
+pub fn handle_xgroup_create(key: &str, group: &str, start: Option<String>, mkstream: bool) {
+    send_request(ClientCommand::XGroupCreate {
+        key: key.to_string(),
+        group: group.to_string(),
+        start,
+        mkstream,
+    });
+}
+
+pub fn handle_xreadgroup(
+    key: &str,
+    group: &str,
+    consumer: &str,
+    id: Option<String>,
+    count: Option<usize>,
+) {
+    send_request(ClientCommand::XReadGroup {
+        key: key.to_string(),
+        group: group.to_string(),
+        consumer: consumer.to_string(),
+        id,
+        count,
+    });
+}
+
+pub fn handle_xack(key: &str, group: &str, ids: &[String]) {
+    send_request(ClientCommand::XAck {
+        key: key.to_string(),
+        group: group.to_string(),
+        ids: ids.to_vec(),
+    });
+}
+
+pub fn handle_xpending(key: &str, group: &str) {
+    send_request(ClientCommand::XPending {
+        key: key.to_string(),
+        group: group.to_string(),
+    });
+}
+
+pub fn handle_xclaim(key: &str, group: &str, consumer: &str, min_idle_ms: u64, ids: &[String]) {
+    send_request(ClientCommand::XClaim {
+        key: key.to_string(),
+        group: group.to_string(),
+        consumer: consumer.to_string(),
+        min_idle_ms,
+        ids: ids.to_vec(),
+    });
+}
@@ -1,4 +1,5 @@
 use crate::utils::{send_request, ClientCommand};
+use core::command::ExpireCondition;
 
 pub fn handle_get(key: &str) {
     send_request(ClientCommand::Get { key: key.to_string() });
@@ -19,3 +20,71 @@ pub fn handle_set_expiring(key: &str, value: &str, ttl_secs: u64) {
         ttl_secs,
     });
 }
+
+pub fn handle_psetex(key: &str, value: &str, ttl_ms: u64) {
+    send_request(ClientCommand::PSetEx {
+        key: key.to_string(),
+        value: value.to_string(),
+        ttl_ms,
+    });
+}
+
+pub fn handle_expire(key: &str, ttl_secs: u64, condition: Option<ExpireCondition>) {
+    send_request(ClientCommand::Expire {
+        key: key.to_string(),
+        ttl_secs,
+        condition,
+    });
+}
+
+pub fn handle_pexpire(key: &str, ttl_ms: u64, condition: Option<ExpireCondition>) {
+    send_request(ClientCommand::PExpire {
+        key: key.to_string(),
+        ttl_ms,
+        condition,
+    });
+}
+
+pub fn handle_expireat(key: &str, timestamp: u64, condition: Option<ExpireCondition>) {
+    send_request(ClientCommand::ExpireAt {
+        key: key.to_string(),
+        timestamp,
+        condition,
+    });
+}
+
+pub fn handle_pexpireat(key: &str, timestamp_ms: u64, condition: Option<ExpireCondition>) {
+    send_request(ClientCommand::PExpireAt {
+        key: key.to_string(),
+        timestamp_ms,
+        condition,
+    });
+}
+
+pub fn handle_ttl(key: &str) {
+    send_request(ClientCommand::Ttl { key: key.to_string() });
+}
+
+pub fn handle_pttl(key: &str) {
+    send_request(ClientCommand::PTtl { key: key.to_string() });
+}
+
+pub fn handle_persist(key: &str) {
+    send_request(ClientCommand::Persist { key: key.to_string() });
+}
+
+pub fn handle_incr(key: &str) {
+    send_request(ClientCommand::Incr { key: key.to_string() });
+}
+
+pub fn handle_decr(key: &str) {
+    send_request(ClientCommand::Decr { key: key.to_string() });
+}
+
+pub fn handle_incrby(key: &str, by: i64) {
+    send_request(ClientCommand::IncrBy { key: key.to_string(), by });
+}
+
+pub fn handle_incrbyfloat(key: &str, by: f64) {
+    send_request(ClientCommand::IncrByFloat { key: key.to_string(), by });
+}
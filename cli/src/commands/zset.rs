@@ -0,0 +1,97 @@
+use crate::utils::{send_request, ClientCommand};
+use core::command::ZAddFlags;
+use core::storage::sorted_set::{LexBound, RangeBy, ScoreBound};
+
+pub fn handle_zadd(key: &str, score_members: &[String], flags: ZAddFlags) {
+    if !score_members.len().is_multiple_of(2) {
+        eprintln!("zadd takes score member pairs");
+        return;
+    }
+    let members = score_members
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect();
+    send_request(ClientCommand::ZAdd {
+        key: key.to_string(),
+        members,
+        flags,
+    });
+}
+
+pub fn handle_zrem(key: &str, members: &[String]) {
+    send_request(ClientCommand::ZRem {
+        key: key.to_string(),
+        members: members.to_vec(),
+    });
+}
+
+pub fn handle_zscore(key: &str, member: &str) {
+    send_request(ClientCommand::ZScore {
+        key: key.to_string(),
+        member: member.to_string(),
+    });
+}
+
+pub fn handle_zrank(key: &str, member: &str) {
+    send_request(ClientCommand::ZRank {
+        key: key.to_string(),
+        member: member.to_string(),
+    });
+}
+
+/// Reads ZRANGE's bounds as indices, or as scores or members.
+pub fn parse_range(start: &str, stop: &str, by_score: bool, by_lex: bool) -> Result<RangeBy, String> {
+    let range = if by_score {
+        RangeBy::Score(
+            start.parse::<ScoreBound>().map_err(|e| e.to_string())?,
+            stop.parse::<ScoreBound>().map_err(|e| e.to_string())?,
+        )
+    } else if by_lex {
+        RangeBy::Lex(
+            start.parse::<LexBound>().map_err(|e| e.to_string())?,
+            stop.parse::<LexBound>().map_err(|e| e.to_string())?,
+        )
+    } else {
+        RangeBy::Index(
+            start.parse().map_err(|_| format!("invalid index '{start}'"))?,
+            stop.parse().map_err(|_| format!("invalid index '{stop}'"))?,
+        )
+    };
+    Ok(range)
+}
+
+pub fn handle_zrange(
+    key: &str,
+    by: RangeBy,
+    rev: bool,
+    limit: Option<(usize, usize)>,
+    with_scores: bool,
+) {
+    send_request(ClientCommand::ZRange {
+        key: key.to_string(),
+        by,
+        rev,
+        limit,
+        with_scores,
+    });
+}
+
+pub fn handle_zpopmin(key: &str, count: Option<usize>) {
+    send_request(ClientCommand::ZPopMin {
+        key: key.to_string(),
+        count,
+    });
+}
+
+pub fn handle_zpopmax(key: &str, count: Option<usize>) {
+    send_request(ClientCommand::ZPopMax {
+        key: key.to_string(),
+        count,
+    });
+}
+
+pub fn handle_zcard(key: &str) {
+    send_request(ClientCommand::ZCard {
+        key: key.to_string(),
+    });
+}
@@ -1,4 +1,5 @@
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 
 mod utils;
 mod commands {
@@ -6,7 +7,10 @@ mod commands {
     pub mod hash;
     pub mod list;
     pub mod set;
+    pub mod zset;
+    pub mod stream;
     pub mod start;
+    pub mod restore;
 }
 
 #[derive(Parser)]
@@ -28,14 +32,111 @@ enum Commands {
         #[arg(short, long)]
         ttl: u64,
     },
+    /// Like set-expiring, with the TTL in milliseconds
+    #[command(name = "psetex")]
+    PSetEx {
+        key: String,
+        value: String,
+        #[arg(short, long)]
+        ttl: u64,
+    },
+    /// Expires an existing key of any type after a TTL in seconds
+    Expire {
+        key: String,
+        #[arg(short, long)]
+        ttl: u64,
+        /// Only if the key has no expiry (nx), has one (xx), or the new one is later (gt) or earlier (lt)
+        condition: Option<core::command::ExpireCondition>,
+    },
+    /// Like expire, with the TTL in milliseconds
+    #[command(name = "pexpire")]
+    PExpire {
+        key: String,
+        #[arg(short, long)]
+        ttl: u64,
+        condition: Option<core::command::ExpireCondition>,
+    },
+    /// Expires an existing key at an epoch time in seconds
+    #[command(name = "expireat")]
+    ExpireAt {
+        key: String,
+        #[arg(long)]
+        at: u64,
+        condition: Option<core::command::ExpireCondition>,
+    },
+    /// Like expireat, with the time in epoch milliseconds
+    #[command(name = "pexpireat")]
+    PExpireAt {
+        key: String,
+        #[arg(long)]
+        at: u64,
+        condition: Option<core::command::ExpireCondition>,
+    },
+    /// Seconds until a key expires, -1 if it never does, -2 if it is missing
+    Ttl { key: String },
+    /// Like ttl, in milliseconds
+    #[command(name = "pttl")]
+    PTtl { key: String },
+    /// Removes a key's expiry
+    Persist { key: String },
+    /// Adds 1 to an integer, counting a missing key as 0
+    Incr { key: String },
+    /// Subtracts 1 from an integer
+    Decr { key: String },
+    #[command(name = "incrby")]
+    IncrBy {
+        key: String,
+        #[arg(allow_hyphen_values = true)]
+        by: i64,
+    },
+    #[command(name = "incrbyfloat")]
+    IncrByFloat {
+        key: String,
+        #[arg(allow_hyphen_values = true)]
+        by: f64,
+    },
 
     // Server
     Start,
 
+    // Point-in-time recovery
+    Restore {
+        /// Directory holding the base backup
+        #[arg(long)]
+        backup: PathBuf,
+        /// Last moment to recover: seq:N, HH:MM[:SS], YYYY-MM-DD HH:MM[:SS] or RFC 3339
+        #[arg(long, value_parser = commands::restore::parse_until)]
+        until: core::backup::RecoveryTarget,
+        /// Directory purged WAL segments were archived to
+        #[arg(long)]
+        archive: Option<PathBuf>,
+        /// Log of the damaged database, for writes not archived yet
+        #[arg(long, default_value = "vapordb.wal")]
+        wal: PathBuf,
+        /// Empty directory to restore into
+        #[arg(long)]
+        target: PathBuf,
+    },
+
     // Hash
     HSet { key: String, field: String, value: String },
     HGet { key: String, field: String },
     HDel { key: String, field: String },
+    /// Adds to an integer field, counting a missing one as 0
+    #[command(name = "hincrby")]
+    HIncrBy {
+        key: String,
+        field: String,
+        #[arg(allow_hyphen_values = true)]
+        by: i64,
+    },
+    #[command(name = "hincrbyfloat")]
+    HIncrByFloat {
+        key: String,
+        field: String,
+        #[arg(allow_hyphen_values = true)]
+        by: f64,
+    },
 
     // List
     LPush { key: String, value: String },
@@ -48,6 +149,140 @@ enum Commands {
     SAdd { key: String, value: String },
     SRem { key: String, value: String },
     SMembers { key: String },
+
+    // Sorted set
+    /// Adds members or updates their scores, given as score member pairs
+    #[command(name = "zadd")]
+    ZAdd {
+        key: String,
+        #[arg(required = true, allow_hyphen_values = true)]
+        score_members: Vec<String>,
+        /// Only add new members
+        #[arg(long)]
+        nx: bool,
+        /// Only update existing members
+        #[arg(long)]
+        xx: bool,
+        /// Only update members whose score would rise
+        #[arg(long)]
+        gt: bool,
+        /// Only update members whose score would fall
+        #[arg(long)]
+        lt: bool,
+        /// Add to the member's score instead of replacing it
+        #[arg(long)]
+        incr: bool,
+    },
+    #[command(name = "zrem")]
+    ZRem {
+        key: String,
+        #[arg(required = true)]
+        members: Vec<String>,
+    },
+    #[command(name = "zscore")]
+    ZScore { key: String, member: String },
+    /// How many members score lower than this one
+    #[command(name = "zrank")]
+    ZRank { key: String, member: String },
+    /// Members by index, or with --by-score or --by-lex between two bounds
+    #[command(name = "zrange")]
+    ZRange {
+        key: String,
+        #[arg(allow_hyphen_values = true)]
+        start: String,
+        #[arg(allow_hyphen_values = true)]
+        stop: String,
+        /// Bounds are scores: 1.5, (1.5 to exclude it, -inf or +inf
+        #[arg(long, conflicts_with = "by_lex")]
+        by_score: bool,
+        /// Bounds are members: [a, (a to exclude it, - or +
+        #[arg(long)]
+        by_lex: bool,
+        /// Highest first
+        #[arg(long)]
+        rev: bool,
+        #[arg(long, num_args = 2, value_names = ["OFFSET", "COUNT"])]
+        limit: Option<Vec<usize>>,
+        #[arg(long)]
+        with_scores: bool,
+    },
+    #[command(name = "zpopmin")]
+    ZPopMin { key: String, count: Option<usize> },
+    #[command(name = "zpopmax")]
+    ZPopMax { key: String, count: Option<usize> },
+    #[command(name = "zcard")]
+    ZCard { key: String },
+
+    // Stream
+    /// Appends an entry given as field value pairs and prints its ID
+    #[command(name = "xadd")]
+    XAdd {
+        key: String,
+        #[arg(required = true)]
+        field_values: Vec<String>,
+        /// The entry's ID, generated if not given
+        #[arg(long)]
+        id: Option<String>,
+        /// Trim the stream to this many entries
+        #[arg(long)]
+        max_len: Option<usize>,
+    },
+    /// Entries between two IDs, lowest first: - and + for the ends, (id to exclude it
+    #[command(name = "xrange")]
+    XRange {
+        key: String,
+        #[arg(allow_hyphen_values = true)]
+        start: String,
+        end: String,
+        /// Highest first
+        #[arg(long)]
+        rev: bool,
+        #[arg(long)]
+        count: Option<usize>,
+    },
+    #[command(name = "xlen")]
+    XLen { key: String },
+    /// Creates a consumer group that starts after the given ID, or after the last entry
+    #[command(name = "xgroup-create")]
+    XGroupCreate {
+        key: String,
+        group: String,
+        #[arg(long)]
+        start: Option<String>,
+        /// Create the stream if it is missing
+        #[arg(long)]
+        mkstream: bool,
+    },
+    /// New entries for the consumer, or with --id its pending entries after the ID
+    #[command(name = "xreadgroup")]
+    XReadGroup {
+        key: String,
+        group: String,
+        consumer: String,
+        #[arg(long)]
+        id: Option<String>,
+        #[arg(long)]
+        count: Option<usize>,
+    },
+    #[command(name = "xack")]
+    XAck {
+        key: String,
+        group: String,
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+    #[command(name = "xpending")]
+    XPending { key: String, group: String },
+    /// Takes over pending entries idle for at least min_idle_ms
+    #[command(name = "xclaim")]
+    XClaim {
+        key: String,
+        group: String,
+        consumer: String,
+        min_idle_ms: u64,
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
 }
 
 fn main() {
@@ -67,12 +302,53 @@ fn main() {
         Commands::SetExpiring { key, value, ttl } => {
             commands::string::handle_set_expiring(&key, &value, ttl);
         }
+        Commands::PSetEx { key, value, ttl } => {
+            commands::string::handle_psetex(&key, &value, ttl);
+        }
+        Commands::Expire { key, ttl, condition } => {
+            commands::string::handle_expire(&key, ttl, condition);
+        }
+        Commands::PExpire { key, ttl, condition } => {
+            commands::string::handle_pexpire(&key, ttl, condition);
+        }
+        Commands::ExpireAt { key, at, condition } => {
+            commands::string::handle_expireat(&key, at, condition);
+        }
+        Commands::PExpireAt { key, at, condition } => {
+            commands::string::handle_pexpireat(&key, at, condition);
+        }
+        Commands::Ttl { key } => {
+            commands::string::handle_ttl(&key);
+        }
+        Commands::PTtl { key } => {
+            commands::string::handle_pttl(&key);
+        }
+        Commands::Persist { key } => {
+            commands::string::handle_persist(&key);
+        }
+        Commands::Incr { key } => {
+            commands::string::handle_incr(&key);
+        }
+        Commands::Decr { key } => {
+            commands::string::handle_decr(&key);
+        }
+        Commands::IncrBy { key, by } => {
+            commands::string::handle_incrby(&key, by);
+        }
+        Commands::IncrByFloat { key, by } => {
+            commands::string::handle_incrbyfloat(&key, by);
+        }
 
         // Server
         Commands::Start => {
             commands::start::start_server();
         }
 
+        // Recovery
+        Commands::Restore { backup, until, archive, wal, target } => {
+            commands::restore::handle_restore(&backup, until, archive.as_deref(), &wal, &target);
+        }
+
         // Hash commands
         Commands::HSet { key, field, value } => {
             commands::hash::handle_hset(&key, &field, &value);
@@ -83,6 +359,12 @@ fn main() {
         Commands::HDel { key, field } => {
             commands::hash::handle_hdel(&key, &field);
         }
+        Commands::HIncrBy { key, field, by } => {
+            commands::hash::handle_hincrby(&key, &field, by);
+        }
+        Commands::HIncrByFloat { key, field, by } => {
+            commands::hash::handle_hincrbyfloat(&key, &field, by);
+        }
 
         // List commands
         Commands::LPush { key, value } => {
@@ -111,5 +393,64 @@ fn main() {
         Commands::SMembers { key } => {
             commands::set::handle_smembers(&key);
         }
+
+        // Sorted set commands
+        Commands::ZAdd { key, score_members, nx, xx, gt, lt, incr } => {
+            let flags = core::command::ZAddFlags { nx, xx, gt, lt, incr };
+            commands::zset::handle_zadd(&key, &score_members, flags);
+        }
+        Commands::ZRem { key, members } => {
+            commands::zset::handle_zrem(&key, &members);
+        }
+        Commands::ZScore { key, member } => {
+            commands::zset::handle_zscore(&key, &member);
+        }
+        Commands::ZRank { key, member } => {
+            commands::zset::handle_zrank(&key, &member);
+        }
+        Commands::ZRange { key, start, stop, by_score, by_lex, rev, limit, with_scores } => {
+            match commands::zset::parse_range(&start, &stop, by_score, by_lex) {
+                Ok(by) => {
+                    let limit = limit.map(|limit| (limit[0], limit[1]));
+                    commands::zset::handle_zrange(&key, by, rev, limit, with_scores);
+                }
+                Err(e) => eprintln!("{e}"),
+            }
+        }
+        Commands::ZPopMin { key, count } => {
+            commands::zset::handle_zpopmin(&key, count);
+        }
+        Commands::ZPopMax { key, count } => {
+            commands::zset::handle_zpopmax(&key, count);
+        }
+        Commands::ZCard { key } => {
+            commands::zset::handle_zcard(&key);
+        }
+
+        // Stream commands
+        Commands::XAdd { key, field_values, id, max_len } => {
+            commands::stream::handle_xadd(&key, &field_values, id, max_len);
+        }
+        Commands::XRange { key, start, end, rev, count } => {
+            commands::stream::handle_xrange(&key, &start, &end, rev, count);
+        }
+        Commands::XLen { key } => {
+            commands::stream::handle_xlen(&key);
+        }
+        Commands::XGroupCreate { key, group, start, mkstream } => {
+            commands::stream::handle_xgroup_create(&key, &group, start, mkstream);
+        }
+        Commands::XReadGroup { key, group, consumer, id, count } => {
+            commands::stream::handle_xreadgroup(&key, &group, &consumer, id, count);
+        }
+        Commands::XAck { key, group, ids } => {
+            commands::stream::handle_xack(&key, &group, &ids);
+        }
+        Commands::XPending { key, group } => {
+            commands::stream::handle_xpending(&key, &group);
+        }
+        Commands::XClaim { key, group, consumer, min_idle_ms, ids } => {
+            commands::stream::handle_xclaim(&key, &group, &consumer, min_idle_ms, &ids);
+        }
     }
 }
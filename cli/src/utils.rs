@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 use reqwest::blocking::Client;
+use core::command::{ExpireCondition, ZAddFlags};
+use core::storage::sorted_set::RangeBy;
 
 /// All client-side commands supported by the CLI and server.
 #[derive(Debug, Serialize, Deserialize)]
@@ -26,6 +28,96 @@ pub enum ClientCommand {
     SAdd { key: String, value: String },
     SRem { key: String, value: String },
     SMembers { key: String },
+
+    // Expiration commands, on keys of any type
+    PSetEx { key: String, value: String, ttl_ms: u64 },
+    Expire { key: String, ttl_secs: u64, condition: Option<ExpireCondition> },
+    PExpire { key: String, ttl_ms: u64, condition: Option<ExpireCondition> },
+    ExpireAt { key: String, timestamp: u64, condition: Option<ExpireCondition> },
+    PExpireAt { key: String, timestamp_ms: u64, condition: Option<ExpireCondition> },
+    Ttl { key: String },
+    PTtl { key: String },
+    Persist { key: String },
+
+    // Sorted set commands. ZADD's scores travel as strings, so that -inf
+    // and +inf survive JSON
+    ZAdd {
+        key: String,
+        members: Vec<(String, String)>, // (score, member)
+        #[serde(default)]
+        flags: ZAddFlags,
+    },
+    ZRem { key: String, members: Vec<String> },
+    ZScore { key: String, member: String },
+    ZRank { key: String, member: String },
+    ZRange {
+        key: String,
+        by: RangeBy,
+        #[serde(default)]
+        rev: bool,
+        #[serde(default)]
+        limit: Option<(usize, usize)>,
+        #[serde(default)]
+        with_scores: bool,
+    },
+    ZPopMin { key: String, count: Option<usize> },
+    ZPopMax { key: String, count: Option<usize> },
+    ZCard { key: String },
+
+    // Stream commands. IDs and range bounds travel as strings and are
+    // parsed by the server, so that a malformed one is the client's error
+    XAdd {
+        key: String,
+        #[serde(default)]
+        id: Option<String>,
+        fields: Vec<(String, String)>,
+        #[serde(default)]
+        max_len: Option<usize>,
+    },
+    XRange {
+        key: String,
+        start: String,
+        end: String,
+        #[serde(default)]
+        rev: bool,
+        #[serde(default)]
+        count: Option<usize>,
+    },
+    XLen { key: String },
+    XGroupCreate {
+        key: String,
+        group: String,
+        #[serde(default)]
+        start: Option<String>,
+        #[serde(default)]
+        mkstream: bool,
+    },
+    XReadGroup {
+        key: String,
+        group: String,
+        consumer: String,
+        #[serde(default)]
+        id: Option<String>,
+        #[serde(default)]
+        count: Option<usize>,
+    },
+    XAck { key: String, group: String, ids: Vec<String> },
+    XPending { key: String, group: String },
+    XClaim {
+        key: String,
+        group: String,
+        consumer: String,
+        min_idle_ms: u64,
+        ids: Vec<String>,
+    },
+
+    // Counters, on strings and hash fields
+    Incr { key: String },
+    Decr { key: String },
+    IncrBy { key: String, by: i64 },
+    IncrByFloat { key: String, by: f64 },
+    HIncrBy { key: String, field: String, by: i64 },
+    HIncrByFloat { key: String, field: String, by: f64 },
 }
 
 /// Response from the server.
@@ -1,17 +1,42 @@
+use core::clock::MockClock;
 use core::db::VaporDB;
+use core::options::VaporDBOptions;
 use core::command::Command;
+use core::ttl_daemon;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Each test gets its own directory, as a MANIFEST must not be shared
+fn setup_db() -> (TempDir, Arc<Mutex<VaporDB>>) {
+    let dir = tempfile::tempdir().unwrap();
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        ..Default::default()
+    };
+    let wal_path = dir.path().join("test.wal");
+    let db = VaporDB::open(wal_path.to_str().unwrap(), options).unwrap();
+    (dir, Arc::new(Mutex::new(db)))
+}
 
-fn setup_db() -> Arc<Mutex<VaporDB>> {
-    let db = VaporDB::new_with_persistence("test.wal").unwrap();
-    Arc::new(Mutex::new(db))
+// Like `setup_db`, but time only moves when the returned clock is advanced
+fn setup_db_with_clock() -> (TempDir, Arc<Mutex<VaporDB>>, Arc<MockClock>) {
+    let dir = tempfile::tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        clock: clock.clone(),
+        ..Default::default()
+    };
+    let wal_path = dir.path().join("test.wal");
+    let db = VaporDB::open(wal_path.to_str().unwrap(), options).unwrap();
+    (dir, Arc::new(Mutex::new(db)), clock)
 }
 
 #[test]
 fn test_string_set_get() {
-    let db = setup_db();
+    let (_dir, db) = setup_db();
     let mut db = db.lock().unwrap();
 
     db.execute(Command::Set("k".into(), "v".into())).unwrap();
@@ -21,7 +46,7 @@ fn test_string_set_get() {
 
 #[test]
 fn test_list_push_pop() {
-    let db = setup_db();
+    let (_dir, db) = setup_db();
     let mut db = db.lock().unwrap();
 
     db.execute(Command::LPush("mylist".into(), "a".into())).unwrap();
@@ -43,7 +68,7 @@ fn test_list_push_pop() {
 
 #[test]
 fn test_set_operations() {
-    let db = setup_db();
+    let (_dir, db) = setup_db();
     let mut db = db.lock().unwrap();
 
     db.execute(Command::SAdd("myset".into(), "x".into())).unwrap();
@@ -64,7 +89,7 @@ fn test_set_operations() {
 
 #[test]
 fn test_hash_hset_hget_hdel() {
-    let db = setup_db();
+    let (_dir, db) = setup_db();
     let mut db = db.lock().unwrap();
 
     db.execute(Command::HSet("myhash".into(), "f1".into(), "v1".into())).unwrap();
@@ -80,9 +105,8 @@ fn test_hash_hset_hget_hdel() {
 
 #[test]
 fn test_ttl_expiration() {
-    let db = setup_db();
-    let db_arc = Arc::clone(&db);
-    VaporDB::start_ttl_daemon(Arc::clone(&db_arc));
+    let (_dir, db, clock) = setup_db_with_clock();
+    ttl_daemon::start_ttl_daemon(Arc::clone(&db), Duration::from_millis(10), false);
 
     {
         let mut db = db.lock().unwrap();
@@ -90,9 +114,67 @@ fn test_ttl_expiration() {
         assert_eq!(db.execute(Command::Get("temp".into())).unwrap(), Some("bye".into()));
     }
 
-    thread::sleep(Duration::from_secs(2));
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(db.lock().unwrap().execute(Command::Get("temp".into())).unwrap(), None);
 
-    let mut db = db.lock().unwrap();
-    let val = db.execute(Command::Get("temp".into())).unwrap();
-    assert_eq!(val, None);
+    // The daemon deletes the key once it sees the clock has passed its expiry
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !db.lock().unwrap().expiration_table().is_empty() {
+        assert!(Instant::now() < deadline, "the TTL daemon never deleted the key");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_restore_subcommand_recovers_up_to_a_sequence_number() {
+    let dir = tempfile::tempdir().unwrap();
+    let archive = dir.path().join("archive");
+    let wal_path = dir.path().join("vapordb.wal");
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        wal_archive_dir: Some(archive.clone()),
+        ..Default::default()
+    };
+    let good_seq = {
+        let mut db = VaporDB::open(wal_path.to_str().unwrap(), options).unwrap();
+        db.execute(Command::Set("kept".into(), "1".into())).unwrap();
+        db.backup(dir.path().join("backup")).unwrap();
+        db.execute(Command::Set("later".into(), "2".into())).unwrap();
+        db.flush().unwrap();
+        let good_seq = db.last_sequence();
+        db.execute(Command::Del("kept".into())).unwrap();
+        good_seq
+    };
+
+    let target = dir.path().join("restored");
+    assert_cmd::Command::cargo_bin("cli")
+        .unwrap()
+        .arg("restore")
+        .arg("--backup")
+        .arg(dir.path().join("backup"))
+        .arg("--until")
+        .arg(format!("seq:{good_seq}"))
+        .arg("--archive")
+        .arg(&archive)
+        .arg("--wal")
+        .arg(&wal_path)
+        .arg("--target")
+        .arg(&target)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Restored 1 writes"));
+
+    let options = VaporDBOptions {
+        sst_dir: target.join("sstables"),
+        ..Default::default()
+    };
+    let mut db = VaporDB::open(target.join("vapordb.wal").to_str().unwrap(), options).unwrap();
+    assert_eq!(db.execute(Command::Get("kept".into())).unwrap(), Some("1".into()));
+    assert_eq!(db.execute(Command::Get("later".into())).unwrap(), Some("2".into()));
+
+    assert_cmd::Command::cargo_bin("cli")
+        .unwrap()
+        .args(["restore", "--backup", "x", "--until", "not a time", "--target", "y"])
+        .assert()
+        .failure();
 }
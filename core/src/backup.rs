@@ -0,0 +1,153 @@
+// A base backup is a flushed copy of a database's SSTables under a MANIFEST
+// of its own, plus a `BACKUP` file recording the newest write it holds and
+// when it was taken. Point-in-time recovery copies one into an empty
+// directory and replays archived WAL segments on top of it, stopping at a
+// target sequence number or wall-clock time.
+
+use crate::error::{Result, VaporDBError};
+use crate::storage::internal_key::SequenceNumber;
+use crate::storage::manifest::{FileMeta, Manifest, table_path};
+use crate::storage::table_set::TableSet;
+use crate::wal::wal::{self, WalRecord};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const BACKUP_FILE: &str = "BACKUP";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackupInfo {
+    pub last_sequence: SequenceNumber, // newest write the backup holds
+    pub created_at: u64,               // epoch milliseconds
+}
+
+impl BackupInfo {
+    pub fn read(dir: impl AsRef<Path>) -> Result<Self> {
+        let path = dir.as_ref().join(BACKUP_FILE);
+        let json = fs::read_to_string(&path)
+            .map_err(|e| VaporDBError::Internal(format!("no backup at {}: {e}", path.display())))?;
+        Ok(serde_json::from_str(&json)?)
+    }
+}
+
+/// How far point-in-time recovery replays the WAL. Both bounds are
+/// inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryTarget {
+    Sequence(SequenceNumber),
+    /// Epoch milliseconds. Records logged before WAL timestamps existed
+    /// count as older than any target.
+    Time(u64),
+}
+
+impl RecoveryTarget {
+    fn includes(&self, record: &WalRecord) -> bool {
+        match *self {
+            Self::Sequence(seq) => record.seq <= seq,
+            Self::Time(time) => record.timestamp <= time,
+        }
+    }
+
+    // A backup taken after the target already holds writes past it
+    fn check(&self, info: &BackupInfo) -> Result<()> {
+        let after = match *self {
+            Self::Sequence(seq) => seq < info.last_sequence,
+            Self::Time(time) => time < info.created_at,
+        };
+        if after {
+            return Err(VaporDBError::Internal(format!(
+                "backup taken at sequence {} is newer than {self:?}",
+                info.last_sequence
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// What point-in-time recovery restored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RestoreReport {
+    pub base_sequence: SequenceNumber, // newest write in the base backup
+    pub replayed: usize,               // WAL records applied on top of it
+    pub last_sequence: SequenceNumber,
+}
+
+// Copies the live tables of `tables` into `dir` with a manifest listing
+// them. The caller flushes first and holds `tables` so no compaction can
+// drop a file part way through.
+pub(crate) fn write_backup(
+    tables: &TableSet,
+    dir: &Path,
+    last_sequence: SequenceNumber,
+) -> Result<BackupInfo> {
+    if dir.exists() && fs::read_dir(dir)?.next().is_some() {
+        return Err(VaporDBError::Internal(format!(
+            "backup directory {} is not empty",
+            dir.display()
+        )));
+    }
+    fs::create_dir_all(dir)?;
+
+    let files: Vec<FileMeta> = tables.manifest().version().files().cloned().collect();
+    for file in &files {
+        let copy = table_path(dir, file.number);
+        fs::copy(tables.manifest().sst_path(file.number), &copy)?;
+        fs::File::open(&copy)?.sync_all()?;
+    }
+    Manifest::create(dir, files)?;
+
+    let info = BackupInfo {
+        last_sequence,
+        created_at: tables.clock().now_millis(),
+    };
+    // Written last, so a half-copied backup is never mistaken for one
+    let tmp = dir.join(format!("{BACKUP_FILE}.tmp"));
+    fs::write(&tmp, serde_json::to_vec(&info)?)?;
+    fs::File::open(&tmp)?.sync_all()?;
+    fs::rename(&tmp, dir.join(BACKUP_FILE))?;
+    Ok(info)
+}
+
+// Copies the backup in `backup` into `sst_dir`, returning what it holds once
+// `target` has been checked against it.
+pub(crate) fn restore_base(
+    backup: &Path,
+    sst_dir: &Path,
+    target: RecoveryTarget,
+) -> Result<BackupInfo> {
+    let info = BackupInfo::read(backup)?;
+    target.check(&info)?;
+
+    fs::create_dir_all(sst_dir)?;
+    for entry in fs::read_dir(backup)? {
+        let entry = entry?;
+        if entry.file_name() == BACKUP_FILE || !entry.file_type()?.is_file() {
+            continue;
+        }
+        fs::copy(entry.path(), sst_dir.join(entry.file_name()))?;
+    }
+    Ok(info)
+}
+
+// The records newer than `after` and within `target` found in the logs at
+// `logs`, in sequence order. A record found in several logs, as happens
+// while a segment is being archived, is kept once.
+pub(crate) fn records_to_replay(
+    logs: &[PathBuf],
+    after: SequenceNumber,
+    target: RecoveryTarget,
+) -> Result<Vec<WalRecord>> {
+    let mut records = BTreeMap::new();
+    for log in logs {
+        for record in wal::read_log(log)? {
+            if record.seq > after {
+                records.insert(record.seq, record);
+            }
+        }
+    }
+    Ok(records
+        .into_values()
+        .take_while(|record| target.includes(record))
+        .collect())
+}
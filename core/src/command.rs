@@ -1,3 +1,10 @@
+use crate::error::{Result, VaporDBError};
+use crate::storage::sorted_set::RangeBy;
+use crate::storage::stream::StreamId;
+use serde::{Deserialize, Serialize};
+use std::ops::Bound;
+use std::str::FromStr;
+
 #[derive(Debug)]
 pub enum Command {
     Get(String),
@@ -20,4 +27,220 @@ pub enum Command {
     SAdd(String, String),
     SRem(String, String),
     SMembers(String),
+
+    // Expiration commands. EXPIRE and TTL work in seconds, the P-prefixed
+    // forms in milliseconds, and the AT forms take epoch times
+    PSetEx(String, u64, String),
+    Expire(String, u64, Option<ExpireCondition>),
+    PExpire(String, u64, Option<ExpireCondition>),
+    ExpireAt(String, u64, Option<ExpireCondition>),
+    PExpireAt(String, u64, Option<ExpireCondition>),
+    Ttl(String),
+    PTtl(String),
+    Persist(String),
+
+    // Sorted set commands. ZADD takes (score, member) pairs
+    ZAdd(String, Vec<(f64, String)>, ZAddFlags),
+    ZRem(String, Vec<String>),
+    ZScore(String, String),
+    ZRank(String, String),
+    ZRange(String, ZRange),
+    ZPopMin(String, usize),
+    ZPopMax(String, usize),
+    ZCard(String),
+
+    // Stream commands. XADD generates the ID if none is given and trims the
+    // stream to the optional max length. XRANGE bounds are always given
+    // lowest first; XREVRANGE only reverses the order of the results
+    XAdd(String, Option<StreamId>, Vec<(String, String)>, Option<usize>),
+    XRange(String, Bound<StreamId>, Bound<StreamId>, Option<usize>),
+    XRevRange(String, Bound<StreamId>, Bound<StreamId>, Option<usize>),
+    XRead(String, StreamId, Option<usize>),
+    XLen(String),
+
+    // Consumer groups. XGROUP CREATE starts the group after the given ID, or
+    // after the stream's last entry, and with MKSTREAM creates the stream if
+    // it is missing. XREADGROUP reads new entries if no ID is given, and
+    // otherwise the consumer's own pending entries after it
+    XGroupCreate(String, String, Option<StreamId>, bool),
+    XReadGroup(String, String, String, Option<StreamId>, Option<usize>),
+    XAck(String, String, Vec<StreamId>),
+    XPending(String, String),
+    XClaim(String, String, String, u64, Vec<StreamId>),
+
+    // Counters. Each parses a string, or a hash field, as a number, counting
+    // a missing one as 0, and stores and returns the result
+    Incr(String),
+    Decr(String),
+    IncrBy(String, i64),
+    IncrByFloat(String, f64),
+    HIncrBy(String, String, i64),
+    HIncrByFloat(String, String, f64),
+}
+
+impl Command {
+    /// The key the command reads or writes.
+    pub fn key(&self) -> &str {
+        match self {
+            Command::Get(key)
+            | Command::Set(key, _)
+            | Command::Del(key)
+            | Command::HSet(key, _, _)
+            | Command::HGet(key, _)
+            | Command::HDel(key, _)
+            | Command::LPush(key, _)
+            | Command::RPush(key, _)
+            | Command::LPop(key)
+            | Command::RPop(key)
+            | Command::LRange(key, _, _)
+            | Command::SAdd(key, _)
+            | Command::SRem(key, _)
+            | Command::SMembers(key)
+            | Command::PSetEx(key, _, _)
+            | Command::Expire(key, _, _)
+            | Command::PExpire(key, _, _)
+            | Command::ExpireAt(key, _, _)
+            | Command::PExpireAt(key, _, _)
+            | Command::Ttl(key)
+            | Command::PTtl(key)
+            | Command::Persist(key)
+            | Command::ZAdd(key, _, _)
+            | Command::ZRem(key, _)
+            | Command::ZScore(key, _)
+            | Command::ZRank(key, _)
+            | Command::ZRange(key, _)
+            | Command::ZPopMin(key, _)
+            | Command::ZPopMax(key, _)
+            | Command::ZCard(key)
+            | Command::XAdd(key, _, _, _)
+            | Command::XRange(key, _, _, _)
+            | Command::XRevRange(key, _, _, _)
+            | Command::XRead(key, _, _)
+            | Command::XLen(key)
+            | Command::XGroupCreate(key, _, _, _)
+            | Command::XReadGroup(key, _, _, _, _)
+            | Command::XAck(key, _, _)
+            | Command::XPending(key, _)
+            | Command::XClaim(key, _, _, _, _)
+            | Command::Incr(key)
+            | Command::Decr(key)
+            | Command::IncrBy(key, _)
+            | Command::IncrByFloat(key, _)
+            | Command::HIncrBy(key, _, _)
+            | Command::HIncrByFloat(key, _, _) => key,
+        }
+    }
+}
+
+/// When an EXPIRE-style command may replace a key's expiry. A key without
+/// one counts as never expiring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpireCondition {
+    Nx, // only if the key has no expiry
+    Xx, // only if it has one
+    Gt, // only if the new expiry is later
+    Lt, // only if the new expiry is earlier
+}
+
+impl ExpireCondition {
+    pub fn allows(&self, current: Option<u64>, expire_at: u64) -> bool {
+        match self {
+            Self::Nx => current.is_none(),
+            Self::Xx => current.is_some(),
+            Self::Gt => current.is_some_and(|current| expire_at > current),
+            Self::Lt => current.is_none_or(|current| expire_at < current),
+        }
+    }
+}
+
+impl FromStr for ExpireCondition {
+    type Err = VaporDBError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "nx" => Ok(Self::Nx),
+            "xx" => Ok(Self::Xx),
+            "gt" => Ok(Self::Gt),
+            "lt" => Ok(Self::Lt),
+            _ => Err(VaporDBError::Internal(format!(
+                "unknown expire condition '{s}', expected nx, xx, gt or lt"
+            ))),
+        }
+    }
+}
+
+/// ZADD's options. NX only adds new members and XX only updates existing
+/// ones; GT and LT only update a member whose score would rise or fall. With
+/// INCR the score is added to the member's current one, as ZINCRBY does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ZAddFlags {
+    pub nx: bool,
+    pub xx: bool,
+    pub gt: bool,
+    pub lt: bool,
+    pub incr: bool,
+}
+
+impl ZAddFlags {
+    /// Parses flags named `nx`, `xx`, `gt`, `lt` or `incr`, in any case.
+    pub fn parse<S: AsRef<str>>(flags: impl IntoIterator<Item = S>) -> Result<Self> {
+        let mut parsed = Self::default();
+        for flag in flags {
+            let flag = flag.as_ref();
+            match flag.to_ascii_lowercase().as_str() {
+                "nx" => parsed.nx = true,
+                "xx" => parsed.xx = true,
+                "gt" => parsed.gt = true,
+                "lt" => parsed.lt = true,
+                "incr" => parsed.incr = true,
+                _ => {
+                    return Err(VaporDBError::InvalidArgument(format!(
+                        "unknown ZADD flag '{flag}', expected nx, xx, gt, lt or incr"
+                    )));
+                }
+            }
+        }
+        parsed.validate()?;
+        Ok(parsed)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.nx && (self.xx || self.gt || self.lt) {
+            return Err(VaporDBError::InvalidArgument(
+                "ZADD's nx cannot be combined with xx, gt or lt".into(),
+            ));
+        }
+        if self.gt && self.lt {
+            return Err(VaporDBError::InvalidArgument(
+                "ZADD's gt and lt cannot be combined".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether a member currently scored `current` may be given `score`.
+    pub fn allows(&self, current: Option<f64>, score: f64) -> bool {
+        match current {
+            None => !self.xx,
+            Some(current) => {
+                !self.nx && (!self.gt || score > current) && (!self.lt || score < current)
+            }
+        }
+    }
+}
+
+/// A ZRANGE query: the members `by` selects, highest first if `rev`, with
+/// `limit` as (offset, count) for score and lex ranges. Bounds are always
+/// given lowest first, `rev` or not.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ZRange {
+    pub by: RangeBy,
+    #[serde(default)]
+    pub rev: bool,
+    #[serde(default)]
+    pub limit: Option<(usize, usize)>,
+    #[serde(default)]
+    pub with_scores: bool, // return [member, score] pairs rather than members
 }
@@ -1,194 +1,557 @@
-use crate::command::Command;
+use crate::backup::{self, BackupInfo, RecoveryTarget, RestoreReport};
+use crate::command::{Command, ExpireCondition, ZRange};
 use crate::error::{VaporDBError, Result};
+use crate::events::{EventBus, EventKind};
+use crate::options::VaporDBOptions;
+use crate::storage::bloom::FilterStats;
+use crate::snapshot::{self, Snapshot};
+use crate::storage::compaction::CompactionWorker;
+use crate::storage::flush::FlushWorker;
+use crate::storage::internal_key::{MAX_SEQUENCE, Sequence, SequenceNumber};
+use crate::storage::snapshots::SnapshotList;
+use crate::storage::memtable_set::MemTableSet;
+use crate::storage::scan::ScanIter;
+use crate::storage::sorted_set::{RangeBy, SortedSet};
+use crate::storage::stream::{Stream, StreamId};
 use crate::storage::sst::SSTable;
-use crate::storage::{memtable::MemTable, Storage, Value};
+use crate::storage::table_set::TableSet;
+use crate::storage::{memtable::MemTable, Value};
+use crate::clock::Clock;
 use crate::ttl::ExpirationTable;
-use crate::wal::wal::{LogEntry, WriteAheadLog};
+use crate::ttl_daemon;
+use crate::wal::group_commit::GroupCommit;
+use crate::wal::cdc::ChangeStream;
+use crate::storage::manifest::Manifest;
+use crate::wal::wal::{self, Durability, LogEntry, RecoveryReport, WalRecoveryMode, WriteAheadLog};
 use serde_json;
+use parking_lot::RwLock;
 use std::sync::{Arc, Mutex};
 
 use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
-use std::thread;
-use std::time::Duration;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// Expired keys deleted between checks of the expiration cycle's time budget
+const EXPIRE_BATCH: usize = 64;
 
 pub struct VaporDB {
-    storage: Arc<MemTable>,
+    memtables: Arc<RwLock<MemTableSet>>,
     ttl: Arc<ExpirationTable>,
     wal: WriteAheadLog,
-    sstables: Vec<SSTable>,
-    sst_dir: PathBuf,       // directory where SSTs are stored
-    flush_threshold: usize, // flush when this many keys are in MemTable
+    wal_recovery: RecoveryReport,
+    tables: Arc<RwLock<TableSet>>,
+    sequence: Arc<Sequence>,
+    snapshots: Arc<SnapshotList>,
+    flush: FlushWorker,
+    compaction: Arc<CompactionWorker>,
+    filter_stats: Arc<FilterStats>,
+    events: Arc<EventBus>,
+    options: VaporDBOptions,
 }
 
 impl VaporDB {
     pub fn new_with_persistence(wal_path: &str) -> Result<Self> {
-        let storage = Arc::new(MemTable::new());
-        let ttl = Arc::new(ExpirationTable::new());
-        let wal = WriteAheadLog::new(wal_path)?;
+        Self::open(wal_path, VaporDBOptions::default())
+    }
 
-        // Load SSTables
-        let sst_dir = PathBuf::from("sstables");
-        std::fs::create_dir_all(&sst_dir)?;
+    pub fn open(wal_path: &str, options: VaporDBOptions) -> Result<Self> {
+        let ttl = Arc::new(ExpirationTable::with_clock(Arc::clone(&options.clock)));
+        let filter_stats = Arc::new(FilterStats::default());
 
-        let mut sstables = vec![];
-        for entry in std::fs::read_dir(&sst_dir)? {
-            let path = entry?.path();
-            if path.extension().map(|ext| ext == "sst").unwrap_or(false) {
-                let sst = SSTable::load(path.to_str().unwrap())?;
-                sstables.push(sst);
-            }
+        let table_set = TableSet::open(&options, Arc::clone(&filter_stats))?;
+        let sequence = Arc::new(Sequence::new(table_set.manifest().last_sequence()));
+        let log_number = table_set.manifest().log_number();
+        // Flushed keys keep being actively expired; replay overrides these
+        // for keys written again since
+        for (key, expire_at) in table_set.expirations()? {
+            ttl.set_at(key, expire_at);
+        }
+        let tables = Arc::new(RwLock::new(table_set));
+
+        WriteAheadLog::upgrade_legacy(wal_path, sequence.last() + 1)?;
+        wal::purge_segments(
+            Path::new(wal_path),
+            log_number,
+            options.wal_retention,
+            options.wal_archive_dir.as_deref(),
+        )?;
+        let (wal, records, wal_recovery) = WriteAheadLog::open(
+            wal_path,
+            log_number,
+            options.wal_recovery_mode,
+            options.durability,
+            options.wal_sync_interval,
+        )?;
+        let wal = wal.with_clock(Arc::clone(&options.clock));
+        if !wal_recovery.is_clean() {
+            eprintln!("WAL recovery discarded data: {wal_recovery:?}");
         }
+        let storage =
+            MemTable::with_expiration_table(Arc::clone(&ttl)).with_sequence(Arc::clone(&sequence));
 
-        for entry in wal.load_entries()? {
-            match entry {
+        for record in records {
+            match record.entry {
                 LogEntry::Set(k, v) => {
-                    storage.set(k, Value::String(v))?;
+                    ttl.remove(&k);
+                    storage.insert(record.seq, k, Some(Value::String(v)), None);
+                }
+                LogEntry::Put(k, v) => {
+                    ttl.remove(&k);
+                    storage.insert(record.seq, k, Some(v), None);
                 }
                 LogEntry::Del(k) => {
-                    storage.del(&k)?;
+                    ttl.remove(&k);
+                    storage.insert(record.seq, k, None, None);
+                }
+                // A key that expired while the database was down comes back
+                // as a tombstone, so no older copy in an SSTable resurfaces
+                LogEntry::PutExpiring(k, v, expire_at) => {
+                    if expire_at <= ttl.now() {
+                        ttl.remove(&k);
+                        storage.insert(record.seq, k, None, None);
+                    } else {
+                        ttl.set_at(k.clone(), expire_at);
+                        storage.insert(record.seq, k, Some(v), Some(expire_at));
+                    }
                 }
             }
         }
 
+        wal.group_commit().recovered(sequence.last());
+
+        let memtables = Arc::new(RwLock::new(MemTableSet::new(storage)));
+        let snapshots = Arc::new(SnapshotList::default());
+        let events = Arc::new(EventBus::new(options.notify_events.iter().copied()));
+        wal.group_commit().on_durable({
+            let events = Arc::clone(&events);
+            move |seq| events.release(seq)
+        });
+        let compaction = Arc::new(CompactionWorker::start(
+            Arc::clone(&tables),
+            Arc::clone(&snapshots),
+            options.clone(),
+            Arc::clone(&events),
+        ));
+        let flush = FlushWorker::start(
+            Arc::clone(&memtables),
+            Arc::clone(&tables),
+            Arc::clone(&snapshots),
+            Arc::clone(&compaction),
+            PathBuf::from(wal_path),
+            options.clone(),
+        );
+
         let vapor_db = Self {
-            storage,
+            memtables,
             wal,
+            wal_recovery,
             ttl,
-            sstables,
-            sst_dir,
-            flush_threshold: 1000,
+            tables,
+            sequence,
+            snapshots,
+            flush,
+            compaction,
+            filter_stats,
+            events,
+            options,
         };
 
         Ok(vapor_db)
     }
 
+    /// Restores the base backup in `backup_dir` into the empty database at
+    /// `wal_path` and `options.sst_dir`, then replays the writes logged after
+    /// it up to `target`. `logs` are the paths WAL segments are named after,
+    /// such as the archive's copy of the log and the live log, and may
+    /// overlap.
+    pub fn restore(
+        backup_dir: impl AsRef<Path>,
+        logs: &[PathBuf],
+        target: RecoveryTarget,
+        wal_path: &str,
+        options: VaporDBOptions,
+    ) -> Result<(Self, RestoreReport)> {
+        if Manifest::exists(&options.sst_dir)
+            || Path::new(wal_path).exists()
+            || !wal::segment_numbers(Path::new(wal_path))?.is_empty()
+        {
+            return Err(VaporDBError::Internal(format!(
+                "cannot restore over the existing database at {wal_path}"
+            )));
+        }
+
+        let info = backup::restore_base(backup_dir.as_ref(), &options.sst_dir, target)?;
+        let records = backup::records_to_replay(logs, info.last_sequence, target)?;
+        {
+            let (mut wal, _, _) = WriteAheadLog::open(
+                wal_path,
+                0,
+                WalRecoveryMode::Fail,
+                Durability::Os,
+                options.wal_sync_interval,
+            )?;
+            for record in &records {
+                wal.append_record(record)?;
+            }
+            wal.group_commit().sync()?;
+        }
+
+        let db = Self::open(wal_path, options)?;
+        let report = RestoreReport {
+            base_sequence: info.last_sequence,
+            replayed: records.len(),
+            last_sequence: db.last_sequence(),
+        };
+        Ok((db, report))
+    }
+
+    /// Flushes the database and copies its SSTables into `dir` as a base
+    /// backup for `restore`. Writes after it are only recoverable from WAL
+    /// segments, so `options.wal_archive_dir` should be set too.
+    pub fn backup(&mut self, dir: impl AsRef<Path>) -> Result<BackupInfo> {
+        self.flush()?;
+        let tables = self.tables.read();
+        backup::write_backup(&tables, dir.as_ref(), self.sequence.last())
+    }
+
+    /// The MemTable currently taking writes.
     pub fn memtable(&self) -> Arc<MemTable> {
-        Arc::clone(&self.storage)
+        Arc::clone(self.memtables.read().active())
+    }
+
+    pub fn memtables(&self) -> Arc<RwLock<MemTableSet>> {
+        Arc::clone(&self.memtables)
+    }
+
+    /// What opening the database had to discard from the WAL.
+    pub fn wal_recovery_report(&self) -> &RecoveryReport {
+        &self.wal_recovery
+    }
+
+    /// Lets callers that ran a command with `execute_deferred` wait for its
+    /// write to become durable.
+    pub fn group_commit(&self) -> Arc<GroupCommit> {
+        self.wal.group_commit()
+    }
+
+    /// Streams every write from sequence number `from` on, in order, as it
+    /// commits. Pass 0 to start at the oldest write the WAL still holds, or
+    /// a stream's `cursor()` to resume it.
+    pub fn subscribe(&self, from: SequenceNumber) -> ChangeStream {
+        ChangeStream::new(self.wal.path(), self.wal.group_commit(), from)
+    }
+
+    /// The bus keyspace events are published on, e.g. to subscribe to
+    /// expirations.
+    pub fn events(&self) -> Arc<EventBus> {
+        Arc::clone(&self.events)
+    }
+
+    /// The clock expiry is decided by, `options.clock`.
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        Arc::clone(&self.options.clock)
     }
 
     pub fn expiration_table(&self) -> Arc<ExpirationTable> {
         Arc::clone(&self.ttl)
     }
 
-    pub fn sstable(&self) -> Option<Arc<Mutex<SSTable>>> {
-        self.sstables.get(0).cloned().map(|sst| Arc::new(Mutex::new(sst)))
+    pub fn sstable(&self) -> Option<Arc<SSTable>> {
+        self.tables.read().first()
     }
 
-    pub fn start_ttl_daemon(db: Arc<Mutex<Self>>) {
-        std::thread::spawn(move || loop {
-            std::thread::sleep(std::time::Duration::from_secs(1));
-            if let Ok(db) = db.lock() {
-                db.clean_expired_keys();
-            }
-        });
+    pub fn tables(&self) -> Arc<RwLock<TableSet>> {
+        Arc::clone(&self.tables)
+    }
+
+    pub fn filter_stats(&self) -> Arc<FilterStats> {
+        Arc::clone(&self.filter_stats)
     }
 
-    pub fn clean_expired_keys(&self) {
-        let expired_keys = self.ttl.get_expired_keys();
-        for key in expired_keys {
-            let _ = self.storage.del(&key);
-            self.ttl.remove(&key);
+    /// Writes the active MemTable and any immutable ones out to SSTables on
+    /// the calling thread.
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.memtables.read().active().is_empty() {
+            self.freeze()?;
         }
+        self.flush.run_pending()?;
+        Ok(())
     }
 
-    pub fn start_background_compaction(&mut self) -> Result<()> {
-        self.sstables.sort_by_key(|sst| sst.size());
+    /// Runs any compactions that are due on the calling thread instead of
+    /// waiting for the background workers, after flushing the MemTables
+    /// already waiting for it.
+    pub fn compact(&self) -> Result<usize> {
+        self.flush.run_pending()?;
+        self.compaction.run_pending()
+    }
 
-        let sstables_len = self.sstables.len();
-        if sstables_len >= 2 {
-            let sst1 = &self.sstables[0];
-            let sst2 = &self.sstables[1];
+    /// Pins the current state of the database for consistent reads.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(
+            self.sequence.last(),
+            Arc::clone(&self.memtables),
+            Arc::clone(&self.tables),
+            Arc::clone(&self.snapshots),
+        )
+    }
 
-            let timestamp = chrono::Utc::now().timestamp();
-            let path = self.sst_dir.join(format!("compact_{}.sst", timestamp));
+    /// Live keys in `range` with their values, in key order. The scan reads a
+    /// consistent view as of the moment it was created; iterate it with
+    /// `.rev()` to walk the range backwards.
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> ScanIter {
+        self.snapshot().scan(range)
+    }
 
-            // Handle compaction result
-            if let Err(e) = SSTable::compact(sst1, sst2, path.to_str().unwrap()) {
-                return Err(VaporDBError::CompactionFailed(e.to_string()));
-            } else {
-                println!("Compaction successful!");
-            }
+    /// Live keys beginning with `prefix` with their values, in key order.
+    pub fn scan_prefix(&self, prefix: &str) -> ScanIter {
+        self.snapshot().scan_prefix(prefix)
+    }
 
-            // Remove old SSTables from memory or disk and Reload the compacted SSTable
-            self.sstables.drain(0..2);
-            self.sstables.push(SSTable::load(path.to_str().unwrap())?);
+    /// The sequence number of the newest write.
+    pub fn last_sequence(&self) -> SequenceNumber {
+        self.sequence.last()
+    }
+
+    // Stamps `entry` with the next sequence number and appends it to the WAL.
+    fn log(&mut self, entry: LogEntry) -> Result<SequenceNumber> {
+        let seq = self.sequence.next();
+        self.wal.append(seq, entry)?;
+        Ok(seq)
+    }
+
+    // Logs a new version of `key`, `None` deleting it, applies it to the
+    // active MemTable and freezes the MemTable once it is full. The key
+    // keeps any expiry it had.
+    fn write(&mut self, key: String, value: Option<Value>) -> Result<()> {
+        let expire_at = match value {
+            Some(_) => self.expire_time(&key)?,
+            None => None,
+        };
+        self.write_version(key, value, expire_at)
+    }
+
+    // Like `write`, expiring the key at `expire_at` (epoch milliseconds), or
+    // never if `None`. The expiry goes into the log with the value so replay
+    // restores it.
+    fn write_version(
+        &mut self,
+        key: String,
+        value: Option<Value>,
+        expire_at: Option<u64>,
+    ) -> Result<()> {
+        let entry = match (&value, expire_at) {
+            (Some(value), Some(expire_at)) => {
+                LogEntry::PutExpiring(key.clone(), value.clone(), expire_at)
+            }
+            (Some(value), None) => LogEntry::Put(key.clone(), value.clone()),
+            (None, _) => LogEntry::Del(key.clone()),
+        };
+        let seq = self.log(entry)?;
+        // Set before the MemTable sees the value, so a flush never misses it
+        match expire_at {
+            Some(expire_at) => self.ttl.set_at(key.clone(), expire_at),
+            None => self.ttl.remove(&key),
         }
+        self.active().insert(seq, key, value, expire_at);
 
-        thread::sleep(Duration::from_secs(60));
+        if self.active().len() >= self.options.flush_threshold {
+            self.freeze()?;
+        }
+        Ok(())
+    }
 
+    // Starts a new WAL segment and hands the active MemTable, whose writes
+    // all sit in older segments, to the flush worker.
+    fn freeze(&mut self) -> Result<()> {
+        self.flush.wait_for_room()?;
+        let next_log = self.wal.rotate()?;
+        self.memtables.write().freeze(next_log);
+        self.flush.schedule();
         Ok(())
     }
 
+    fn active(&self) -> Arc<MemTable> {
+        Arc::clone(self.memtables.read().active())
+    }
+
+    pub fn start_ttl_daemon(db: Arc<Mutex<Self>>) {
+        ttl_daemon::start_ttl_daemon(db, Duration::from_secs(1), false);
+    }
+
+    /// Deletes keys whose expiry has passed, soonest first, through the
+    /// write path, so the tombstones reach the WAL and change streams like
+    /// any other delete. Stops once `options.expire_cycle_budget` is spent
+    /// and returns how many keys it deleted, once the deletes are as durable
+    /// as `options.durability` promises.
+    pub fn clean_expired_keys(&mut self) -> Result<usize> {
+        let deadline = Instant::now() + self.options.expire_cycle_budget;
+        let mut removed = 0;
+        loop {
+            let now = self.ttl.now();
+            let batch = self.ttl.due(now, EXPIRE_BATCH);
+            for key in &batch {
+                // The delete takes the key out of the expiration table
+                self.write(key.clone(), None)?;
+                self.events
+                    .publish_when_durable(self.sequence.last(), EventKind::Expired, key);
+            }
+            removed += batch.len();
+            if batch.len() < EXPIRE_BATCH || Instant::now() >= deadline {
+                break;
+            }
+        }
+        if removed > 0 {
+            self.wal.group_commit().commit(self.sequence.last())?;
+        }
+        Ok(removed)
+    }
+
+    // A key past its expiry reads as missing even before it is cleaned up
+    fn get_value(&self, key: &str) -> Result<Option<Value>> {
+        if self.ttl.is_expired(key) {
+            return Ok(None);
+        }
+        snapshot::get_at(&self.memtables, &self.tables, key, MAX_SEQUENCE)
+    }
+
+    // When a live `key` expires, in epoch milliseconds. The expiration table
+    // covers every key with a version in memory; a key only found in the
+    // SSTables carries its expiry there.
+    fn expire_time(&self, key: &str) -> Result<Option<u64>> {
+        let now = self.ttl.now();
+        if let Some(expire_at) = self.ttl.get(key) {
+            return Ok(Some(expire_at).filter(|&at| at > now));
+        }
+        let in_memory = self
+            .memtables
+            .read()
+            .all()
+            .iter()
+            .any(|memtable| memtable.get_entry(key).is_some());
+        if in_memory {
+            return Ok(None);
+        }
+        let entry = self.tables.read().get_entry(key)?;
+        Ok(entry.and_then(|entry| entry.ttl).filter(|&at| at > now))
+    }
+
+    // Moves the expiry of an existing key to `expire_at` if `condition`
+    // allows it, deleting the key outright if that time has passed.
+    fn expire(
+        &mut self,
+        key: String,
+        expire_at: u64,
+        condition: Option<ExpireCondition>,
+    ) -> Result<Option<String>> {
+        let Some(value) = self.get_value(&key)? else {
+            return Ok(Some("0".to_string()));
+        };
+        let current = self.expire_time(&key)?;
+        if condition.is_some_and(|condition| !condition.allows(current, expire_at)) {
+            return Ok(Some("0".to_string()));
+        }
+
+        if expire_at <= self.ttl.now() {
+            self.write(key, None)?;
+        } else {
+            self.write_version(key, Some(value), Some(expire_at))?;
+        }
+        Ok(Some("1".to_string()))
+    }
+
+    // Time left before `key` expires in milliseconds, -1 if it never does
+    // and -2 if it does not exist
+    fn time_to_live(&self, key: &str) -> Result<i64> {
+        if self.get_value(key)?.is_none() {
+            return Ok(-2);
+        }
+        Ok(match self.expire_time(key)? {
+            Some(expire_at) => {
+                let left = expire_at.saturating_sub(self.ttl.now());
+                i64::try_from(left).unwrap_or(i64::MAX)
+            }
+            None => -1,
+        })
+    }
+
+    /// Runs `cmd`, returning once any write it made is as durable as
+    /// `options.durability` promises.
     pub fn execute(&mut self, cmd: Command) -> Result<Option<String>> {
+        let (result, seq) = self.execute_deferred(cmd)?;
+        self.wal.group_commit().commit(seq)?;
+        Ok(result)
+    }
+
+    /// Runs `cmd` without waiting for its write to reach disk, returning the
+    /// sequence number to hand to `GroupCommit::commit` before acknowledging
+    /// it. Waiting after releasing the lock on the database lets concurrent
+    /// writers share one fsync.
+    pub fn execute_deferred(&mut self, cmd: Command) -> Result<(Option<String>, SequenceNumber)> {
+        let event = EventKind::of(&cmd);
+        let key = cmd.key().to_string();
+        let before = self.sequence.last();
+        let result = self.apply(cmd)?;
+        let seq = self.sequence.last();
+        if seq <= before {
+            return Ok((result, 0));
+        }
+        let event = match event {
+            // An expiry that already passed deleted the key instead
+            Some(EventKind::Expire) if self.get_value(&key)?.is_none() => Some(EventKind::Del),
+            event => event,
+        };
+        if let Some(kind) = event {
+            self.events.publish_when_durable(seq, kind, &key);
+        }
+        Ok((result, seq))
+    }
+
+    fn apply(&mut self, cmd: Command) -> Result<Option<String>> {
         match cmd {
             Command::Get(key) => {
                 if self.ttl.is_expired(&key) {
-                    self.storage.del(&key)?; // Remove from storage if expired
-                    self.ttl.remove(&key); // Remove from TTL table
+                    self.write(key.clone(), None)?; // Tombstone the expired value
+                    self.events
+                        .publish_when_durable(self.sequence.last(), EventKind::Expired, &key);
                     return Ok(None); // Return None since the value expired
                 }
 
-                match self.storage.get(&key)? {
+                match self.get_value(&key)? {
                     Some(Value::String(val)) => Ok(Some(val)),
-                    Some(_) => Ok(None),
-                    None => {
-                        // If not found in MemTable, check SSTables
-                        for sst in &self.sstables {
-                            if let Some(Value::String(val)) = sst.get(&key) {
-                                return Ok(Some(val));
-                            }
-                        }
-                        Ok(None)
-                    }
+                    _ => Ok(None),
                 }
             }
 
+            // A plain set replaces the key outright, expiry included
             Command::Set(key, value) => {
-                self.wal.append(LogEntry::Set(key.clone(), value.clone()))?;
-                self.storage.set(key, Value::String(value))?;
-
-                if self.storage.len() >= self.flush_threshold {
-                    let timestamp = chrono::Utc::now().timestamp();
-                    let path = self.sst_dir.join(format!("{}.sst", timestamp));
-                    self.storage.flush_to_sstable(path.to_str().unwrap())?;
-
-                    // Load flushed SSTable into memory
-                    let new_sst = SSTable::load(path.to_str().unwrap())?;
-                    self.sstables.push(new_sst);
-
-                    // Clear MemTable after flushing
-                    self.storage.clear();
-                }
-
+                self.write_version(key, Some(Value::String(value)), None)?;
                 Ok(None)
             }
 
+            // Deleting a missing key writes nothing
             Command::Del(key) => {
-                self.wal.append(LogEntry::Del(key.clone()))?;
-                let _existed = self.storage.del(&key)?;
-                self.ttl.remove(&key);
+                if self.get_value(&key)?.is_some() {
+                    self.write(key, None)?;
+                }
                 Ok(None)
             }
 
             // HSet command
             Command::HSet(key, field, value) => {
-                let mut map = match self.storage.get(&key)? {
+                let mut map = match self.get_value(&key)? {
                     Some(Value::Hash(map)) => map,
                     _ => HashMap::new(),
                 };
 
                 map.insert(field, value);
-                self.storage.set(key.clone(), Value::Hash(map.clone()))?;
-                self.wal.append(LogEntry::Set(
-                    key,
-                    serde_json::to_string(&Value::Hash(map))?,
-                ))?;
+                self.write(key, Some(Value::Hash(map)))?;
 
                 Ok(None)
             }
 
-            Command::HGet(key, field) => match self.storage.get(&key)? {
+            Command::HGet(key, field) => match self.get_value(&key)? {
                 Some(Value::Hash(map)) => Ok(map.get(&field).cloned()),
                 Some(Value::String(_)) => Err(VaporDBError::TypeMismatch(
                     "Expected hash, found string".into(),
@@ -199,24 +562,27 @@ impl VaporDB {
                 Some(Value::Set(_)) => Err(VaporDBError::TypeMismatch(
                     "Expected hash, found set".into(),
                 )),
+                Some(Value::SortedSet(_)) => Err(VaporDBError::TypeMismatch(
+                    "Expected hash, found sorted set".into(),
+                )),
+                Some(Value::Stream(_)) => Err(VaporDBError::TypeMismatch(
+                    "Expected hash, found stream".into(),
+                )),
                 None => Ok(None),
             },
 
             // HDel command
             Command::HDel(key, field) => {
-                match self.storage.get(&key)? {
+                match self.get_value(&key)? {
                     Some(Value::Hash(mut map)) => {
-                        let _removed = map.remove(&field);
+                        if map.remove(&field).is_none() {
+                            return Ok(None);
+                        }
 
                         if map.is_empty() {
-                            self.storage.del(&key)?;
-                            self.wal.append(LogEntry::Del(key))?;
+                            self.write(key, None)?;
                         } else {
-                            self.storage.set(key.clone(), Value::Hash(map.clone()))?;
-                            self.wal.append(LogEntry::Set(
-                                key,
-                                serde_json::to_string(&Value::Hash(map))?,
-                            ))?;
+                            self.write(key, Some(Value::Hash(map)))?;
                         }
                     }
                     Some(Value::String(_)) => {
@@ -234,6 +600,16 @@ impl VaporDB {
                             "Expected hash, found set".into(),
                         ))
                     }
+                    Some(Value::SortedSet(_)) => {
+                        return Err(VaporDBError::TypeMismatch(
+                            "Expected hash, found sorted set".into(),
+                        ))
+                    }
+                    Some(Value::Stream(_)) => {
+                        return Err(VaporDBError::TypeMismatch(
+                            "Expected hash, found stream".into(),
+                        ))
+                    }
                     None => {}
                 }
 
@@ -242,57 +618,67 @@ impl VaporDB {
 
             // LPush command
             Command::LPush(key, value) => {
-                let mut list = match self.storage.get(&key)? {
+                let mut list = match self.get_value(&key)? {
                     Some(Value::List(list)) => list,
                     _ => Vec::new(),
                 };
 
                 list.insert(0, value);
-                self.storage.set(key, Value::List(list))?;
+                self.write(key, Some(Value::List(list)))?;
                 Ok(None)
             }
 
             // RPush command
             Command::RPush(key, value) => {
-                let mut list = match self.storage.get(&key)? {
+                let mut list = match self.get_value(&key)? {
                     Some(Value::List(list)) => list,
                     _ => Vec::new(),
                 };
 
                 list.push(value);
-                self.storage.set(key, Value::List(list))?;
+                self.write(key, Some(Value::List(list)))?;
                 Ok(None)
             }
 
             // LPop command - Fixed: should remove from beginning, not end
             Command::LPop(key) => {
-                match self.storage.get(&key)? {
+                match self.get_value(&key)? {
                     Some(Value::List(mut list)) => {
                         if !list.is_empty() {
                             let value = list.remove(0); // Remove from beginning for LPop
                             if list.is_empty() {
-                                self.storage.del(&key)?;
+                                self.write(key, None)?;
                             } else {
-                                self.storage.set(key, Value::List(list))?;
+                                self.write(key, Some(Value::List(list)))?;
                             }
                             return Ok(Some(value));
                         }
                         Ok(None) // Empty list
                     }
                     Some(Value::String(_)) => {
-                        return Err(VaporDBError::TypeMismatch(
+                        Err(VaporDBError::TypeMismatch(
                             "Expected list, found string".into(),
-                        ));
+                        ))
                     }
                     Some(Value::Hash(_)) => {
-                        return Err(VaporDBError::TypeMismatch(
+                        Err(VaporDBError::TypeMismatch(
                             "Expected list, found hash".into(),
-                        ));
+                        ))
                     }
                     Some(Value::Set(_)) => {
-                        return Err(VaporDBError::TypeMismatch(
+                        Err(VaporDBError::TypeMismatch(
                             "Expected list, found set".into(),
-                        ));
+                        ))
+                    }
+                    Some(Value::SortedSet(_)) => {
+                        Err(VaporDBError::TypeMismatch(
+                            "Expected list, found sorted set".into(),
+                        ))
+                    }
+                    Some(Value::Stream(_)) => {
+                        Err(VaporDBError::TypeMismatch(
+                            "Expected list, found stream".into(),
+                        ))
                     }
                     None => Ok(None), // No such key
                 }
@@ -300,33 +686,43 @@ impl VaporDB {
 
             // RPop command - Fixed: should remove from end
             Command::RPop(key) => {
-                match self.storage.get(&key)? {
+                match self.get_value(&key)? {
                     Some(Value::List(mut list)) => {
                         if let Some(value) = list.pop() {
                             // Remove from end for RPop
                             if list.is_empty() {
-                                self.storage.del(&key)?;
+                                self.write(key, None)?;
                             } else {
-                                self.storage.set(key, Value::List(list))?;
+                                self.write(key, Some(Value::List(list)))?;
                             }
                             return Ok(Some(value));
                         }
                         Ok(None) // Empty list
                     }
                     Some(Value::String(_)) => {
-                        return Err(VaporDBError::TypeMismatch(
+                        Err(VaporDBError::TypeMismatch(
                             "Expected list, found string".into(),
-                        ));
+                        ))
                     }
                     Some(Value::Hash(_)) => {
-                        return Err(VaporDBError::TypeMismatch(
+                        Err(VaporDBError::TypeMismatch(
                             "Expected list, found hash".into(),
-                        ));
+                        ))
                     }
                     Some(Value::Set(_)) => {
-                        return Err(VaporDBError::TypeMismatch(
+                        Err(VaporDBError::TypeMismatch(
                             "Expected list, found set".into(),
-                        ));
+                        ))
+                    }
+                    Some(Value::SortedSet(_)) => {
+                        Err(VaporDBError::TypeMismatch(
+                            "Expected list, found sorted set".into(),
+                        ))
+                    }
+                    Some(Value::Stream(_)) => {
+                        Err(VaporDBError::TypeMismatch(
+                            "Expected list, found stream".into(),
+                        ))
                     }
                     None => Ok(None), // No such key
                 }
@@ -334,7 +730,7 @@ impl VaporDB {
 
             // LRange command - Fixed: proper range handling
             Command::LRange(key, start, end) => {
-                match self.storage.get(&key)? {
+                match self.get_value(&key)? {
                     Some(Value::List(list)) => {
                         let len = list.len();
                         if len == 0 {
@@ -342,11 +738,11 @@ impl VaporDB {
                         }
 
                         // Handle negative indices and bounds
-                        let start_idx = start as usize;
-                        let end_idx = if end as usize >= len {
+                        let start_idx = start;
+                        let end_idx = if end >= len {
                             len - 1
                         } else {
-                            end as usize
+                            end
                         };
 
                         if start_idx > end_idx || start_idx >= len {
@@ -357,19 +753,29 @@ impl VaporDB {
                         Ok(Some(serde_json::to_string(&range)?))
                     }
                     Some(Value::String(_)) => {
-                        return Err(VaporDBError::TypeMismatch(
+                        Err(VaporDBError::TypeMismatch(
                             "Expected list, found string".into(),
-                        ));
+                        ))
                     }
                     Some(Value::Hash(_)) => {
-                        return Err(VaporDBError::TypeMismatch(
+                        Err(VaporDBError::TypeMismatch(
                             "Expected list, found hash".into(),
-                        ));
+                        ))
                     }
                     Some(Value::Set(_)) => {
-                        return Err(VaporDBError::TypeMismatch(
+                        Err(VaporDBError::TypeMismatch(
                             "Expected list, found set".into(),
-                        ));
+                        ))
+                    }
+                    Some(Value::SortedSet(_)) => {
+                        Err(VaporDBError::TypeMismatch(
+                            "Expected list, found sorted set".into(),
+                        ))
+                    }
+                    Some(Value::Stream(_)) => {
+                        Err(VaporDBError::TypeMismatch(
+                            "Expected list, found stream".into(),
+                        ))
                     }
                     None => Ok(Some("[]".to_string())), // No such key
                 }
@@ -377,25 +783,27 @@ impl VaporDB {
 
             // SAdd command
             Command::SAdd(key, value) => {
-                let mut set = match self.storage.get(&key)? {
+                let mut set = match self.get_value(&key)? {
                     Some(Value::Set(set)) => set,
                     _ => HashSet::new(),
                 };
 
                 set.insert(value);
-                self.storage.set(key, Value::Set(set))?;
+                self.write(key, Some(Value::Set(set)))?;
                 Ok(None)
             }
 
             // SRem command
             Command::SRem(key, value) => {
-                match self.storage.get(&key)? {
+                match self.get_value(&key)? {
                     Some(Value::Set(mut set)) => {
-                        set.remove(&value); // Remove member from the set
+                        if !set.remove(&value) {
+                            return Ok(None); // Not a member, nothing to write
+                        }
                         if set.is_empty() {
-                            self.storage.del(&key)?;
+                            self.write(key, None)?;
                         } else {
-                            self.storage.set(key, Value::Set(set))?;
+                            self.write(key, Some(Value::Set(set)))?;
                         }
                     }
                     Some(Value::String(_)) => {
@@ -413,6 +821,16 @@ impl VaporDB {
                             "Expected set, found list".into(),
                         ));
                     }
+                    Some(Value::SortedSet(_)) => {
+                        return Err(VaporDBError::TypeMismatch(
+                            "Expected set, found sorted set".into(),
+                        ));
+                    }
+                    Some(Value::Stream(_)) => {
+                        return Err(VaporDBError::TypeMismatch(
+                            "Expected set, found stream".into(),
+                        ));
+                    }
                     None => {} // Set doesn't exist
                 }
                 Ok(None)
@@ -420,35 +838,462 @@ impl VaporDB {
 
             // SMembers command
             Command::SMembers(key) => {
-                match self.storage.get(&key)? {
+                match self.get_value(&key)? {
                     Some(Value::Set(set)) => {
                         let members: Vec<String> = set.into_iter().collect();
                         Ok(Some(serde_json::to_string(&members)?))
                     }
                     Some(Value::String(_)) => {
-                        return Err(VaporDBError::TypeMismatch(
+                        Err(VaporDBError::TypeMismatch(
                             "Expected set, found string".into(),
-                        ));
+                        ))
                     }
                     Some(Value::Hash(_)) => {
-                        return Err(VaporDBError::TypeMismatch(
+                        Err(VaporDBError::TypeMismatch(
                             "Expected set, found hash".into(),
-                        ));
+                        ))
                     }
                     Some(Value::List(_)) => {
-                        return Err(VaporDBError::TypeMismatch(
+                        Err(VaporDBError::TypeMismatch(
                             "Expected set, found list".into(),
-                        ));
+                        ))
+                    }
+                    Some(Value::SortedSet(_)) => {
+                        Err(VaporDBError::TypeMismatch(
+                            "Expected set, found sorted set".into(),
+                        ))
+                    }
+                    Some(Value::Stream(_)) => {
+                        Err(VaporDBError::TypeMismatch(
+                            "Expected set, found stream".into(),
+                        ))
                     }
                     None => Ok(None), // No set found
                 }
             } // Removed the unreachable pattern catch-all
+
+            Command::PSetEx(key, ttl_ms, value) => {
+                let expire_at = self.ttl.expire_at(Duration::from_millis(ttl_ms));
+                self.write_version(key, Some(Value::String(value)), Some(expire_at))?;
+                Ok(None)
+            }
+
+            // Each rewrites the value with its new expiry, so the log
+            // carries both
+            Command::Expire(key, secs, condition) => {
+                let expire_at = self.ttl.expire_at(Duration::from_secs(secs));
+                self.expire(key, expire_at, condition)
+            }
+            Command::PExpire(key, ttl_ms, condition) => {
+                let expire_at = self.ttl.expire_at(Duration::from_millis(ttl_ms));
+                self.expire(key, expire_at, condition)
+            }
+            Command::ExpireAt(key, secs, condition) => {
+                self.expire(key, secs.saturating_mul(1000), condition)
+            }
+            Command::PExpireAt(key, expire_at, condition) => {
+                self.expire(key, expire_at, condition)
+            }
+
+            // Seconds are rounded to the nearest, as Redis reports them
+            Command::Ttl(key) => {
+                let ttl = self.time_to_live(&key)?;
+                let secs = if ttl < 0 { ttl } else { (ttl + 500) / 1000 };
+                Ok(Some(secs.to_string()))
+            }
+            Command::PTtl(key) => Ok(Some(self.time_to_live(&key)?.to_string())),
+
+            Command::Persist(key) => {
+                let Some(value) = self.get_value(&key)? else {
+                    return Ok(Some("0".to_string()));
+                };
+                if self.expire_time(&key)?.is_none() {
+                    return Ok(Some("0".to_string()));
+                }
+                self.write_version(key, Some(value), None)?;
+                Ok(Some("1".to_string()))
+            }
+
+            // Returns how many members were added, or with INCR the new
+            // score, if the flags allowed it
+            Command::ZAdd(key, members, flags) => {
+                flags.validate()?;
+                if flags.incr && members.len() != 1 {
+                    return Err(VaporDBError::InvalidArgument(
+                        "ZADD with incr takes a single score and member".into(),
+                    ));
+                }
+                let mut set = self.sorted_set(&key)?.unwrap_or_default();
+                let mut added = 0;
+                let mut changed = false;
+                let mut incremented = None;
+                for (score, member) in members {
+                    let current = set.score(&member);
+                    let score = if flags.incr {
+                        current.unwrap_or(0.0) + score
+                    } else {
+                        score
+                    };
+                    if score.is_nan() {
+                        return Err(VaporDBError::InvalidArgument(
+                            "score is not a number".into(),
+                        ));
+                    }
+                    if !flags.allows(current, score) {
+                        continue;
+                    }
+                    incremented = Some(score);
+                    changed |= current != Some(score);
+                    if set.insert(member, score).is_none() {
+                        added += 1;
+                    }
+                }
+                if changed {
+                    self.write(key, Some(Value::SortedSet(set)))?;
+                }
+                if flags.incr {
+                    Ok(incremented.map(|score| score.to_string()))
+                } else {
+                    Ok(Some(added.to_string()))
+                }
+            }
+
+            Command::ZRem(key, members) => {
+                let Some(mut set) = self.sorted_set(&key)? else {
+                    return Ok(Some("0".to_string()));
+                };
+                let removed = members
+                    .iter()
+                    .filter(|member| set.remove(member).is_some())
+                    .count();
+                if removed > 0 {
+                    self.write_sorted_set(key, set)?;
+                }
+                Ok(Some(removed.to_string()))
+            }
+
+            Command::ZScore(key, member) => Ok(self
+                .sorted_set(&key)?
+                .and_then(|set| set.score(&member))
+                .map(|score| score.to_string())),
+
+            Command::ZRank(key, member) => Ok(self
+                .sorted_set(&key)?
+                .and_then(|set| set.rank(&member))
+                .map(|rank| rank.to_string())),
+
+            Command::ZRange(key, range) => {
+                let ZRange {
+                    by,
+                    rev,
+                    limit,
+                    with_scores,
+                } = range;
+                if limit.is_some() && matches!(by, RangeBy::Index(..)) {
+                    return Err(VaporDBError::InvalidArgument(
+                        "ZRANGE's limit needs a score or lex range".into(),
+                    ));
+                }
+                let Some(set) = self.sorted_set(&key)? else {
+                    return Ok(Some("[]".to_string()));
+                };
+                let members = set.range(&by, rev, limit);
+                if with_scores {
+                    Ok(Some(scored_json(members)?))
+                } else {
+                    let members: Vec<&str> = members.into_iter().map(|(member, _)| member).collect();
+                    Ok(Some(serde_json::to_string(&members)?))
+                }
+            }
+
+            Command::ZPopMin(key, count) => self.pop_sorted_set(key, count, false),
+            Command::ZPopMax(key, count) => self.pop_sorted_set(key, count, true),
+
+            Command::ZCard(key) => {
+                let len = self.sorted_set(&key)?.map_or(0, |set| set.len());
+                Ok(Some(len.to_string()))
+            }
+
+            // Returns the new entry's ID
+            Command::XAdd(key, id, fields, max_len) => {
+                let mut stream = self.stream(&key)?.unwrap_or_default();
+                let id = stream.add(id, fields, self.ttl.now())?;
+                if let Some(max_len) = max_len {
+                    stream.trim(max_len);
+                }
+                self.write(key, Some(Value::Stream(stream)))?;
+                Ok(Some(id.to_string()))
+            }
+
+            Command::XRange(key, start, end, count) => {
+                self.stream_range(&key, start, end, false, count)
+            }
+            Command::XRevRange(key, start, end, count) => {
+                self.stream_range(&key, start, end, true, count)
+            }
+            Command::XRead(key, after, count) => {
+                self.stream_range(&key, Bound::Excluded(after), Bound::Unbounded, false, count)
+            }
+
+            Command::XLen(key) => {
+                let len = self.stream(&key)?.map_or(0, |stream| stream.len());
+                Ok(Some(len.to_string()))
+            }
+
+            Command::XGroupCreate(key, group, start, make_stream) => {
+                let mut stream = match self.stream(&key)? {
+                    Some(stream) => stream,
+                    None if make_stream => Stream::new(),
+                    None => {
+                        return Err(VaporDBError::InvalidArgument(format!(
+                            "no stream at '{key}', create it with mkstream"
+                        )));
+                    }
+                };
+                stream.create_group(group, start)?;
+                self.write(key, Some(Value::Stream(stream)))?;
+                Ok(None)
+            }
+
+            // Delivering new entries moves the group on and fills its pending
+            // entries list, so it writes like any other command
+            Command::XReadGroup(key, group, consumer, after, count) => {
+                let mut stream = self.stream(&key)?.unwrap_or_default();
+                let entries = stream.read_group(&group, &consumer, after, count, self.ttl.now())?;
+                if after.is_none() && !entries.is_empty() {
+                    self.write(key, Some(Value::Stream(stream)))?;
+                }
+                Ok(Some(serde_json::to_string(&entries)?))
+            }
+
+            Command::XAck(key, group, ids) => {
+                let Some(mut stream) = self.stream(&key)? else {
+                    return Ok(Some("0".to_string()));
+                };
+                let acked = stream.ack(&group, &ids)?;
+                if acked > 0 {
+                    self.write(key, Some(Value::Stream(stream)))?;
+                }
+                Ok(Some(acked.to_string()))
+            }
+
+            Command::XPending(key, group) => {
+                let stream = self.stream(&key)?.unwrap_or_default();
+                let now = self.ttl.now();
+                let pending: Vec<serde_json::Value> = stream
+                    .pending(&group)?
+                    .into_iter()
+                    .map(|(id, pending)| {
+                        serde_json::json!({
+                            "id": id,
+                            "consumer": pending.consumer,
+                            "idle_ms": now.saturating_sub(pending.delivered_at),
+                            "deliveries": pending.deliveries,
+                        })
+                    })
+                    .collect();
+                Ok(Some(serde_json::to_string(&pending)?))
+            }
+
+            // Claiming can also drop trimmed entries from the pending entries
+            // list, so the stream is written back even if nothing was claimed
+            Command::XClaim(key, group, consumer, min_idle, ids) => {
+                let Some(mut stream) = self.stream(&key)? else {
+                    return Err(VaporDBError::InvalidArgument(format!(
+                        "no such consumer group '{group}'"
+                    )));
+                };
+                let claimed = stream.claim(&group, &consumer, min_idle, &ids, self.ttl.now())?;
+                self.write(key, Some(Value::Stream(stream)))?;
+                Ok(Some(serde_json::to_string(&claimed)?))
+            }
+
+            Command::Incr(key) => self.increment(key, 1),
+            Command::Decr(key) => self.increment(key, -1),
+            Command::IncrBy(key, by) => self.increment(key, by),
+            Command::IncrByFloat(key, by) => {
+                let current = self.string(&key)?;
+                let value = format_float(add_float(current.as_deref(), by)?);
+                self.write(key, Some(Value::String(value.clone())))?;
+                Ok(Some(value))
+            }
+
+            Command::HIncrBy(key, field, by) => {
+                let mut map = self.hash(&key)?.unwrap_or_default();
+                let value = add_int(map.get(&field).map(String::as_str), by)?.to_string();
+                map.insert(field, value.clone());
+                self.write(key, Some(Value::Hash(map)))?;
+                Ok(Some(value))
+            }
+            Command::HIncrByFloat(key, field, by) => {
+                let mut map = self.hash(&key)?.unwrap_or_default();
+                let value = format_float(add_float(map.get(&field).map(String::as_str), by)?);
+                map.insert(field, value.clone());
+                self.write(key, Some(Value::Hash(map)))?;
+                Ok(Some(value))
+            }
+        }
+    }
+
+    // The string at `key`, if there is one
+    fn string(&self, key: &str) -> Result<Option<String>> {
+        match self.get_value(key)? {
+            Some(Value::String(value)) => Ok(Some(value)),
+            Some(other) => Err(VaporDBError::TypeMismatch(format!(
+                "Expected string, found {}",
+                other.type_name()
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    // The hash at `key`, if there is one
+    fn hash(&self, key: &str) -> Result<Option<HashMap<String, String>>> {
+        match self.get_value(key)? {
+            Some(Value::Hash(map)) => Ok(Some(map)),
+            Some(other) => Err(VaporDBError::TypeMismatch(format!(
+                "Expected hash, found {}",
+                other.type_name()
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    // Adds `by` to the integer at `key` in a single write, keeping its expiry
+    fn increment(&mut self, key: String, by: i64) -> Result<Option<String>> {
+        let current = self.string(&key)?;
+        let value = add_int(current.as_deref(), by)?.to_string();
+        self.write(key, Some(Value::String(value.clone())))?;
+        Ok(Some(value))
+    }
+
+    // The stream at `key`, if there is one
+    fn stream(&self, key: &str) -> Result<Option<Stream>> {
+        match self.get_value(key)? {
+            Some(Value::Stream(stream)) => Ok(Some(stream)),
+            Some(other) => Err(VaporDBError::TypeMismatch(format!(
+                "Expected stream, found {}",
+                other.type_name()
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    fn stream_range(
+        &self,
+        key: &str,
+        start: Bound<StreamId>,
+        end: Bound<StreamId>,
+        rev: bool,
+        count: Option<usize>,
+    ) -> Result<Option<String>> {
+        let entries = self
+            .stream(key)?
+            .map(|stream| stream.range(start, end, rev, count))
+            .unwrap_or_default();
+        Ok(Some(serde_json::to_string(&entries)?))
+    }
+
+    // The sorted set at `key`, if there is one
+    fn sorted_set(&self, key: &str) -> Result<Option<SortedSet>> {
+        match self.get_value(key)? {
+            Some(Value::SortedSet(set)) => Ok(Some(set)),
+            Some(other) => Err(VaporDBError::TypeMismatch(format!(
+                "Expected sorted set, found {}",
+                other.type_name()
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    // Stores `set`, deleting the key once it is empty
+    fn write_sorted_set(&mut self, key: String, set: SortedSet) -> Result<()> {
+        if set.is_empty() {
+            self.write(key, None)
+        } else {
+            self.write(key, Some(Value::SortedSet(set)))
         }
     }
 
+    // Removes up to `count` of the lowest, or if `max` the highest, scoring
+    // members of the sorted set at `key`
+    fn pop_sorted_set(&mut self, key: String, count: usize, max: bool) -> Result<Option<String>> {
+        let Some(mut set) = self.sorted_set(&key)? else {
+            return Ok(Some("[]".to_string()));
+        };
+        let popped = if max {
+            set.pop_max(count)
+        } else {
+            set.pop_min(count)
+        };
+        if !popped.is_empty() {
+            self.write_sorted_set(key, set)?;
+        }
+        let popped = popped
+            .iter()
+            .map(|(member, score)| (member.as_str(), *score));
+        Ok(Some(scored_json(popped)?))
+    }
+
     pub fn set_with_expiration(&mut self, key: String, value: String, ttl_secs: u64) -> Result<()> {
-        self.execute(Command::Set(key.clone(), value))?;
-        self.ttl.set(key, Duration::from_secs(ttl_secs));
+        self.execute(Command::PSetEx(key, ttl_secs.saturating_mul(1000), value))?;
         Ok(())
     }
 }
+
+// Members and their scores as a JSON array of [member, score] pairs. Scores
+// are strings, as ZSCORE returns them, so that infinities survive.
+fn scored_json<'a>(members: impl IntoIterator<Item = (&'a str, f64)>) -> Result<String> {
+    let members: Vec<(&str, String)> = members
+        .into_iter()
+        .map(|(member, score)| (member, score.to_string()))
+        .collect();
+    Ok(serde_json::to_string(&members)?)
+}
+
+// `current` plus `by`, where a missing value counts as 0
+fn add_int(current: Option<&str>, by: i64) -> Result<i64> {
+    let current: i64 = match current {
+        Some(current) => current.parse().map_err(|_| {
+            VaporDBError::NotANumber(format!("'{current}' is not an integer"))
+        })?,
+        None => 0,
+    };
+    current
+        .checked_add(by)
+        .ok_or_else(|| VaporDBError::Overflow(format!("{current} + {by} is out of range")))
+}
+
+// Like `add_int`, refusing results that are not finite, which could not be
+// added to again
+fn add_float(current: Option<&str>, by: f64) -> Result<f64> {
+    let current: f64 = match current {
+        Some(current) => current
+            .parse()
+            .ok()
+            .filter(|current: &f64| current.is_finite())
+            .ok_or_else(|| VaporDBError::NotANumber(format!("'{current}' is not a number")))?,
+        None => 0.0,
+    };
+    if !by.is_finite() {
+        return Err(VaporDBError::NotANumber(format!("{by} is not a finite increment")));
+    }
+    let sum = current + by;
+    if !sum.is_finite() {
+        return Err(VaporDBError::Overflow(format!("{current} + {by} is out of range")));
+    }
+    Ok(sum)
+}
+
+// The shortest form that parses back to the same value. Display would spell
+// 1e21 out as 22 digits, so this keeps the exponent Debug writes for very
+// large and small magnitudes, and drops the `.0` it gives whole numbers
+fn format_float(value: f64) -> String {
+    // -0 reads as 0, like any other zero a counter reaches
+    let value = if value == 0.0 { 0.0 } else { value };
+    let formatted = format!("{value:?}");
+    match formatted.strip_suffix(".0") {
+        Some(whole) => whole.to_string(),
+        None => formatted,
+    }
+}
@@ -19,6 +19,34 @@ pub enum VaporDBError {
 
     #[error("Compaction error: {0}")]
     CompactionFailed(String),
+
+    #[error("Corruption detected: {0}")]
+    Corruption(String),
+
+    #[error("Sequence {0} is no longer retained in the WAL")]
+    SequenceUnavailable(u64),
+
+    #[error("Not a number: {0}")]
+    NotANumber(String),
+
+    #[error("Overflow: {0}")]
+    Overflow(String),
+
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+}
+
+impl VaporDBError {
+    /// Whether the request itself was at fault rather than the database.
+    pub fn is_client_error(&self) -> bool {
+        matches!(
+            self,
+            Self::InvalidArgument(_)
+                | Self::TypeMismatch(_)
+                | Self::NotANumber(_)
+                | Self::Overflow(_)
+        )
+    }
 }
 
 pub type Result<T> = std::result::Result<T, VaporDBError>;
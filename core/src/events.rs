@@ -0,0 +1,299 @@
+// Keyspace notifications. Every write the database makes, every key the TTL
+// daemon or a read finds expired and every key FIFO compaction drops is
+// published on an in-process bus as a `KeyEvent`. Subscribers get their own
+// bounded channel; one that falls behind misses events rather than holding
+// up writers. An event of a write is held back until the write is as durable
+// as the WAL's policy promises, so a crash never takes back a write that was
+// already announced. Under the default `Durability::EverySec` that is the
+// next background sync, up to a second after the write.
+
+use crate::command::Command;
+use crate::error::{Result, VaporDBError};
+use crate::storage::internal_key::SequenceNumber;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
+const SUBSCRIBER_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Set,
+    Del,
+    Expire,
+    Persist,
+    Expired, // removed once its expiry passed
+    Evicted, // dropped by FIFO compaction
+    HSet,
+    HDel,
+    LPush,
+    RPush,
+    LPop,
+    RPop,
+    SAdd,
+    SRem,
+    ZAdd,
+    ZRem,
+    ZPopMin,
+    ZPopMax,
+    XAdd,
+    XGroupCreate,
+    IncrBy,
+    IncrByFloat,
+    HIncrBy,
+    HIncrByFloat,
+}
+
+impl EventKind {
+    pub fn class(self) -> EventClass {
+        match self {
+            Self::Del | Self::Expire | Self::Persist => EventClass::Generic,
+            Self::Set | Self::IncrBy | Self::IncrByFloat => EventClass::String,
+            Self::Expired => EventClass::Expired,
+            Self::Evicted => EventClass::Evicted,
+            Self::HSet | Self::HDel | Self::HIncrBy | Self::HIncrByFloat => EventClass::Hash,
+            Self::LPush | Self::RPush | Self::LPop | Self::RPop => EventClass::List,
+            Self::SAdd | Self::SRem => EventClass::Set,
+            Self::ZAdd | Self::ZRem | Self::ZPopMin | Self::ZPopMax => EventClass::ZSet,
+            Self::XAdd | Self::XGroupCreate => EventClass::Stream,
+        }
+    }
+
+    /// The event a command publishes if it writes, `None` for reads.
+    pub fn of(cmd: &Command) -> Option<Self> {
+        match cmd {
+            Command::Set(..) | Command::PSetEx(..) => Some(Self::Set),
+            Command::Del(_) => Some(Self::Del),
+            Command::HSet(..) => Some(Self::HSet),
+            Command::HDel(..) => Some(Self::HDel),
+            Command::LPush(..) => Some(Self::LPush),
+            Command::RPush(..) => Some(Self::RPush),
+            Command::LPop(_) => Some(Self::LPop),
+            Command::RPop(_) => Some(Self::RPop),
+            Command::SAdd(..) => Some(Self::SAdd),
+            Command::SRem(..) => Some(Self::SRem),
+            Command::Expire(..)
+            | Command::PExpire(..)
+            | Command::ExpireAt(..)
+            | Command::PExpireAt(..) => Some(Self::Expire),
+            Command::Persist(_) => Some(Self::Persist),
+            Command::ZAdd(..) => Some(Self::ZAdd),
+            Command::ZRem(..) => Some(Self::ZRem),
+            Command::ZPopMin(..) => Some(Self::ZPopMin),
+            Command::ZPopMax(..) => Some(Self::ZPopMax),
+            Command::XAdd(..) => Some(Self::XAdd),
+            Command::XGroupCreate(..) => Some(Self::XGroupCreate),
+            Command::Incr(_) | Command::Decr(_) | Command::IncrBy(..) => Some(Self::IncrBy),
+            Command::IncrByFloat(..) => Some(Self::IncrByFloat),
+            Command::HIncrBy(..) => Some(Self::HIncrBy),
+            Command::HIncrByFloat(..) => Some(Self::HIncrByFloat),
+            Command::Get(_)
+            | Command::HGet(..)
+            | Command::LRange(..)
+            | Command::SMembers(_)
+            | Command::Ttl(_)
+            | Command::PTtl(_)
+            | Command::ZScore(..)
+            | Command::ZRank(..)
+            | Command::ZRange(..)
+            | Command::ZCard(_)
+            | Command::XRange(..)
+            | Command::XRevRange(..)
+            | Command::XRead(..)
+            | Command::XLen(_)
+            | Command::XPending(..) => None,
+            // Consumer group bookkeeping is written but not announced
+            Command::XReadGroup(..) | Command::XAck(..) | Command::XClaim(..) => None,
+        }
+    }
+}
+
+/// Groups of events that are switched on and subscribed to together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventClass {
+    Generic, // del, expire, persist
+    String,
+    List,
+    Set,
+    Hash,
+    Expired,
+    Evicted,
+    ZSet, // sorted sets
+    Stream,
+}
+
+impl EventClass {
+    pub const ALL: [EventClass; 9] = [
+        Self::Generic,
+        Self::String,
+        Self::List,
+        Self::Set,
+        Self::Hash,
+        Self::Expired,
+        Self::Evicted,
+        Self::ZSet,
+        Self::Stream,
+    ];
+
+    /// Parses a comma-separated list of classes, where `all` stands for
+    /// every class.
+    pub fn parse_list(s: &str) -> Result<Vec<Self>> {
+        let mut classes = Vec::new();
+        for name in s.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            if name.eq_ignore_ascii_case("all") {
+                classes.extend(Self::ALL);
+            } else {
+                classes.push(name.parse()?);
+            }
+        }
+        Ok(classes)
+    }
+}
+
+impl FromStr for EventClass {
+    type Err = VaporDBError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "generic" => Ok(Self::Generic),
+            "string" => Ok(Self::String),
+            "list" => Ok(Self::List),
+            "set" => Ok(Self::Set),
+            "hash" => Ok(Self::Hash),
+            "expired" => Ok(Self::Expired),
+            "evicted" => Ok(Self::Evicted),
+            "zset" => Ok(Self::ZSet),
+            "stream" => Ok(Self::Stream),
+            _ => Err(VaporDBError::Internal(format!(
+                "unknown event class '{s}', expected generic, string, list, set, hash, expired, evicted, zset or stream"
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyEvent {
+    pub kind: EventKind,
+    pub key: String,
+}
+
+struct Subscriber {
+    classes: HashSet<EventClass>,
+    sender: SyncSender<KeyEvent>,
+}
+
+// Events of writes a crash could still lose, held back until they are not
+#[derive(Default)]
+struct Held {
+    durable: SequenceNumber, // newest write known to be durable
+    events: VecDeque<(SequenceNumber, EventKind, String)>,
+}
+
+pub struct EventBus {
+    enabled: RwLock<HashSet<EventClass>>,
+    subscribers: Mutex<Vec<Subscriber>>,
+    held: Mutex<Held>,
+    dropped: AtomicU64,
+}
+
+impl EventBus {
+    /// A bus publishing only events of the `enabled` classes.
+    pub fn new(enabled: impl IntoIterator<Item = EventClass>) -> Self {
+        Self {
+            enabled: RwLock::new(enabled.into_iter().collect()),
+            subscribers: Mutex::new(Vec::new()),
+            held: Mutex::new(Held::default()),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn enabled(&self) -> HashSet<EventClass> {
+        self.enabled.read().clone()
+    }
+
+    /// Switches publishing to the `enabled` classes from now on.
+    pub fn set_enabled(&self, enabled: impl IntoIterator<Item = EventClass>) {
+        *self.enabled.write() = enabled.into_iter().collect();
+    }
+
+    /// Receives the events of `classes` published from now on, for as long
+    /// as the receiver is kept.
+    pub fn subscribe(&self, classes: impl IntoIterator<Item = EventClass>) -> Receiver<KeyEvent> {
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_CAPACITY);
+        self.subscribers.lock().push(Subscriber {
+            classes: classes.into_iter().collect(),
+            sender,
+        });
+        receiver
+    }
+
+    /// Events never delivered because a subscriber's channel was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn publish(&self, kind: EventKind, key: &str) {
+        let class = kind.class();
+        if !self.enabled.read().contains(&class) {
+            return;
+        }
+        let mut subscribers = self.subscribers.lock();
+        subscribers.retain(|subscriber| {
+            if !subscriber.classes.contains(&class) {
+                return true;
+            }
+            let event = KeyEvent {
+                kind,
+                key: key.to_string(),
+            };
+            match subscriber.sender.try_send(event) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    /// Publishes an event of the write numbered `seq` once `release` says
+    /// that write is durable, so no one hears of a write a crash could undo.
+    pub fn publish_when_durable(&self, seq: SequenceNumber, kind: EventKind, key: &str) {
+        if !self.wants(kind.class()) {
+            return;
+        }
+        let mut held = self.held.lock();
+        if seq <= held.durable {
+            self.publish(kind, key);
+        } else {
+            held.events.push_back((seq, kind, key.to_string()));
+        }
+    }
+
+    /// Publishes the held events of writes up to `seq`, which is durable.
+    pub fn release(&self, seq: SequenceNumber) {
+        let mut held = self.held.lock();
+        held.durable = held.durable.max(seq);
+        while held.events.front().is_some_and(|(event_seq, ..)| *event_seq <= seq) {
+            let (_, kind, key) = held.events.pop_front().unwrap();
+            self.publish(kind, &key);
+        }
+    }
+
+    // Whether anyone would receive an event of `class`, so publishers can
+    // skip work that only feeds the bus
+    pub(crate) fn wants(&self, class: EventClass) -> bool {
+        self.enabled.read().contains(&class)
+            && self
+                .subscribers
+                .lock()
+                .iter()
+                .any(|subscriber| subscriber.classes.contains(&class))
+    }
+}
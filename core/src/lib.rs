@@ -1,6 +1,11 @@
+pub mod backup;
+pub mod clock;
 pub mod command;
 pub mod db;
 pub mod error;
+pub mod events;
+pub mod options;
+pub mod snapshot;
 pub mod storage;
 pub mod ttl_daemon;
 pub mod ttl;
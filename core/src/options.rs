@@ -0,0 +1,72 @@
+use crate::clock::{Clock, SystemClock};
+use crate::events::EventClass;
+use crate::storage::compaction::CompactionStyle;
+use crate::storage::sst::{DEFAULT_BLOOM_BITS_PER_KEY, SSTableOptions};
+use crate::wal::group_commit::EVERYSEC_INTERVAL;
+use crate::wal::wal::{Durability, WalRecoveryMode};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct VaporDBOptions {
+    pub sst_dir: PathBuf,                   // directory where SSTs are stored
+    pub flush_threshold: usize,             // flush when this many keys are in MemTable
+    pub max_immutable_memtables: usize,     // stall writes while this many await flushing
+    pub bloom_bits_per_key: usize,          // Bloom filter size per SSTable key, 0 disables
+    pub compaction_style: CompactionStyle,  // which CompactionStrategy picks work
+    pub level0_compaction_trigger: usize,   // compact level 0 once it has this many files
+    pub level_base_bytes: u64,              // size budget of level 1
+    pub level_size_multiplier: u64,         // each deeper level may be this much larger
+    pub target_file_size: u64,              // compaction output files are cut at this size
+    pub size_tiered_min_merge_width: usize, // merge once this many similar runs exist
+    pub size_tiered_max_merge_width: usize, // never merge more runs than this at once
+    pub size_tiered_size_ratio: f64,        // runs within this factor count as similar
+    pub fifo_max_bytes: u64,                // FIFO drops the oldest files above this size
+    pub fifo_max_age: Option<Duration>,     // FIFO drops files older than this
+    pub wal_recovery_mode: WalRecoveryMode, // how replay treats corruption mid-log
+    pub durability: Durability,             // when WAL appends are forced to disk
+    pub wal_sync_interval: Duration,        // how often EverySec forces the WAL to disk
+    pub wal_retention: Duration,            // keep flushed WAL segments this long for change streams
+    pub wal_archive_dir: Option<PathBuf>,   // move purged WAL segments here for point-in-time recovery
+    pub expire_cycle_budget: Duration,      // time one active expiration cycle may spend deleting keys
+    pub notify_events: Vec<EventClass>,     // keyspace event classes published to subscribers
+    pub clock: Arc<dyn Clock>,              // time source for expiry, a MockClock in tests
+}
+
+impl Default for VaporDBOptions {
+    fn default() -> Self {
+        Self {
+            sst_dir: PathBuf::from("sstables"),
+            flush_threshold: 1000,
+            max_immutable_memtables: 4,
+            bloom_bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+            compaction_style: CompactionStyle::Leveled,
+            level0_compaction_trigger: 4,
+            level_base_bytes: 10 * 1024 * 1024,
+            level_size_multiplier: 10,
+            target_file_size: 2 * 1024 * 1024,
+            size_tiered_min_merge_width: 4,
+            size_tiered_max_merge_width: 32,
+            size_tiered_size_ratio: 1.5,
+            fifo_max_bytes: 1024 * 1024 * 1024,
+            fifo_max_age: None,
+            wal_recovery_mode: WalRecoveryMode::Fail,
+            durability: Durability::EverySec,
+            wal_sync_interval: EVERYSEC_INTERVAL,
+            wal_retention: Duration::ZERO,
+            wal_archive_dir: None,
+            expire_cycle_budget: Duration::from_millis(25),
+            notify_events: EventClass::ALL.to_vec(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl VaporDBOptions {
+    pub fn sst_options(&self) -> SSTableOptions {
+        SSTableOptions {
+            bloom_bits_per_key: self.bloom_bits_per_key,
+        }
+    }
+}
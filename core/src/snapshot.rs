@@ -0,0 +1,125 @@
+use crate::error::Result;
+use crate::storage::Value;
+use crate::storage::internal_key::SequenceNumber;
+use crate::storage::memtable_set::MemTableSet;
+use crate::storage::scan::{EntryCursor, KeyRange, ScanIter};
+use crate::storage::snapshots::SnapshotList;
+use crate::storage::sst::RangeIter;
+use crate::storage::table_set::TableSet;
+use parking_lot::RwLock;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+/// A consistent, read-only view of the database as of one sequence number.
+/// Writes, flushes and compactions made after it was taken are invisible to
+/// it, and the versions it can see are kept on disk until it is dropped.
+pub struct Snapshot {
+    seq: SequenceNumber,
+    memtables: Arc<RwLock<MemTableSet>>,
+    tables: Arc<RwLock<TableSet>>,
+    list: Arc<SnapshotList>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        seq: SequenceNumber,
+        memtables: Arc<RwLock<MemTableSet>>,
+        tables: Arc<RwLock<TableSet>>,
+        list: Arc<SnapshotList>,
+    ) -> Self {
+        list.acquire(seq);
+        Self {
+            seq,
+            memtables,
+            tables,
+            list,
+        }
+    }
+
+    pub fn sequence(&self) -> SequenceNumber {
+        self.seq
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Value>> {
+        get_at(&self.memtables, &self.tables, key, self.seq)
+    }
+
+    /// Live keys in `range` as of this snapshot, in key order. Iterate it
+    /// with `.rev()` to walk the range backwards.
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> ScanIter {
+        self.scan_range(KeyRange::new(range))
+    }
+
+    /// Live keys beginning with `prefix` as of this snapshot, in key order.
+    pub fn scan_prefix(&self, prefix: &str) -> ScanIter {
+        self.scan_range(KeyRange::prefix(prefix))
+    }
+
+    fn scan_range(&self, range: KeyRange) -> ScanIter {
+        // The scan pins the versions it reads for as long as it lives, even
+        // if this snapshot is dropped first
+        let pinned = Snapshot::new(
+            self.seq,
+            Arc::clone(&self.memtables),
+            Arc::clone(&self.tables),
+            Arc::clone(&self.list),
+        );
+        ScanIter::new(
+            self.seq,
+            Arc::clone(self.tables.read().clock()),
+            Box::new(move |reverse| pinned.cursors(&range, reverse)),
+        )
+    }
+
+    // One cursor per source that may hold keys in `range`, newest first
+    fn cursors(&self, range: &KeyRange, reverse: bool) -> Result<Vec<EntryCursor>> {
+        let mut cursors: Vec<EntryCursor> = Vec::new();
+        for memtable in self.memtables.read().all() {
+            let mut entries = memtable.range_entries(range);
+            if reverse {
+                entries.reverse();
+            }
+            cursors.push(Box::new(entries.into_iter().map(Ok)));
+        }
+
+        let tables = self.tables.read();
+        for file in tables.manifest().version().files() {
+            if range.is_after_end(&file.smallest_key) || range.is_before_start(&file.largest_key) {
+                continue;
+            }
+            if let Some(sst) = tables.table(file.number) {
+                cursors.push(Box::new(RangeIter::new(sst, range.clone(), reverse)));
+            }
+        }
+        Ok(cursors)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.list.release(self.seq);
+    }
+}
+
+// Looks a key up in the MemTables, newest first, then in the SSTables. A
+// tombstone or expired entry hides older versions.
+pub(crate) fn get_at(
+    memtables: &RwLock<MemTableSet>,
+    tables: &RwLock<TableSet>,
+    key: &str,
+    seq: SequenceNumber,
+) -> Result<Option<Value>> {
+    // The MemTables must be read before the SSTables: a flush installs its
+    // table before dropping the MemTable it came from
+    for memtable in memtables.read().all() {
+        if let Some(entry) = memtable.get_entry_at(key, seq) {
+            return Ok(entry);
+        }
+    }
+
+    let tables = tables.read();
+    match tables.get_entry_at(key, seq)? {
+        Some(entry) if !entry.is_expired(tables.clock().now_millis()) => Ok(entry.value),
+        _ => Ok(None),
+    }
+}
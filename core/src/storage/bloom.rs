@@ -0,0 +1,117 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Bloom filter over the keys of one SSTable. Encoded as the bit array
+/// followed by a single byte holding the number of probes.
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    num_probes: u32,
+}
+
+impl BloomFilter {
+    pub fn build(key_hashes: &[u64], bits_per_key: usize) -> Self {
+        // ln(2) * bits_per_key probes minimises the false-positive rate
+        let num_probes = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        let num_bits = (key_hashes.len() * bits_per_key).max(64);
+        let mut bits = vec![0u8; num_bits.div_ceil(8)];
+        let num_bits = bits.len() * 8;
+
+        for &hash in key_hashes {
+            for bit in probes(hash, num_probes, num_bits) {
+                bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+
+        Self { bits, num_probes }
+    }
+
+    pub fn may_contain(&self, key: &str) -> bool {
+        let num_bits = self.bits.len() * 8;
+        if num_bits == 0 {
+            return true;
+        }
+        probes(hash_key(key), self.num_probes, num_bits)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = self.bits.clone();
+        buf.push(self.num_probes as u8);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let (&num_probes, bits) = buf.split_last()?;
+        Some(Self {
+            bits: bits.to_vec(),
+            num_probes: num_probes as u32,
+        })
+    }
+}
+
+// Double hashing: probe i is h1 + i * h2, with both halves taken from one
+// 64-bit hash.
+fn probes(hash: u64, num_probes: u32, num_bits: usize) -> impl Iterator<Item = usize> {
+    let h1 = hash as u32;
+    let h2 = (hash >> 32) as u32 | 1;
+    (0..num_probes).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) as usize % num_bits)
+}
+
+/// Stable 64-bit key hash (FNV-1a with a splitmix64 finaliser). Filters are
+/// persisted, so this must never change between releases.
+pub fn hash_key(key: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key.as_bytes() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58476d1ce4e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+/// Counters for tuning `bits_per_key`, shared by every SSTable of a database.
+#[derive(Debug, Default)]
+pub struct FilterStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    false_positives: AtomicU64,
+}
+
+impl FilterStats {
+    pub(crate) fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_miss(&self, found: bool) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        if !found {
+            self.false_positives.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Lookups the filter answered on its own, skipping the block read.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Lookups the filter let through to a block read.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Misses where the block turned out not to contain the key.
+    pub fn false_positives(&self) -> u64 {
+        self.false_positives.load(Ordering::Relaxed)
+    }
+
+    pub fn false_positive_rate(&self) -> f64 {
+        let absent = self.hits() + self.false_positives();
+        if absent == 0 {
+            return 0.0;
+        }
+        self.false_positives() as f64 / absent as f64
+    }
+}
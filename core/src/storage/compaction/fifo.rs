@@ -0,0 +1,36 @@
+// FIFO compaction for cache-like data. Files are never merged; once the
+// database is over its size budget, or a file is older than the configured
+// age, whole files are dropped oldest first.
+
+use super::{Compaction, CompactionStrategy};
+use crate::options::VaporDBOptions;
+use crate::storage::manifest::{FileMeta, Version};
+
+#[derive(Default)]
+pub struct FifoCompaction;
+
+impl CompactionStrategy for FifoCompaction {
+    fn pick(&mut self, version: &Version, options: &VaporDBOptions) -> Option<Compaction> {
+        let mut files: Vec<&FileMeta> = version.files().collect();
+        files.sort_by_key(|f| f.largest_seq);
+
+        let now = options.clock.now_millis() / 1000;
+        let mut total: u64 = files.iter().map(|f| f.file_size).sum();
+        let mut expired = vec![];
+        for file in files {
+            let too_old = options
+                .fifo_max_age
+                .is_some_and(|age| now.saturating_sub(file.created_at) >= age.as_secs());
+            if !too_old && total <= options.fifo_max_bytes {
+                break;
+            }
+            total -= file.file_size;
+            expired.push(file.clone());
+        }
+
+        if expired.is_empty() {
+            return None;
+        }
+        Some(Compaction::delete(expired))
+    }
+}
@@ -0,0 +1,80 @@
+// Leveled compaction. Level 0 holds whole MemTable flushes whose key ranges
+// may overlap; every deeper level is one sorted run of non-overlapping files,
+// and each is allowed `level_size_multiplier` times the bytes of the level
+// above it. A level's score is how far it is over budget (file count for
+// level 0, bytes for the others). The highest-scoring level is merged into
+// the next one until every score is below 1.
+
+use super::{Compaction, CompactionStrategy, key_range, overlaps};
+use crate::options::VaporDBOptions;
+use crate::storage::manifest::{FileMeta, NUM_LEVELS, Version};
+
+pub struct LeveledCompaction {
+    // Where the last compaction of each level stopped, so successive
+    // compactions walk the whole key space round-robin
+    compact_pointers: Vec<Option<String>>,
+}
+
+impl Default for LeveledCompaction {
+    fn default() -> Self {
+        Self {
+            compact_pointers: vec![None; NUM_LEVELS],
+        }
+    }
+}
+
+impl LeveledCompaction {
+    pub fn score(version: &Version, level: usize, options: &VaporDBOptions) -> f64 {
+        let files = &version.levels[level];
+        if level == 0 {
+            return files.len() as f64 / options.level0_compaction_trigger.max(1) as f64;
+        }
+        let bytes: u64 = files.iter().map(|f| f.file_size).sum();
+        bytes as f64 / max_bytes_for_level(level, options) as f64
+    }
+}
+
+impl CompactionStrategy for LeveledCompaction {
+    fn pick(&mut self, version: &Version, options: &VaporDBOptions) -> Option<Compaction> {
+        // The last level has nowhere to go
+        let (level, score) = (0..NUM_LEVELS - 1)
+            .map(|level| (level, Self::score(version, level, options)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))?;
+        if score < 1.0 {
+            return None;
+        }
+
+        let mut inputs: Vec<FileMeta> = if level == 0 {
+            // Level 0 files overlap, so they all go down together
+            version.levels[0].clone()
+        } else {
+            let files = &version.levels[level];
+            let pointer = self.compact_pointers[level].as_deref();
+            let file = files
+                .iter()
+                .find(|f| pointer.is_none_or(|p| f.largest_key.as_str() > p))
+                .unwrap_or(&files[0]);
+            vec![file.clone()]
+        };
+
+        let (smallest, largest) = key_range(&inputs);
+        let output_level = level + 1;
+        inputs.extend(
+            version.levels[output_level]
+                .iter()
+                .filter(|f| overlaps(f, &smallest, &largest))
+                .cloned(),
+        );
+        self.compact_pointers[level] = Some(largest);
+
+        Some(Compaction::merge(output_level, inputs))
+    }
+}
+
+fn max_bytes_for_level(level: usize, options: &VaporDBOptions) -> u64 {
+    let mut bytes = options.level_base_bytes;
+    for _ in 1..level {
+        bytes = bytes.saturating_mul(options.level_size_multiplier);
+    }
+    bytes
+}
@@ -0,0 +1,374 @@
+// Compaction keeps the number of SSTables a read has to probe in check by
+// rewriting them in the background. What to compact is decided by a
+// `CompactionStrategy`; this module runs the chosen work on a worker thread.
+
+mod fifo;
+mod leveled;
+mod size_tiered;
+
+pub use fifo::FifoCompaction;
+pub use leveled::LeveledCompaction;
+pub use size_tiered::SizeTieredCompaction;
+
+use crate::error::{Result, VaporDBError};
+use crate::events::{EventBus, EventClass, EventKind};
+use crate::options::VaporDBOptions;
+use crate::storage::internal_key::SequenceNumber;
+use crate::storage::manifest::{FileMeta, Version, VersionEdit};
+use crate::storage::snapshots::{SnapshotList, VersionFilter};
+use crate::storage::sst::{MergingIter, SSTable, SSTableWriter};
+use crate::storage::table_set::TableSet;
+use parking_lot::{Mutex, RwLock};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+
+/// Decides which files to compact next. Called repeatedly by the worker until
+/// it returns `None`, so every pick must make progress towards that.
+pub trait CompactionStrategy: Send {
+    fn pick(&mut self, version: &Version, options: &VaporDBOptions) -> Option<Compaction>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompactionStyle {
+    #[default]
+    Leveled,
+    SizeTiered,
+    Fifo,
+}
+
+impl CompactionStyle {
+    pub fn strategy(self) -> Box<dyn CompactionStrategy> {
+        match self {
+            CompactionStyle::Leveled => Box::new(LeveledCompaction::default()),
+            CompactionStyle::SizeTiered => Box::new(SizeTieredCompaction),
+            CompactionStyle::Fifo => Box::new(FifoCompaction),
+        }
+    }
+}
+
+/// One unit of compaction work: `inputs` are either merged into
+/// `output_level` or, for a delete-only compaction, simply dropped.
+#[derive(Debug, Clone)]
+pub struct Compaction {
+    pub output_level: usize,
+    pub inputs: Vec<FileMeta>,
+    pub delete_only: bool,
+}
+
+impl Compaction {
+    pub fn merge(output_level: usize, inputs: Vec<FileMeta>) -> Self {
+        Self {
+            output_level,
+            inputs,
+            delete_only: false,
+        }
+    }
+
+    pub fn delete(inputs: Vec<FileMeta>) -> Self {
+        Self {
+            output_level: 0,
+            inputs,
+            delete_only: true,
+        }
+    }
+
+    fn smallest_key(&self) -> &str {
+        self.inputs
+            .iter()
+            .map(|f| f.smallest_key.as_str())
+            .min()
+            .unwrap_or_default()
+    }
+
+    fn largest_key(&self) -> &str {
+        self.inputs
+            .iter()
+            .map(|f| f.largest_key.as_str())
+            .max()
+            .unwrap_or_default()
+    }
+
+    // Whether `file`, which is not an input, may hold versions of the
+    // compacted keys that are older than the inputs
+    fn is_shadowed_by(&self, file: &FileMeta) -> bool {
+        if self.inputs.iter().any(|f| f.number == file.number)
+            || !overlaps(file, self.smallest_key(), self.largest_key())
+        {
+            return false;
+        }
+        if file.level != self.output_level {
+            return file.level > self.output_level;
+        }
+        // Only level 0 can hold untouched overlapping files at the output level
+        let oldest_input = self.inputs.iter().map(|f| f.smallest_seq).min();
+        oldest_input.is_some_and(|seq| file.largest_seq < seq)
+    }
+}
+
+pub(super) fn key_range(files: &[FileMeta]) -> (String, String) {
+    let smallest = files.iter().map(|f| &f.smallest_key).min().cloned();
+    let largest = files.iter().map(|f| &f.largest_key).max().cloned();
+    (smallest.unwrap_or_default(), largest.unwrap_or_default())
+}
+
+pub(super) fn overlaps(file: &FileMeta, smallest: &str, largest: &str) -> bool {
+    file.smallest_key.as_str() <= largest && file.largest_key.as_str() >= smallest
+}
+
+/// Merges the inputs of `compaction` into size-bounded files and installs the
+/// result. Only the final install takes the write lock, so reads and flushes
+/// carry on while the merge runs.
+pub fn run_compaction(
+    tables: &RwLock<TableSet>,
+    compaction: &Compaction,
+    snapshots: &[SequenceNumber],
+    options: &VaporDBOptions,
+) -> Result<()> {
+    if compaction.delete_only {
+        let edit = VersionEdit {
+            deleted: compaction.inputs.iter().map(|f| f.number).collect(),
+            ..Default::default()
+        };
+        return tables.write().install(edit);
+    }
+
+    // Oldest first: merged output favours later inputs. Files from the output
+    // level are older than anything above it.
+    let mut inputs = compaction.inputs.clone();
+    inputs.sort_by_key(|f| (f.level != compaction.output_level, f.largest_seq));
+
+    let (ssts, older) = {
+        let tables = tables.read();
+        let ssts = inputs
+            .iter()
+            .map(|f| {
+                tables.table(f.number).ok_or_else(|| {
+                    VaporDBError::CompactionFailed(format!("table {} is not live", f.number))
+                })
+            })
+            .collect::<Result<Vec<Arc<SSTable>>>>()?;
+
+        // Files outside the compaction that might still hold older versions
+        // of the keys being compacted
+        let older: Vec<FileMeta> = tables
+            .manifest()
+            .version()
+            .files()
+            .filter(|f| compaction.is_shadowed_by(f))
+            .cloned()
+            .collect();
+        (ssts, older)
+    };
+
+    let smallest_seq = inputs.iter().map(|f| f.smallest_seq).min().unwrap_or(0);
+    let largest_seq = inputs.iter().map(|f| f.largest_seq).max().unwrap_or(0);
+    let refs: Vec<&SSTable> = ssts.iter().map(|sst| sst.as_ref()).collect();
+
+    let mut outputs = vec![];
+    let mut writer: Option<(u64, SSTableWriter)> = None;
+    let mut versions = VersionFilter::new(snapshots);
+    let mut last_key: Option<String> = None;
+    let now = options.clock.now_millis();
+    for entry in MergingIter::new(&refs)? {
+        // Expiry timestamps carry over; values past them become tombstones
+        let entry = entry?.expire(now);
+        if !versions.keep(&entry.key, entry.seq) {
+            continue;
+        }
+
+        // Nothing older can resurface, so the tombstone has done its job
+        if entry.value.is_none()
+            && versions.is_below_all_snapshots(entry.seq)
+            && !older.iter().any(|f| f.may_contain_key(&entry.key))
+        {
+            continue;
+        }
+
+        // Output files are only cut between keys, so the versions of a key
+        // never straddle two files of one level
+        let new_key = last_key.as_deref() != Some(entry.key.as_str());
+        if new_key
+            && let Some((_, w)) = &writer
+            && w.data_size() >= options.target_file_size
+        {
+            let (number, w) = writer.take().unwrap();
+            w.finish()?;
+            outputs.push(number);
+        }
+
+        if writer.is_none() {
+            let (number, path) = tables.write().new_table_path();
+            writer = Some((number, SSTableWriter::new(path, options.sst_options())?));
+        }
+        writer.as_mut().unwrap().1.add(&entry)?;
+        if new_key {
+            last_key = Some(entry.key.clone());
+        }
+    }
+    if let Some((number, w)) = writer.take() {
+        w.finish()?;
+        outputs.push(number);
+    }
+
+    let mut edit = VersionEdit {
+        deleted: inputs.iter().map(|f| f.number).collect(),
+        ..Default::default()
+    };
+    let mut tables = tables.write();
+    for number in outputs {
+        let sst = SSTable::open(tables.manifest().sst_path(number))?
+            .with_clock(Arc::clone(&options.clock));
+        edit.added.push(FileMeta::from_table(
+            number,
+            compaction.output_level,
+            &sst,
+            smallest_seq,
+            largest_seq,
+        )?);
+    }
+    tables.install(edit)
+}
+
+/// Background thread running compactions whenever it is woken by
+/// `schedule`. Dropping the worker stops the thread once its current
+/// compaction finishes.
+pub struct CompactionWorker {
+    shared: Arc<Shared>,
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    tables: Arc<RwLock<TableSet>>,
+    snapshots: Arc<SnapshotList>,
+    picker: Mutex<Box<dyn CompactionStrategy>>,
+    options: VaporDBOptions,
+    events: Arc<EventBus>,
+}
+
+impl Shared {
+    fn run_pending(&self) -> Result<usize> {
+        let mut picker = self.picker.lock();
+        let mut count = 0;
+        loop {
+            let compaction = {
+                let tables = self.tables.read();
+                picker.pick(tables.manifest().version(), &self.options)
+            };
+            let Some(compaction) = compaction else {
+                return Ok(count);
+            };
+            let dropped = if compaction.delete_only && self.events.wants(EventClass::Evicted) {
+                self.live_keys(&compaction.inputs)?
+            } else {
+                BTreeSet::new()
+            };
+            let snapshots = self.snapshots.sequences();
+            run_compaction(&self.tables, &compaction, &snapshots, &self.options)?;
+            count += 1;
+
+            // A key is only evicted if no remaining table holds it
+            let now = self.options.clock.now_millis();
+            for key in dropped {
+                let remaining = self.tables.read().get_entry(&key)?;
+                if remaining.is_none_or(|entry| entry.value.is_none() || entry.is_expired(now)) {
+                    self.events.publish(EventKind::Evicted, &key);
+                }
+            }
+        }
+    }
+
+    // The keys with a live value in `files`
+    fn live_keys(&self, files: &[FileMeta]) -> Result<BTreeSet<String>> {
+        let tables = self.tables.read();
+        let now = self.options.clock.now_millis();
+        let mut keys = BTreeSet::new();
+        for file in files {
+            let Some(sst) = tables.table(file.number) else {
+                continue;
+            };
+            for entry in sst.iter() {
+                let entry = entry?;
+                if entry.value.is_some() && !entry.is_expired(now) {
+                    keys.insert(entry.key);
+                }
+            }
+        }
+        Ok(keys)
+    }
+}
+
+impl CompactionWorker {
+    /// Starts the worker, publishing the keys delete-only compactions drop
+    /// on `events`.
+    pub fn start(
+        tables: Arc<RwLock<TableSet>>,
+        snapshots: Arc<SnapshotList>,
+        options: VaporDBOptions,
+        events: Arc<EventBus>,
+    ) -> Self {
+        let strategy = options.compaction_style.strategy();
+        Self::with_strategy(tables, snapshots, options, events, strategy)
+    }
+
+    pub fn with_strategy(
+        tables: Arc<RwLock<TableSet>>,
+        snapshots: Arc<SnapshotList>,
+        options: VaporDBOptions,
+        events: Arc<EventBus>,
+        strategy: Box<dyn CompactionStrategy>,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            tables,
+            snapshots,
+            picker: Mutex::new(strategy),
+            options,
+            events,
+        });
+        let (sender, receiver) = mpsc::channel::<()>();
+
+        let worker = Arc::clone(&shared);
+        let handle = thread::spawn(move || {
+            while receiver.recv().is_ok() {
+                // Coalesce wake-ups that arrived while busy
+                while receiver.try_recv().is_ok() {}
+                if let Err(e) = worker.run_pending() {
+                    eprintln!("Compaction failed: {e}");
+                }
+            }
+        });
+
+        let worker = Self {
+            shared,
+            sender: Some(sender),
+            handle: Some(handle),
+        };
+        // Catch up on anything left over from the last run
+        worker.schedule();
+        worker
+    }
+
+    /// Wakes the worker without waiting for it.
+    pub fn schedule(&self) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(());
+        }
+    }
+
+    /// Runs every pending compaction on the calling thread, returning how
+    /// many were done.
+    pub fn run_pending(&self) -> Result<usize> {
+        self.shared.run_pending()
+    }
+}
+
+impl Drop for CompactionWorker {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
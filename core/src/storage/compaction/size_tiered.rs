@@ -0,0 +1,46 @@
+// Size-tiered compaction. Every flush is a sorted run in level 0 and nothing
+// is ever pushed deeper. Once enough runs of similar size have piled up they
+// are merged into one bigger run, so data is rewritten roughly once per tier
+// rather than once per level, trading read and space amplification for
+// cheaper writes.
+
+use super::{Compaction, CompactionStrategy};
+use crate::options::VaporDBOptions;
+use crate::storage::manifest::{FileMeta, Version};
+
+#[derive(Default)]
+pub struct SizeTieredCompaction;
+
+impl CompactionStrategy for SizeTieredCompaction {
+    fn pick(&mut self, version: &Version, options: &VaporDBOptions) -> Option<Compaction> {
+        let min_width = options.size_tiered_min_merge_width.max(2);
+        let max_width = options.size_tiered_max_merge_width.max(min_width);
+
+        // Oldest first. Only neighbouring runs may be merged: the output takes
+        // the place of its inputs in the newest-first probe order.
+        let mut runs: Vec<&FileMeta> = version.levels[0].iter().collect();
+        runs.reverse();
+
+        for start in 0..runs.len() {
+            let mut total = runs[start].file_size;
+            let mut end = start + 1;
+            while end < runs.len() && end - start < max_width {
+                let average = total as f64 / (end - start) as f64;
+                let size = runs[end].file_size as f64;
+                if size > average * options.size_tiered_size_ratio
+                    || size * options.size_tiered_size_ratio < average
+                {
+                    break;
+                }
+                total += runs[end].file_size;
+                end += 1;
+            }
+
+            if end - start >= min_width {
+                let inputs = runs[start..end].iter().map(|f| (*f).clone()).collect();
+                return Some(Compaction::merge(0, inputs));
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,180 @@
+// Flushing turns immutable MemTables into level 0 SSTables. Writers freeze
+// the active MemTable once it is full and carry on with a fresh one; this
+// module writes the frozen ones out on a worker thread, oldest first, drops
+// the WAL segments they no longer need once their retention has passed, and
+// holds writers back when they get too far ahead of it.
+
+use crate::error::{Result, VaporDBError};
+use crate::options::VaporDBOptions;
+use crate::storage::compaction::CompactionWorker;
+use crate::storage::manifest::{FileMeta, VersionEdit};
+use crate::storage::memtable::MemTable;
+use crate::storage::memtable_set::MemTableSet;
+use crate::storage::snapshots::SnapshotList;
+use crate::storage::sst::SSTable;
+use crate::storage::table_set::TableSet;
+use crate::wal::wal;
+use parking_lot::{Condvar, Mutex, RwLock};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+
+pub struct FlushWorker {
+    shared: Arc<Shared>,
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    memtables: Arc<RwLock<MemTableSet>>,
+    tables: Arc<RwLock<TableSet>>,
+    snapshots: Arc<SnapshotList>,
+    compaction: Arc<CompactionWorker>,
+    wal_path: PathBuf,
+    options: VaporDBOptions,
+    // Serializes flushes so MemTables reach level 0 in the order they froze
+    running: Mutex<()>,
+    // The last background failure, handed to the next stalled writer
+    error: Mutex<Option<VaporDBError>>,
+    flushed: Condvar,
+}
+
+impl Shared {
+    fn run_pending(&self) -> Result<usize> {
+        let _running = self.running.lock();
+        let mut count = 0;
+        loop {
+            let Some((memtable, next_log)) = self.memtables.read().oldest_immutable() else {
+                break;
+            };
+            self.flush(&memtable, next_log)?;
+            count += 1;
+        }
+        if count > 0 {
+            self.compaction.schedule();
+        }
+        Ok(count)
+    }
+
+    fn flush(&self, memtable: &Arc<MemTable>, next_log: u64) -> Result<()> {
+        let (number, path) = self.tables.write().new_table_path();
+        let seqs = memtable.flush_to_sstable(
+            &path,
+            self.options.sst_options(),
+            &self.snapshots.sequences(),
+        )?;
+
+        let mut edit = VersionEdit {
+            log_number: Some(next_log),
+            ..Default::default()
+        };
+        if let Some((smallest_seq, largest_seq)) = seqs {
+            let sst = SSTable::open(&path)?.with_clock(Arc::clone(&self.options.clock));
+            edit.added.push(FileMeta::from_table(
+                number,
+                0,
+                &sst,
+                smallest_seq,
+                largest_seq,
+            )?);
+            edit.last_sequence = Some(largest_seq);
+        } else {
+            std::fs::remove_file(&path)?;
+        }
+        self.tables.write().install(edit)?;
+        wal::purge_segments(
+            &self.wal_path,
+            next_log,
+            self.options.wal_retention,
+            self.options.wal_archive_dir.as_deref(),
+        )?;
+
+        // Readers pick up the new table before the MemTable disappears, so no
+        // read can miss both
+        self.memtables.write().remove_flushed(memtable);
+        let _error = self.error.lock();
+        self.flushed.notify_all();
+        Ok(())
+    }
+}
+
+impl FlushWorker {
+    pub fn start(
+        memtables: Arc<RwLock<MemTableSet>>,
+        tables: Arc<RwLock<TableSet>>,
+        snapshots: Arc<SnapshotList>,
+        compaction: Arc<CompactionWorker>,
+        wal_path: PathBuf,
+        options: VaporDBOptions,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            memtables,
+            tables,
+            snapshots,
+            compaction,
+            wal_path,
+            options,
+            running: Mutex::new(()),
+            error: Mutex::new(None),
+            flushed: Condvar::new(),
+        });
+        let (sender, receiver) = mpsc::channel::<()>();
+
+        let worker = Arc::clone(&shared);
+        let handle = thread::spawn(move || {
+            while receiver.recv().is_ok() {
+                while receiver.try_recv().is_ok() {}
+                if let Err(e) = worker.run_pending() {
+                    eprintln!("Flush failed: {e}");
+                    *worker.error.lock() = Some(e);
+                    worker.flushed.notify_all();
+                }
+            }
+        });
+
+        Self {
+            shared,
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+
+    /// Wakes the worker without waiting for it.
+    pub fn schedule(&self) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(());
+        }
+    }
+
+    /// Flushes every immutable MemTable on the calling thread, returning how
+    /// many were written.
+    pub fn run_pending(&self) -> Result<usize> {
+        self.shared.run_pending()
+    }
+
+    /// Blocks while `max_immutable_memtables` are already waiting, so the
+    /// active MemTable can be frozen.
+    pub fn wait_for_room(&self) -> Result<()> {
+        let mut error = self.shared.error.lock();
+        while self.shared.memtables.read().immutable_count()
+            >= self.shared.options.max_immutable_memtables.max(1)
+        {
+            if let Some(e) = error.take() {
+                return Err(e);
+            }
+            self.schedule();
+            self.shared.flushed.wait(&mut error);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FlushWorker {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
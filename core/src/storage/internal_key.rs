@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+
+pub type SequenceNumber = u64;
+
+/// Sequence number that sees every write.
+pub const MAX_SEQUENCE: SequenceNumber = u64::MAX;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ValueKind {
+    Delete,
+    Put,
+}
+
+/// One version of a user key. Internal keys sort by user key ascending, then
+/// newest first, so the first match for a user key is its latest version.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InternalKey {
+    pub user_key: String,
+    pub seq: SequenceNumber,
+    pub kind: ValueKind,
+}
+
+impl InternalKey {
+    pub fn new(user_key: impl Into<String>, seq: SequenceNumber, kind: ValueKind) -> Self {
+        Self {
+            user_key: user_key.into(),
+            seq,
+            kind,
+        }
+    }
+
+    /// The smallest internal key for `user_key` visible at `seq`: seeking to
+    /// it lands on the newest version no newer than `seq`.
+    pub fn seek(user_key: impl Into<String>, seq: SequenceNumber) -> Self {
+        Self::new(user_key, seq, ValueKind::Put)
+    }
+}
+
+impl Ord for InternalKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.user_key
+            .cmp(&other.user_key)
+            .then(other.seq.cmp(&self.seq))
+            .then(other.kind.cmp(&self.kind))
+    }
+}
+
+impl PartialOrd for InternalKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The database-wide write counter. Every write takes the next number, so a
+/// higher sequence number always means a newer version.
+#[derive(Debug, Default)]
+pub struct Sequence {
+    last: AtomicU64,
+}
+
+impl Sequence {
+    pub fn new(last: SequenceNumber) -> Self {
+        Self {
+            last: AtomicU64::new(last),
+        }
+    }
+
+    pub fn next(&self) -> SequenceNumber {
+        self.last.fetch_add(1, AtomicOrdering::SeqCst) + 1
+    }
+
+    pub fn last(&self) -> SequenceNumber {
+        self.last.load(AtomicOrdering::SeqCst)
+    }
+
+    /// Moves the counter forward to at least `seq`, e.g. while replaying.
+    pub fn advance_to(&self, seq: SequenceNumber) {
+        self.last.fetch_max(seq, AtomicOrdering::SeqCst);
+    }
+}
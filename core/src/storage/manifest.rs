@@ -0,0 +1,350 @@
+// The MANIFEST is an append-only log of `VersionEdit` records describing
+// which SSTables are live. Replaying every edit in order rebuilds the current
+// `Version`. `CURRENT` names the active manifest file and is only ever
+// replaced through an atomic rename, so a crash leaves either the old or the
+// new manifest in charge. A record torn by a crash is ignored on replay, but
+// any other unreadable record is reported as corruption.
+
+use crate::error::{Result, VaporDBError};
+use crate::storage::sst::SSTable;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+pub const NUM_LEVELS: usize = 7;
+
+const CURRENT_FILE: &str = "CURRENT";
+const MAX_MANIFEST_SIZE: u64 = 4 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileMeta {
+    pub number: u64,
+    pub level: usize,
+    pub smallest_key: String,
+    pub largest_key: String,
+    pub smallest_seq: u64,
+    pub largest_seq: u64,
+    pub num_entries: u64,
+    pub file_size: u64,
+    pub created_at: u64, // Epoch seconds
+}
+
+impl FileMeta {
+    pub fn from_table(
+        number: u64,
+        level: usize,
+        sst: &SSTable,
+        smallest_seq: u64,
+        largest_seq: u64,
+    ) -> Result<Self> {
+        Ok(Self {
+            number,
+            level,
+            smallest_key: sst.smallest_key()?.unwrap_or_default(),
+            largest_key: sst.largest_key().unwrap_or_default().to_string(),
+            smallest_seq,
+            largest_seq,
+            num_entries: sst.size() as u64,
+            file_size: fs::metadata(sst.path())?.len(),
+            created_at: sst.clock().now_millis() / 1000,
+        })
+    }
+
+    pub fn may_contain_key(&self, key: &str) -> bool {
+        self.smallest_key.as_str() <= key && key <= self.largest_key.as_str()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct VersionEdit {
+    pub added: Vec<FileMeta>,
+    pub deleted: Vec<u64>,
+    pub next_file_number: Option<u64>,
+    pub last_sequence: Option<u64>,
+    pub log_number: Option<u64>,
+}
+
+// Edits as written before WAL segments existed
+#[derive(Deserialize)]
+struct EditV1 {
+    added: Vec<FileMeta>,
+    deleted: Vec<u64>,
+    next_file_number: Option<u64>,
+    last_sequence: Option<u64>,
+}
+
+impl From<EditV1> for VersionEdit {
+    fn from(edit: EditV1) -> Self {
+        Self {
+            added: edit.added,
+            deleted: edit.deleted,
+            next_file_number: edit.next_file_number,
+            last_sequence: edit.last_sequence,
+            log_number: None,
+        }
+    }
+}
+
+/// The set of live SSTables, by level. Level 0 is ordered newest first and
+/// its files may overlap; deeper levels are ordered by key.
+#[derive(Debug, Clone)]
+pub struct Version {
+    pub levels: Vec<Vec<FileMeta>>,
+}
+
+impl Default for Version {
+    fn default() -> Self {
+        Self {
+            levels: vec![Vec::new(); NUM_LEVELS],
+        }
+    }
+}
+
+impl Version {
+    fn apply(&mut self, edit: &VersionEdit) {
+        for level in self.levels.iter_mut() {
+            level.retain(|file| !edit.deleted.contains(&file.number));
+        }
+        for file in &edit.added {
+            self.levels[file.level].retain(|f| f.number != file.number);
+            self.levels[file.level].push(file.clone());
+        }
+
+        self.levels[0].sort_by_key(|f| std::cmp::Reverse(f.largest_seq));
+        for level in self.levels.iter_mut().skip(1) {
+            level.sort_by(|a, b| a.smallest_key.cmp(&b.smallest_key));
+        }
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &FileMeta> {
+        self.levels.iter().flatten()
+    }
+
+    pub fn contains(&self, number: u64) -> bool {
+        self.files().any(|file| file.number == number)
+    }
+
+    /// Files whose key range covers `key`, in the order they must be probed:
+    /// newest data first.
+    pub fn files_for_key<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a FileMeta> {
+        self.files().filter(move |file| file.may_contain_key(key))
+    }
+
+    fn snapshot_edit(&self) -> VersionEdit {
+        VersionEdit {
+            added: self.files().cloned().collect(),
+            ..Default::default()
+        }
+    }
+}
+
+pub struct Manifest {
+    dir: PathBuf,
+    number: u64,
+    writer: BufWriter<File>,
+    version: Version,
+    next_file_number: u64,
+    last_sequence: u64,
+    log_number: u64,
+}
+
+impl Manifest {
+    pub fn exists(dir: impl AsRef<Path>) -> bool {
+        dir.as_ref().join(CURRENT_FILE).exists()
+    }
+
+    /// Starts a manifest for a directory that has none yet, recording `files`
+    /// as the initial contents. `CURRENT` is written last, so a crash before
+    /// that point simply makes the next open start over.
+    pub fn create(dir: impl AsRef<Path>, files: Vec<FileMeta>) -> Result<Self> {
+        let mut version = Version::default();
+        let last_sequence = files.iter().map(|f| f.largest_seq).max().unwrap_or(0);
+        version.apply(&VersionEdit {
+            added: files,
+            ..Default::default()
+        });
+        Self::start(dir.as_ref().to_path_buf(), version, 1, last_sequence, 0)
+    }
+
+    /// Replays the manifest named by `CURRENT` and starts a fresh manifest
+    /// holding a single snapshot of the recovered version.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let mut version = Version::default();
+        let mut next_file_number = 1;
+        let mut last_sequence = 0;
+        let mut log_number = 0;
+
+        let name = fs::read_to_string(dir.join(CURRENT_FILE))?;
+        let old_manifest = dir.join(name.trim());
+        for edit in read_edits(&old_manifest)? {
+            version.apply(&edit);
+            if let Some(n) = edit.next_file_number {
+                next_file_number = next_file_number.max(n);
+            }
+            if let Some(seq) = edit.last_sequence {
+                last_sequence = last_sequence.max(seq);
+            }
+            if let Some(n) = edit.log_number {
+                log_number = log_number.max(n);
+            }
+        }
+
+        let manifest = Self::start(dir, version, next_file_number, last_sequence, log_number)?;
+        let _ = fs::remove_file(old_manifest);
+        Ok(manifest)
+    }
+
+    fn start(
+        dir: PathBuf,
+        version: Version,
+        next_file_number: u64,
+        last_sequence: u64,
+        log_number: u64,
+    ) -> Result<Self> {
+        // Never hand out a number that is already taken by a live file
+        let max_live = version.files().map(|f| f.number).max().unwrap_or(0);
+        let number = next_file_number.max(max_live + 1);
+
+        let mut manifest = Self {
+            writer: create_manifest_file(&dir, number)?,
+            dir,
+            number,
+            version,
+            next_file_number: number + 1,
+            last_sequence,
+            log_number,
+        };
+        manifest.write_snapshot()?;
+        manifest.set_current()?;
+        Ok(manifest)
+    }
+
+    pub fn version(&self) -> &Version {
+        &self.version
+    }
+
+    pub fn sst_path(&self, number: u64) -> PathBuf {
+        table_path(&self.dir, number)
+    }
+
+    pub fn new_file_number(&mut self) -> u64 {
+        let number = self.next_file_number;
+        self.next_file_number += 1;
+        number
+    }
+
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// WAL segments numbered below this only hold writes that are already in
+    /// SSTables.
+    pub fn log_number(&self) -> u64 {
+        self.log_number
+    }
+
+    /// Durably appends `edit` and only then applies it to the live version.
+    /// `edit.last_sequence` may carry the newest sequence number the edit's
+    /// files contain and `edit.log_number` the oldest WAL segment still
+    /// needed; the manifest never moves either backwards.
+    pub fn log_and_apply(&mut self, mut edit: VersionEdit) -> Result<()> {
+        if let Some(seq) = edit.last_sequence {
+            self.last_sequence = self.last_sequence.max(seq);
+        }
+        if let Some(n) = edit.log_number {
+            self.log_number = self.log_number.max(n);
+        }
+        edit.next_file_number = Some(self.next_file_number);
+        edit.last_sequence = Some(self.last_sequence);
+        edit.log_number = Some(self.log_number);
+
+        self.append(&edit)?;
+        self.version.apply(&edit);
+
+        if self.writer.get_ref().metadata()?.len() > MAX_MANIFEST_SIZE {
+            self.roll()?;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, edit: &VersionEdit) -> Result<()> {
+        let encoded =
+            bincode::serialize(edit).map_err(|e| VaporDBError::Internal(e.to_string()))?;
+        self.writer
+            .write_all(&(encoded.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&encoded)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    fn write_snapshot(&mut self) -> Result<()> {
+        let mut edit = self.version.snapshot_edit();
+        edit.next_file_number = Some(self.next_file_number);
+        edit.last_sequence = Some(self.last_sequence);
+        edit.log_number = Some(self.log_number);
+        self.append(&edit)
+    }
+
+    // Points CURRENT at this manifest via write-then-rename.
+    fn set_current(&self) -> Result<()> {
+        let tmp = self.dir.join(format!("{CURRENT_FILE}.tmp"));
+        fs::write(&tmp, format!("{}\n", manifest_name(self.number)))?;
+        File::open(&tmp)?.sync_all()?;
+        fs::rename(&tmp, self.dir.join(CURRENT_FILE))?;
+        Ok(())
+    }
+
+    // Replaces an oversized manifest with a snapshot of the live version.
+    fn roll(&mut self) -> Result<()> {
+        let old = self.dir.join(manifest_name(self.number));
+        self.number = self.new_file_number();
+        self.writer = create_manifest_file(&self.dir, self.number)?;
+        self.write_snapshot()?;
+        self.set_current()?;
+        let _ = fs::remove_file(old);
+        Ok(())
+    }
+}
+
+pub fn table_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{number:06}.sst"))
+}
+
+fn manifest_name(number: u64) -> String {
+    format!("MANIFEST-{number:06}")
+}
+
+fn create_manifest_file(dir: &Path, number: u64) -> Result<BufWriter<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(dir.join(manifest_name(number)))?;
+    Ok(BufWriter::new(file))
+}
+
+fn read_edits(path: &Path) -> Result<Vec<VersionEdit>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut edits = Vec::new();
+    let mut len_buf = [0u8; 4];
+
+    loop {
+        if reader.read_exact(&mut len_buf).is_err() {
+            break;
+        }
+        let mut data = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+        if reader.read_exact(&mut data).is_err() {
+            eprintln!("Ignoring torn record at the end of {}", path.display());
+            break;
+        }
+        let edit = bincode::deserialize::<VersionEdit>(&data)
+            .or_else(|_| bincode::deserialize::<EditV1>(&data).map(VersionEdit::from))
+            .map_err(|e| VaporDBError::Corruption(format!("{}: {e}", path.display())))?;
+        edits.push(edit);
+    }
+
+    Ok(edits)
+}
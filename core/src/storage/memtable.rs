@@ -1,94 +1,276 @@
 use crate::error::{VaporDBError, Result};
+use crate::storage::internal_key::{InternalKey, MAX_SEQUENCE, Sequence, SequenceNumber, ValueKind};
+use crate::storage::scan::KeyRange;
+use crate::storage::snapshots::VersionFilter;
+use crate::storage::sst::{SSTableEntry, SSTableOptions, SSTableWriter};
 use crate::storage::{Storage, Value};
-use serde_json;
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Bound;
+use std::path::Path;
 use std::sync::RwLock;
 use std::sync::{Arc};
 use crate::ttl::ExpirationTable;
 
+/// A version of a key. Each carries its own expiry, so a snapshot reading an
+/// older version sees the expiry it was written with.
+#[derive(Debug, Clone)]
+pub struct MemTableEntry {
+    pub value: Option<Value>,   // None = tombstone
+    pub expire_at: Option<u64>, // epoch milliseconds
+}
+
 pub struct MemTable {
-    pub map: RwLock<HashMap<String, Value>>,
-    pub expiration_table: Option<Arc<ExpirationTable>>,
+    pub map: RwLock<BTreeMap<InternalKey, MemTableEntry>>,
+    pub expiration_table: Arc<ExpirationTable>,
+    sequence: Arc<Sequence>,
+}
+
+impl Default for MemTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MemTable {
     pub fn new() -> Self {
         Self {
-            map: RwLock::new(HashMap::new()),
-            expiration_table: Some(Arc::new(ExpirationTable::new())),
+            map: RwLock::new(BTreeMap::new()),
+            expiration_table: Arc::new(ExpirationTable::new()),
+            sequence: Arc::default(),
         }
     }
 
+    pub fn with_expiration_table(expiration_table: Arc<ExpirationTable>) -> Self {
+        Self {
+            map: RwLock::new(BTreeMap::new()),
+            expiration_table,
+            sequence: Arc::default(),
+        }
+    }
+
+    /// Stamps writes made through `Storage` and the list/set helpers from
+    /// `sequence` instead of a private counter.
+    pub fn with_sequence(mut self, sequence: Arc<Sequence>) -> Self {
+        self.sequence = sequence;
+        self
+    }
+
+    /// An empty MemTable sharing this one's expiration table and sequence.
+    pub fn fresh(&self) -> Self {
+        Self {
+            map: RwLock::new(BTreeMap::new()),
+            expiration_table: Arc::clone(&self.expiration_table),
+            sequence: Arc::clone(&self.sequence),
+        }
+    }
+
+    /// Number of versions held, counting every overwrite and delete.
     pub fn len(&self) -> usize {
         self.map.read().unwrap().len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.map.read().unwrap().is_empty()
+    }
+
     pub fn clear(&self) {
         self.map.write().unwrap().clear();
     }
 
-    pub fn flush_to_sstable(&self, path: &str) -> Result<()> {
-        let file = File::create(path)?;
-        let mut writer = BufWriter::new(file);
+    /// Records a version of `key` written at `seq`, expiring at `expire_at`
+    /// (epoch milliseconds) if given. `None` is a tombstone.
+    pub fn insert(
+        &self,
+        seq: SequenceNumber,
+        key: String,
+        value: Option<Value>,
+        expire_at: Option<u64>,
+    ) {
+        let kind = match value {
+            Some(_) => ValueKind::Put,
+            None => ValueKind::Delete,
+        };
+        self.sequence.advance_to(seq);
+        self.map
+            .write()
+            .unwrap()
+            .insert(InternalKey::new(key, seq, kind), MemTableEntry { value, expire_at });
+    }
 
-        let map = self.map.read().unwrap();
-        for (key, value) in map.iter() {
-            let serialized_value = serde_json::to_string(value)?;
-            writeln!(writer, "{}\t{}", key, serialized_value)?;
-        }
-        Ok(())
+    fn latest<'a>(
+        map: &'a BTreeMap<InternalKey, MemTableEntry>,
+        key: &str,
+    ) -> Option<&'a MemTableEntry> {
+        Self::version_at(map, key, MAX_SEQUENCE)
     }
 
-    // List operations
-    pub fn lpush(&self, key: String, value: String) -> Result<()> {
-        let mut map = self.map.write().unwrap();
-        let list = map.entry(key.clone()).or_insert(Value::List(vec![]));
+    fn version_at<'a>(
+        map: &'a BTreeMap<InternalKey, MemTableEntry>,
+        key: &str,
+        seq: SequenceNumber,
+    ) -> Option<&'a MemTableEntry> {
+        map.range(InternalKey::seek(key, seq)..)
+            .next()
+            .filter(|(ikey, _)| ikey.user_key == key)
+            .map(|(_, entry)| entry)
+    }
 
-        if let Value::List(vec) = list {
-            vec.insert(0, value);
-        } else {
-            return Err(VaporDBError::TypeMismatch("Expected List".into()));
+    // The value of `entry`, or `None` if it is a tombstone or has expired
+    fn live_value(&self, entry: &MemTableEntry) -> Option<Value> {
+        match entry.expire_at {
+            Some(expire_at) if expire_at <= self.expiration_table.now() => None,
+            _ => entry.value.clone(),
         }
-        Ok(())
     }
 
-    pub fn rpush(&self, key: String, value: String) -> Result<()> {
-        let mut map = self.map.write().unwrap();
-        let list = map.entry(key.clone()).or_insert(Value::List(vec![]));
+    /// Returns `Some(None)` when the key was deleted or has expired, so callers
+    /// know not to fall through to older SSTables.
+    pub fn get_entry(&self, key: &str) -> Option<Option<Value>> {
+        self.get_entry_at(key, MAX_SEQUENCE)
+    }
 
-        if let Value::List(vec) = list {
-            vec.push(value);
-        } else {
-            return Err(VaporDBError::TypeMismatch("Expected List".into()));
+    /// Like `get_entry`, but only sees versions written at or before `seq`.
+    pub fn get_entry_at(&self, key: &str, seq: SequenceNumber) -> Option<Option<Value>> {
+        let map = self.map.read().unwrap();
+        let entry = Self::version_at(&map, key, seq)?;
+        Some(self.live_value(entry))
+    }
+
+    /// The newest version of every key, tombstones included.
+    pub fn latest_entries(&self) -> HashMap<String, Option<Value>> {
+        let map = self.map.read().unwrap();
+        let mut entries = HashMap::new();
+        for (ikey, entry) in map.iter().rev() {
+            entries.insert(ikey.user_key.clone(), entry.value.clone());
         }
-        Ok(())
+        entries
     }
 
-    pub fn lpop(&self, key: String) -> Result<Option<String>> {
-        let mut map = self.map.write().unwrap();
-        if let Some(Value::List(vec)) = map.get_mut(&key) {
-            if !vec.is_empty() {
-                return Ok(Some(vec.remove(0)));
+    /// Every version of every key in `range`, tombstones and TTLs included,
+    /// in internal key order.
+    pub fn range_entries(&self, range: &KeyRange) -> Vec<SSTableEntry> {
+        let map = self.map.read().unwrap();
+        let versions = match &range.start {
+            Bound::Included(key) | Bound::Excluded(key) => {
+                map.range(InternalKey::seek(key, MAX_SEQUENCE)..)
             }
+            Bound::Unbounded => map.range(..),
+        };
+        versions
+            .skip_while(|(ikey, _)| range.is_before_start(&ikey.user_key))
+            .take_while(|(ikey, _)| !range.is_after_end(&ikey.user_key))
+            .map(|(ikey, entry)| SSTableEntry {
+                key: ikey.user_key.clone(),
+                seq: ikey.seq,
+                value: entry.value.clone(),
+                ttl: entry.expire_at,
+            })
+            .collect()
+    }
+
+    /// Writes the newest version of every key, tombstones and TTLs included,
+    /// to a new SSTable, along with any older versions still visible to one of
+    /// `snapshots` (sorted oldest first). Returns the range of sequence
+    /// numbers written, or `None` if the MemTable was empty.
+    pub fn flush_to_sstable(
+        &self,
+        path: impl AsRef<Path>,
+        options: SSTableOptions,
+        snapshots: &[SequenceNumber],
+    ) -> Result<Option<(SequenceNumber, SequenceNumber)>> {
+        let map = self.map.read().unwrap();
+
+        let now = self.expiration_table.now();
+        let mut writer = SSTableWriter::new(path, options)?;
+        let mut seqs: Option<(SequenceNumber, SequenceNumber)> = None;
+        let mut versions = VersionFilter::new(snapshots);
+        for (ikey, version) in map.iter() {
+            if !versions.keep(&ikey.user_key, ikey.seq) {
+                continue;
+            }
+
+            let entry = SSTableEntry {
+                key: ikey.user_key.clone(),
+                seq: ikey.seq,
+                value: version.value.clone(),
+                ttl: version.expire_at,
+            };
+            writer.add(&entry.expire(now))?;
+
+            seqs = Some(match seqs {
+                Some((smallest, largest)) => (smallest.min(ikey.seq), largest.max(ikey.seq)),
+                None => (ikey.seq, ikey.seq),
+            });
         }
-        Ok(None)
+        writer.finish()?;
+        Ok(seqs)
     }
 
-    pub fn rpop(&self, key: String) -> Result<Option<String>> {
+    // Applies `f` to the newest value of `key` and stores the result as a new
+    // version, keeping the key's expiry.
+    fn update<T>(&self, key: String, f: impl FnOnce(&mut Option<Value>) -> Result<T>) -> Result<T> {
         let mut map = self.map.write().unwrap();
-        if let Some(Value::List(vec)) = map.get_mut(&key) {
-            if !vec.is_empty() {
-                return Ok(Some(vec.pop().unwrap()));
+        let latest = Self::latest(&map, &key);
+        let mut value = latest.and_then(|entry| self.live_value(entry));
+        let mut expire_at = latest.and_then(|entry| entry.expire_at);
+        let result = f(&mut value)?;
+
+        let kind = match value {
+            Some(_) => ValueKind::Put,
+            None => {
+                expire_at = None;
+                ValueKind::Delete
             }
-        }
-        Ok(None)
+        };
+        map.insert(
+            InternalKey::new(key, self.sequence.next(), kind),
+            MemTableEntry { value, expire_at },
+        );
+        Ok(result)
+    }
+
+    // List operations
+    pub fn lpush(&self, key: String, value: String) -> Result<()> {
+        self.update(key, |current| {
+            match current.get_or_insert_with(|| Value::List(vec![])) {
+                Value::List(vec) => {
+                    vec.insert(0, value);
+                    Ok(())
+                }
+                _ => Err(VaporDBError::TypeMismatch("Expected List".into())),
+            }
+        })
+    }
+
+    pub fn rpush(&self, key: String, value: String) -> Result<()> {
+        self.update(key, |current| {
+            match current.get_or_insert_with(|| Value::List(vec![])) {
+                Value::List(vec) => {
+                    vec.push(value);
+                    Ok(())
+                }
+                _ => Err(VaporDBError::TypeMismatch("Expected List".into())),
+            }
+        })
+    }
+
+    pub fn lpop(&self, key: String) -> Result<Option<String>> {
+        self.update(key, |current| match current {
+            Some(Value::List(vec)) if !vec.is_empty() => Ok(Some(vec.remove(0))),
+            _ => Ok(None),
+        })
+    }
+
+    pub fn rpop(&self, key: String) -> Result<Option<String>> {
+        self.update(key, |current| match current {
+            Some(Value::List(vec)) => Ok(vec.pop()),
+            _ => Ok(None),
+        })
     }
 
     pub fn lrange(&self, key: String, start: usize, end: usize) -> Result<Vec<String>> {
         let map = self.map.read().unwrap();
-        if let Some(Value::List(vec)) = map.get(&key) {
+        if let Some(MemTableEntry { value: Some(Value::List(vec)), .. }) = Self::latest(&map, &key) {
             let start = start.min(vec.len());
             let end = end.min(vec.len());
             Ok(vec[start..end].to_vec())
@@ -99,30 +281,30 @@ impl MemTable {
 
     // Set operations
     pub fn sadd(&self, key: String, value: String) -> Result<()> {
-        let mut map = self.map.write().unwrap();
-        let set = map.entry(key.clone()).or_insert(Value::Set(HashSet::new()));
-
-        if let Value::Set(set) = set {
-            set.insert(value);
-        } else {
-            return Err(VaporDBError::TypeMismatch("Expected Set".into()));
-        }
-        Ok(())
+        self.update(key, |current| {
+            match current.get_or_insert_with(|| Value::Set(HashSet::new())) {
+                Value::Set(set) => {
+                    set.insert(value);
+                    Ok(())
+                }
+                _ => Err(VaporDBError::TypeMismatch("Expected Set".into())),
+            }
+        })
     }
 
     pub fn srem(&self, key: String, value: String) -> Result<()> {
-        let mut map = self.map.write().unwrap();
-        if let Some(Value::Set(set)) = map.get_mut(&key) {
-            set.remove(&value);
-            Ok(())
-        } else {
-            Err(VaporDBError::TypeMismatch("Expected Set".into()))
-        }
+        self.update(key, |current| match current {
+            Some(Value::Set(set)) => {
+                set.remove(&value);
+                Ok(())
+            }
+            _ => Err(VaporDBError::TypeMismatch("Expected Set".into())),
+        })
     }
 
     pub fn smembers(&self, key: String) -> Result<HashSet<String>> {
         let map = self.map.read().unwrap();
-        if let Some(Value::Set(set)) = map.get(&key) {
+        if let Some(MemTableEntry { value: Some(Value::Set(set)), .. }) = Self::latest(&map, &key) {
             Ok(set.clone())
         } else {
             Err(VaporDBError::TypeMismatch("Expected Set".into()))
@@ -132,29 +314,38 @@ impl MemTable {
 
 impl Storage for MemTable {
     fn get(&self, key: &str) -> Result<Option<Value>> {
-        if let Some(expiration_table) = &self.expiration_table {
-            if expiration_table.is_expired(key) {
-                return Ok(None);
-            }
-        }
-        Ok(self.map.read().unwrap().get(key).cloned())
+        let map = self.map.read().unwrap();
+        Ok(Self::latest(&map, key).and_then(|entry| self.live_value(entry)))
     }
 
     fn set(&self, key: String, value: Value) -> Result<()> {
-        self.map.write().unwrap().insert(key, value);
+        self.insert(self.sequence.next(), key, Some(value), None);
         Ok(())
     }
 
+    // Deletes leave a tombstone behind so older SSTables stay shadowed
     fn del(&self, key: &str) -> Result<()> {
-        self.map.write().unwrap().remove(key);
+        self.insert(self.sequence.next(), key.to_string(), None, None);
         Ok(())
     }
 
     fn exists(&self, key: &str) -> Result<bool> {
-        Ok(self.map.read().unwrap().contains_key(key))
+        Ok(self.get(key)?.is_some())
     }
 
     fn keys(&self) -> Result<Vec<String>> {
-        Ok(self.map.read().unwrap().keys().cloned().collect())
+        let map = self.map.read().unwrap();
+        let mut keys = Vec::new();
+        let mut last_key: Option<&str> = None;
+        for (ikey, entry) in map.iter() {
+            if last_key == Some(ikey.user_key.as_str()) {
+                continue;
+            }
+            last_key = Some(&ikey.user_key);
+            if self.live_value(entry).is_some() {
+                keys.push(ikey.user_key.clone());
+            }
+        }
+        Ok(keys)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,55 @@
+use crate::storage::memtable::MemTable;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// The MemTable taking writes together with the frozen ones still waiting to
+/// be flushed. Shared between `VaporDB`, its snapshots and its flush worker.
+pub struct MemTableSet {
+    active: Arc<MemTable>,
+    // Newest first, each with the first WAL segment written after it froze
+    immutable: VecDeque<(Arc<MemTable>, u64)>,
+}
+
+impl MemTableSet {
+    pub fn new(active: MemTable) -> Self {
+        Self {
+            active: Arc::new(active),
+            immutable: VecDeque::new(),
+        }
+    }
+
+    pub fn active(&self) -> &Arc<MemTable> {
+        &self.active
+    }
+
+    pub fn immutable_count(&self) -> usize {
+        self.immutable.len()
+    }
+
+    /// Every MemTable, newest first.
+    pub fn all(&self) -> Vec<Arc<MemTable>> {
+        std::iter::once(&self.active)
+            .chain(self.immutable.iter().map(|(memtable, _)| memtable))
+            .cloned()
+            .collect()
+    }
+
+    /// Makes the active MemTable immutable and swaps in an empty one. Every
+    /// write to the frozen MemTable went to WAL segments before `next_log`.
+    pub fn freeze(&mut self, next_log: u64) {
+        let fresh = Arc::new(self.active.fresh());
+        let frozen = std::mem::replace(&mut self.active, fresh);
+        self.immutable.push_front((frozen, next_log));
+    }
+
+    /// The immutable MemTable that has waited longest, with the first WAL
+    /// segment it does not need.
+    pub fn oldest_immutable(&self) -> Option<(Arc<MemTable>, u64)> {
+        self.immutable.back().cloned()
+    }
+
+    /// Drops `memtable` once its contents are safely in an SSTable.
+    pub fn remove_flushed(&mut self, memtable: &Arc<MemTable>) {
+        self.immutable.retain(|(m, _)| !Arc::ptr_eq(m, memtable));
+    }
+}
@@ -1,5 +1,16 @@
+pub mod bloom;
+pub mod compaction;
+pub mod flush;
+pub mod internal_key;
+pub mod manifest;
 pub mod memtable;
+pub mod memtable_set;
+pub mod scan;
+pub mod snapshots;
+pub mod sorted_set;
+pub mod stream;
 pub mod sst;
+pub mod table_set;
 pub mod value;
 
 use crate::error::Result;
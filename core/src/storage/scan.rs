@@ -0,0 +1,277 @@
+// Range scans merge every source that may hold a key - the MemTable and each
+// live SSTable - into one stream in key order. All versions of a key are
+// gathered from the merged stream and the newest one visible at the scan's
+// sequence number decides what the caller sees; tombstones and expired
+// entries hide the key. Scans run forwards or backwards, and a `ScanIter`
+// can be consumed from both ends at once.
+
+use crate::clock::Clock;
+use crate::error::Result;
+use crate::storage::Value;
+use crate::storage::internal_key::SequenceNumber;
+use crate::storage::sst::SSTableEntry;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+pub type EntryCursor = Box<dyn Iterator<Item = Result<SSTableEntry>> + Send>;
+
+/// An owned key range.
+#[derive(Debug, Clone)]
+pub struct KeyRange {
+    pub start: Bound<String>,
+    pub end: Bound<String>,
+}
+
+impl KeyRange {
+    pub fn new<R: RangeBounds<String>>(range: R) -> Self {
+        Self {
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+        }
+    }
+
+    /// Every key beginning with `prefix`.
+    pub fn prefix(prefix: &str) -> Self {
+        Self {
+            start: Bound::Included(prefix.to_string()),
+            end: prefix_end(prefix).map_or(Bound::Unbounded, Bound::Excluded),
+        }
+    }
+
+    pub fn is_before_start(&self, key: &str) -> bool {
+        match &self.start {
+            Bound::Included(start) => key < start.as_str(),
+            Bound::Excluded(start) => key <= start.as_str(),
+            Bound::Unbounded => false,
+        }
+    }
+
+    pub fn is_after_end(&self, key: &str) -> bool {
+        match &self.end {
+            Bound::Included(end) => key > end.as_str(),
+            Bound::Excluded(end) => key >= end.as_str(),
+            Bound::Unbounded => false,
+        }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        !self.is_before_start(key) && !self.is_after_end(key)
+    }
+}
+
+// The smallest string greater than every string starting with `prefix`, if
+// there is one
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+/// Source cursors for one direction of a scan, newest source first. Built
+/// lazily, the first time that direction is used.
+pub type CursorFactory = Box<dyn FnMut(bool) -> Result<Vec<EntryCursor>> + Send>;
+
+pub struct ScanIter {
+    seq: SequenceNumber,
+    clock: Arc<dyn Clock>,
+    factory: CursorFactory,
+    front: Option<MergedVersions>,
+    back: Option<MergedVersions>,
+    // Last keys handed out from each end, so the two ends never cross
+    front_key: Option<String>,
+    back_key: Option<String>,
+    done: bool,
+}
+
+impl ScanIter {
+    pub fn new(seq: SequenceNumber, clock: Arc<dyn Clock>, factory: CursorFactory) -> Self {
+        Self {
+            seq,
+            clock,
+            factory,
+            front: None,
+            back: None,
+            front_key: None,
+            back_key: None,
+            done: false,
+        }
+    }
+
+    fn next_visible(&mut self, reverse: bool) -> Result<Option<(String, Value)>> {
+        let seq = self.seq;
+        let merged = if reverse {
+            &mut self.back
+        } else {
+            &mut self.front
+        };
+        if merged.is_none() {
+            *merged = Some(MergedVersions::new((self.factory)(reverse)?, reverse)?);
+        }
+        let merged = merged.as_mut().unwrap();
+
+        while let Some(versions) = merged.next_key()? {
+            let key = &versions[0].0.key;
+            let crossed = if reverse {
+                self.front_key.as_ref().is_some_and(|k| key <= k)
+            } else {
+                self.back_key.as_ref().is_some_and(|k| key >= k)
+            };
+            if crossed {
+                return Ok(None);
+            }
+
+            // Newest version visible at `seq`; on a tie the newer source wins
+            let visible = versions
+                .into_iter()
+                .filter(|(entry, _)| entry.seq <= seq)
+                .min_by(|(a, a_src), (b, b_src)| b.seq.cmp(&a.seq).then(a_src.cmp(b_src)));
+            let Some((entry, _)) = visible else {
+                continue;
+            };
+            if entry.is_expired(self.clock.now_millis()) {
+                continue;
+            }
+            if let Some(value) = entry.value {
+                return Ok(Some((entry.key, value)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn step(&mut self, reverse: bool) -> Option<Result<(String, Value)>> {
+        if self.done {
+            return None;
+        }
+        match self.next_visible(reverse) {
+            Ok(Some((key, value))) => {
+                if reverse {
+                    self.back_key = Some(key.clone());
+                } else {
+                    self.front_key = Some(key.clone());
+                }
+                Some(Ok((key, value)))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl Iterator for ScanIter {
+    type Item = Result<(String, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.step(false)
+    }
+}
+
+impl DoubleEndedIterator for ScanIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.step(true)
+    }
+}
+
+// K-way merge of source cursors that hands out all versions of one key at a
+// time, each tagged with the index of the source it came from.
+struct MergedVersions {
+    cursors: Vec<EntryCursor>,
+    heap: BinaryHeap<HeapItem>,
+}
+
+impl MergedVersions {
+    fn new(mut cursors: Vec<EntryCursor>, reverse: bool) -> Result<Self> {
+        let mut heap = BinaryHeap::new();
+        for (source, cursor) in cursors.iter_mut().enumerate() {
+            if let Some(entry) = cursor.next() {
+                heap.push(HeapItem {
+                    entry: entry?,
+                    source,
+                    reverse,
+                });
+            }
+        }
+        Ok(Self { cursors, heap })
+    }
+
+    fn pop(&mut self) -> Result<Option<(SSTableEntry, usize)>> {
+        let Some(HeapItem {
+            entry,
+            source,
+            reverse,
+        }) = self.heap.pop()
+        else {
+            return Ok(None);
+        };
+        if let Some(next) = self.cursors[source].next() {
+            self.heap.push(HeapItem {
+                entry: next?,
+                source,
+                reverse,
+            });
+        }
+        Ok(Some((entry, source)))
+    }
+
+    fn next_key(&mut self) -> Result<Option<Vec<(SSTableEntry, usize)>>> {
+        let Some(first) = self.pop()? else {
+            return Ok(None);
+        };
+        let mut versions = vec![first];
+        while self
+            .heap
+            .peek()
+            .is_some_and(|top| top.entry.key == versions[0].0.key)
+        {
+            versions.extend(self.pop()?);
+        }
+        Ok(Some(versions))
+    }
+}
+
+struct HeapItem {
+    entry: SSTableEntry,
+    source: usize,
+    reverse: bool,
+}
+
+impl Ord for HeapItem {
+    // The heap pops its maximum: the smallest key going forwards, the largest
+    // going backwards
+    fn cmp(&self, other: &Self) -> Ordering {
+        let by_key = self.entry.key.cmp(&other.entry.key);
+        let by_key = if self.reverse {
+            by_key
+        } else {
+            by_key.reverse()
+        };
+        by_key.then(other.source.cmp(&self.source))
+    }
+}
+
+impl PartialOrd for HeapItem {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapItem {}
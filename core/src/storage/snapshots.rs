@@ -0,0 +1,69 @@
+use crate::storage::internal_key::SequenceNumber;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+
+/// Sequence numbers of the snapshots that are still open. Flushes and
+/// compactions consult it so they never drop a version a snapshot can see.
+#[derive(Debug, Default)]
+pub struct SnapshotList {
+    live: Mutex<BTreeMap<SequenceNumber, usize>>,
+}
+
+impl SnapshotList {
+    pub fn acquire(&self, seq: SequenceNumber) {
+        *self.live.lock().entry(seq).or_insert(0) += 1;
+    }
+
+    pub fn release(&self, seq: SequenceNumber) {
+        let mut live = self.live.lock();
+        if let Some(count) = live.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                live.remove(&seq);
+            }
+        }
+    }
+
+    /// Live snapshot sequence numbers, oldest first.
+    pub fn sequences(&self) -> Vec<SequenceNumber> {
+        self.live.lock().keys().cloned().collect()
+    }
+}
+
+/// Tracks the versions of one key, newest first, and decides which must be
+/// kept: the newest one, and the newest one visible to each live snapshot.
+/// Every other version is hidden from all readers.
+pub struct VersionFilter<'a> {
+    snapshots: &'a [SequenceNumber],
+    last: Option<(String, usize)>,
+}
+
+impl<'a> VersionFilter<'a> {
+    /// `snapshots` must be sorted oldest first.
+    pub fn new(snapshots: &'a [SequenceNumber]) -> Self {
+        Self {
+            snapshots,
+            last: None,
+        }
+    }
+
+    pub fn keep(&mut self, key: &str, seq: SequenceNumber) -> bool {
+        // Versions between the same pair of snapshots are indistinguishable
+        // to every reader, so only the newest of them survives
+        let stripe = self.snapshots.partition_point(|&s| s < seq);
+        if let Some((last_key, last_stripe)) = &self.last
+            && last_key == key
+            && *last_stripe == stripe
+        {
+            return false;
+        }
+        self.last = Some((key.to_string(), stripe));
+        true
+    }
+
+    /// A tombstone at `seq` can be dropped once nothing older sits below it
+    /// and no snapshot predates it.
+    pub fn is_below_all_snapshots(&self, seq: SequenceNumber) -> bool {
+        self.snapshots.first().is_none_or(|&oldest| seq <= oldest)
+    }
+}
@@ -0,0 +1,576 @@
+// A sorted set keeps every member twice: in a map from member to score for
+// O(1) lookups, and in a skiplist ordered by score, then member. Each skiplist
+// link records how many members it skips, so finding a member's rank or the
+// member at a rank takes O(log n) like a search by score does. Nodes live in
+// an arena and link to each other by index.
+//
+// On disk a sorted set is just its (member, score) pairs in order; the
+// skiplist is rebuilt when it is read back.
+
+use crate::error::{Result, VaporDBError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+const MAX_LEVEL: usize = 32;
+const HEAD: usize = 0;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Link {
+    next: Option<usize>,
+    span: usize, // members skipped by following `next`, itself included
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    member: String,
+    score: f64,
+    links: Vec<Link>,
+    prev: Option<usize>,
+}
+
+impl Node {
+    fn precedes(&self, score: f64, member: &str) -> bool {
+        self.score < score || (self.score == score && self.member.as_str() < member)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "Vec<(String, f64)>", into = "Vec<(String, f64)>")]
+pub struct SortedSet {
+    nodes: Vec<Node>, // nodes[HEAD] holds no member
+    free: Vec<usize>,
+    tail: Option<usize>,
+    level: usize,
+    scores: HashMap<String, f64>,
+    rng: u64,
+}
+
+impl Default for SortedSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        let head = Node {
+            member: String::new(),
+            score: f64::NEG_INFINITY,
+            links: vec![Link::default(); MAX_LEVEL],
+            prev: None,
+        };
+        Self {
+            nodes: vec![head],
+            free: Vec::new(),
+            tail: None,
+            level: 1,
+            scores: HashMap::new(),
+            rng: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Sets `member`'s score, returning the one it replaced. Scores must not
+    /// be NaN.
+    pub fn insert(&mut self, member: String, score: f64) -> Option<f64> {
+        debug_assert!(!score.is_nan());
+        if let Some(&old) = self.scores.get(&member)
+            && old == score
+        {
+            return Some(old);
+        }
+        let old = self.scores.remove(&member);
+        if let Some(old) = old {
+            self.unlink(old, &member);
+        }
+        self.link(member.clone(), score);
+        self.scores.insert(member, score);
+        old
+    }
+
+    pub fn remove(&mut self, member: &str) -> Option<f64> {
+        let score = self.scores.remove(member)?;
+        self.unlink(score, member);
+        Some(score)
+    }
+
+    /// How many members order before `member`.
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        let score = self.score(member)?;
+        let mut rank = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].links[i].next
+                && (self.nodes[next].precedes(score, member) || self.nodes[next].member == member)
+            {
+                rank += self.nodes[x].links[i].span;
+                x = next;
+            }
+            if x != HEAD && self.nodes[x].member == member {
+                return Some(rank - 1);
+            }
+        }
+        None
+    }
+
+    /// Members in order, lowest score first.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.walk(self.nodes[HEAD].links[0].next, false)
+            .map(|id| (self.nodes[id].member.as_str(), self.nodes[id].score))
+    }
+
+    /// The members `by` selects, highest first if `rev`, after skipping
+    /// `offset` of them and keeping at most `count`.
+    pub fn range(
+        &self,
+        by: &RangeBy,
+        rev: bool,
+        limit: Option<(usize, usize)>,
+    ) -> Vec<(&str, f64)> {
+        let (offset, count) = limit.unwrap_or((0, usize::MAX));
+        let ids: Box<dyn Iterator<Item = usize> + '_> = match by {
+            RangeBy::Index(start, stop) => {
+                let Some((start, stop)) = self.index_range(*start, *stop) else {
+                    return Vec::new();
+                };
+                let first = if rev { self.len() - 1 - start } else { start };
+                Box::new(self.walk(self.at_rank(first), rev).take(stop - start + 1))
+            }
+            RangeBy::Score(min, max) if rev => {
+                let last = self.seek(|node| max.admits_below(node.score));
+                Box::new(
+                    self.walk(last, true)
+                        .take_while(|&id| min.admits_above(self.nodes[id].score)),
+                )
+            }
+            RangeBy::Score(min, max) => {
+                let first = self.seek(|node| !min.admits_above(node.score));
+                let first = self.next(first.unwrap_or(HEAD));
+                Box::new(
+                    self.walk(first, false)
+                        .take_while(|&id| max.admits_below(self.nodes[id].score)),
+                )
+            }
+            RangeBy::Lex(min, max) if rev => {
+                let last = self.seek(|node| max.admits_below(&node.member));
+                Box::new(
+                    self.walk(last, true)
+                        .take_while(|&id| min.admits_above(&self.nodes[id].member)),
+                )
+            }
+            RangeBy::Lex(min, max) => {
+                let first = self.seek(|node| !min.admits_above(&node.member));
+                let first = self.next(first.unwrap_or(HEAD));
+                Box::new(
+                    self.walk(first, false)
+                        .take_while(|&id| max.admits_below(&self.nodes[id].member)),
+                )
+            }
+        };
+        ids.skip(offset)
+            .take(count)
+            .map(|id| (self.nodes[id].member.as_str(), self.nodes[id].score))
+            .collect()
+    }
+
+    /// Removes and returns up to `count` of the lowest scoring members.
+    pub fn pop_min(&mut self, count: usize) -> Vec<(String, f64)> {
+        self.pop(count, false)
+    }
+
+    /// Removes and returns up to `count` of the highest scoring members.
+    pub fn pop_max(&mut self, count: usize) -> Vec<(String, f64)> {
+        self.pop(count, true)
+    }
+
+    fn pop(&mut self, count: usize, rev: bool) -> Vec<(String, f64)> {
+        let start = if rev { self.tail } else { self.next(HEAD) };
+        let members: Vec<String> = self
+            .walk(start, rev)
+            .take(count)
+            .map(|id| self.nodes[id].member.clone())
+            .collect();
+        members
+            .into_iter()
+            .map(|member| {
+                let score = self.remove(&member).unwrap();
+                (member, score)
+            })
+            .collect()
+    }
+
+    // Clamps an inclusive index range, where negative indices count back
+    // from the end, to the members that exist
+    fn index_range(&self, start: i64, stop: i64) -> Option<(usize, usize)> {
+        let len = self.len() as i64;
+        let start = if start < 0 {
+            (len + start).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            len + stop
+        } else {
+            stop.min(len - 1)
+        };
+        (start <= stop && start < len).then_some((start as usize, stop as usize))
+    }
+
+    fn next(&self, id: usize) -> Option<usize> {
+        self.nodes[id].links[0].next
+    }
+
+    fn walk(&self, start: Option<usize>, rev: bool) -> impl Iterator<Item = usize> + '_ {
+        std::iter::successors(start, move |&id| {
+            if rev {
+                self.nodes[id].prev
+            } else {
+                self.next(id)
+            }
+        })
+    }
+
+    // The last member for which `past` holds, which must hold for a prefix
+    // of the set
+    fn seek(&self, past: impl Fn(&Node) -> bool) -> Option<usize> {
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].links[i].next
+                && past(&self.nodes[next])
+            {
+                x = next;
+            }
+        }
+        (x != HEAD).then_some(x)
+    }
+
+    fn at_rank(&self, rank: usize) -> Option<usize> {
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].links[i].next
+                && traversed + self.nodes[x].links[i].span <= target
+            {
+                traversed += self.nodes[x].links[i].span;
+                x = next;
+            }
+            if traversed == target {
+                return Some(x);
+            }
+        }
+        None
+    }
+
+    // For every level, the last node before (score, member) and its rank.
+    // `link` and `unlink` expect `member` to be missing from `scores`, so
+    // that `len` counts the members linked besides it.
+    fn path(&self, score: f64, member: &str) -> ([usize; MAX_LEVEL], [usize; MAX_LEVEL]) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i + 1 == self.level { 0 } else { rank[i + 1] };
+            while let Some(next) = self.nodes[x].links[i].next
+                && self.nodes[next].precedes(score, member)
+            {
+                rank[i] += self.nodes[x].links[i].span;
+                x = next;
+            }
+            update[i] = x;
+        }
+        (update, rank)
+    }
+
+    fn link(&mut self, member: String, score: f64) {
+        // Levels above the current top start from the head, spanning every
+        // member
+        let (update, rank) = self.path(score, &member);
+        let level = self.random_level();
+        if level > self.level {
+            for i in self.level..level {
+                self.nodes[HEAD].links[i].span = self.len();
+            }
+            self.level = level;
+        }
+
+        let node = Node {
+            member,
+            score,
+            links: vec![Link::default(); level],
+            prev: (update[0] != HEAD).then_some(update[0]),
+        };
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.nodes[id] = node;
+                id
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+
+        for i in 0..level {
+            let before = self.nodes[update[i]].links[i];
+            let skipped = rank[0] - rank[i];
+            self.nodes[id].links[i] = Link {
+                next: before.next,
+                span: before.span - skipped,
+            };
+            self.nodes[update[i]].links[i] = Link {
+                next: Some(id),
+                span: skipped + 1,
+            };
+        }
+        for (i, &before) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[before].links[i].span += 1;
+        }
+        match self.next(id) {
+            Some(next) => self.nodes[next].prev = Some(id),
+            None => self.tail = Some(id),
+        }
+    }
+
+    fn unlink(&mut self, score: f64, member: &str) {
+        let (update, _) = self.path(score, member);
+        let Some(id) = self.next(update[0]) else {
+            return;
+        };
+        debug_assert_eq!(self.nodes[id].member, member);
+
+        for (i, &before) in update.iter().enumerate().take(self.level) {
+            if self.nodes[before].links[i].next == Some(id) {
+                let removed = self.nodes[id].links[i];
+                let link = &mut self.nodes[before].links[i];
+                link.span = link.span + removed.span - 1;
+                link.next = removed.next;
+            } else {
+                self.nodes[before].links[i].span -= 1;
+            }
+        }
+        let prev = self.nodes[id].prev;
+        match self.next(id) {
+            Some(next) => self.nodes[next].prev = prev,
+            None => self.tail = prev,
+        }
+        while self.level > 1 && self.nodes[HEAD].links[self.level - 1].next.is_none() {
+            self.level -= 1;
+        }
+
+        let node = &mut self.nodes[id];
+        node.member = String::new();
+        node.links = Vec::new();
+        self.free.push(id);
+    }
+
+    // Each level up holds a quarter of the members of the one below
+    fn random_level(&mut self) -> usize {
+        let mut level = 1;
+        while level < MAX_LEVEL && self.next_random().is_multiple_of(4) {
+            level += 1;
+        }
+        level
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+}
+
+impl fmt::Debug for SortedSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl From<Vec<(String, f64)>> for SortedSet {
+    fn from(members: Vec<(String, f64)>) -> Self {
+        let mut set = Self::new();
+        for (member, score) in members {
+            set.insert(member, score);
+        }
+        set
+    }
+}
+
+impl From<SortedSet> for Vec<(String, f64)> {
+    fn from(set: SortedSet) -> Self {
+        set.iter()
+            .map(|(member, score)| (member.to_string(), score))
+            .collect()
+    }
+}
+
+/// Which members a range query selects: those at indices `start..=stop`
+/// (negative indices count back from the highest), those with scores between
+/// two bounds, or, in a set whose members all share a score, those between
+/// two members.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RangeBy {
+    Index(i64, i64),
+    Score(ScoreBound, ScoreBound),
+    Lex(LexBound, LexBound),
+}
+
+/// One end of a score range, written `1.5` to include the score, `(1.5` to
+/// exclude it, or `-inf`/`+inf`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ScoreBound {
+    pub score: f64,
+    pub exclusive: bool,
+}
+
+impl ScoreBound {
+    // Whether `score` is within this bound taken as a minimum
+    fn admits_above(&self, score: f64) -> bool {
+        score > self.score || (!self.exclusive && score == self.score)
+    }
+
+    // Whether `score` is within this bound taken as a maximum
+    fn admits_below(&self, score: f64) -> bool {
+        score < self.score || (!self.exclusive && score == self.score)
+    }
+}
+
+impl FromStr for ScoreBound {
+    type Err = VaporDBError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (exclusive, score) = match s.strip_prefix('(') {
+            Some(score) => (true, score),
+            None => (false, s),
+        };
+        Ok(Self {
+            score: parse_score(score)?,
+            exclusive,
+        })
+    }
+}
+
+impl TryFrom<String> for ScoreBound {
+    type Error = VaporDBError;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<ScoreBound> for String {
+    fn from(bound: ScoreBound) -> Self {
+        let score = match bound.score {
+            f64::INFINITY => "+inf".to_string(),
+            score => score.to_string(),
+        };
+        if bound.exclusive {
+            format!("({score}")
+        } else {
+            score
+        }
+    }
+}
+
+/// One end of a lexicographic range, written `[member` to include the
+/// member, `(member` to exclude it, or `-`/`+` for the lowest and highest
+/// possible members.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum LexBound {
+    Min,
+    Max,
+    Inclusive(String),
+    Exclusive(String),
+}
+
+impl LexBound {
+    fn admits_above(&self, member: &str) -> bool {
+        match self {
+            Self::Min => true,
+            Self::Max => false,
+            Self::Inclusive(min) => member >= min.as_str(),
+            Self::Exclusive(min) => member > min.as_str(),
+        }
+    }
+
+    fn admits_below(&self, member: &str) -> bool {
+        match self {
+            Self::Min => false,
+            Self::Max => true,
+            Self::Inclusive(max) => member <= max.as_str(),
+            Self::Exclusive(max) => member < max.as_str(),
+        }
+    }
+}
+
+impl FromStr for LexBound {
+    type Err = VaporDBError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_at_checked(1) {
+            Some(("-", "")) => Ok(Self::Min),
+            Some(("+", "")) => Ok(Self::Max),
+            Some(("[", member)) => Ok(Self::Inclusive(member.to_string())),
+            Some(("(", member)) => Ok(Self::Exclusive(member.to_string())),
+            _ => Err(VaporDBError::InvalidArgument(format!(
+                "invalid lex bound '{s}', expected -, +, [member or (member"
+            ))),
+        }
+    }
+}
+
+impl TryFrom<String> for LexBound {
+    type Error = VaporDBError;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<LexBound> for String {
+    fn from(bound: LexBound) -> Self {
+        match bound {
+            LexBound::Min => "-".to_string(),
+            LexBound::Max => "+".to_string(),
+            LexBound::Inclusive(member) => format!("[{member}"),
+            LexBound::Exclusive(member) => format!("({member}"),
+        }
+    }
+}
+
+/// Parses a score, accepting `inf`, `+inf` and `-inf` but not NaN.
+pub fn parse_score(s: &str) -> Result<f64> {
+    match s.parse::<f64>() {
+        Ok(score) if !score.is_nan() => Ok(score),
+        _ => Err(VaporDBError::InvalidArgument(format!(
+            "invalid score '{s}', expected a number, -inf or +inf"
+        ))),
+    }
+}
+
+/// Parses (score, member) pairs whose scores are strings, as ZADD takes them
+/// over HTTP.
+pub fn parse_scored_members(members: Vec<(String, String)>) -> Result<Vec<(f64, String)>> {
+    members
+        .into_iter()
+        .map(|(score, member)| Ok((parse_score(&score)?, member)))
+        .collect()
+}
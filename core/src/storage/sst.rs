@@ -1,145 +1,881 @@
-use crate::error::Result;
+// On-disk layout of an SSTable:
+//
+//   [data block 0] .. [data block N-1] [filter block] [index block] [footer]
+//
+// Data blocks hold length-prefixed, bincode-encoded `SSTableEntry` records
+// sorted by internal key (user key, then newest first), cut once a block
+// reaches `BLOCK_SIZE` bytes. A user key may appear once per version. The
+// filter block is a Bloom filter over every user key (empty when filters are
+// disabled). The index block is a bincode-encoded `Vec<BlockHandle>` with the
+// last internal key of every block, so a lookup checks the filter,
+// binary-searches the index and reads exactly one block. The footer ends with
+// the format version and the magic number; version 1 tables have no filter
+// block, tables before version 3 carry no sequence numbers, and tables before
+// version 4 store expiry times in epoch seconds rather than milliseconds.
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::{Result, VaporDBError};
 use crate::storage::Value;
+use crate::storage::bloom::{self, BloomFilter, FilterStats};
+use crate::storage::internal_key::{InternalKey, MAX_SEQUENCE, SequenceNumber, ValueKind};
+use crate::storage::scan::KeyRange;
+use crate::storage::snapshots::VersionFilter;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const SST_MAGIC: u64 = u64::from_le_bytes(*b"VAPORSST");
+pub const SST_FORMAT_VERSION: u32 = 4;
+pub const BLOCK_SIZE: usize = 4096;
+pub const DEFAULT_BLOOM_BITS_PER_KEY: usize = 10;
+
+// version + magic, shared by every format version
+const FOOTER_TAIL_SIZE: usize = 4 + 8;
+// v1: index offset + index len + entry count + tail
+const FOOTER_SIZE_V1: usize = 8 + 8 + 8 + FOOTER_TAIL_SIZE;
+// v2 and later: v1 fields + filter offset + filter len + tail
+const FOOTER_SIZE_V2: usize = 8 + 8 + 8 + 8 + 8 + FOOTER_TAIL_SIZE;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SSTableOptions {
+    pub bloom_bits_per_key: usize, // 0 disables the filter block
+}
+
+impl Default for SSTableOptions {
+    fn default() -> Self {
+        Self {
+            bloom_bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-struct SSTableEntry {
+pub struct SSTableEntry {
+    pub key: String,
+    #[serde(default)]
+    pub seq: SequenceNumber, // 0 for tables written before sequence numbers
+    pub value: Option<Value>, // None = tombstone
+    pub ttl: Option<u64>,     // Epoch milliseconds
+}
+
+// Entry layout of format versions 1 and 2
+#[derive(Deserialize)]
+struct EntryV2 {
     key: String,
-    value: Option<Value>, // None = tombstone
-    ttl: Option<u64>,     // Epoch seconds
+    value: Option<Value>,
+    ttl: Option<u64>,
 }
 
-#[derive(Clone)]
-pub struct SSTable {
-    pub map: HashMap<String, Option<Value>>,
-    pub ttl_map: HashMap<String, u64>,
+impl From<EntryV2> for SSTableEntry {
+    fn from(entry: EntryV2) -> Self {
+        Self {
+            key: entry.key,
+            seq: 0,
+            value: entry.value,
+            ttl: entry.ttl,
+        }
+    }
 }
 
-impl SSTable {
-    fn current_timestamp() -> u64 {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs()
+impl SSTableEntry {
+    pub fn kind(&self) -> ValueKind {
+        match self.value {
+            Some(_) => ValueKind::Put,
+            None => ValueKind::Delete,
+        }
     }
 
-    pub fn load(path: &str) -> Result<Self> {
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        let mut map = HashMap::new();
-        let mut ttl_map = HashMap::new();
+    pub fn internal_key(&self) -> InternalKey {
+        InternalKey::new(self.key.clone(), self.seq, self.kind())
+    }
 
-        for line in reader.lines() {
-            let line = line?;
-            let entry: SSTableEntry = match serde_json::from_str(&line) {
-                Ok(e) => e,
-                Err(e) => {
-                    eprintln!("Skipping malformed line: {e}");
-                    continue;
-                }
-            };
+    // Internal key order without cloning the user key
+    fn cmp_internal(&self, key: &str, seq: SequenceNumber) -> Ordering {
+        self.key.as_str().cmp(key).then(seq.cmp(&self.seq))
+    }
 
-            // Check TTL before inserting
-            if let Some(ttl) = entry.ttl {
-                if Self::current_timestamp() >= ttl {
-                    // Skip expired entry entirely
-                    continue;
-                }
-                ttl_map.insert(entry.key.clone(), ttl);
-            }
+    /// Whether the entry's TTL passed by `now`, in epoch milliseconds.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.ttl.is_some_and(|ttl| now >= ttl)
+    }
 
-            map.insert(entry.key, entry.value);
+    // An expired entry still has to shadow older versions of its key, so it
+    // is written out as a tombstone rather than dropped.
+    pub(crate) fn expire(mut self, now: u64) -> Self {
+        if self.is_expired(now) {
+            self.value = None;
+            self.ttl = None;
+        }
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct BlockHandle {
+    last_key: String,
+    last_seq: SequenceNumber,
+    offset: u64,
+    len: u64,
+}
+
+impl BlockHandle {
+    fn cmp_internal(&self, key: &str, seq: SequenceNumber) -> Ordering {
+        self.last_key.as_str().cmp(key).then(seq.cmp(&self.last_seq))
+    }
+}
+
+// Index layout of format versions 1 and 2
+#[derive(Deserialize)]
+struct BlockHandleV2 {
+    last_key: String,
+    offset: u64,
+    len: u64,
+}
+
+struct Footer {
+    index_offset: u64,
+    index_len: u64,
+    num_entries: u64,
+    filter_offset: u64,
+    filter_len: u64,
+}
+
+impl Footer {
+    fn encode(&self) -> [u8; FOOTER_SIZE_V2] {
+        let mut buf = [0u8; FOOTER_SIZE_V2];
+        buf[0..8].copy_from_slice(&self.index_offset.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.index_len.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.num_entries.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.filter_offset.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.filter_len.to_le_bytes());
+        buf[40..44].copy_from_slice(&SST_FORMAT_VERSION.to_le_bytes());
+        buf[44..52].copy_from_slice(&SST_MAGIC.to_le_bytes());
+        buf
+    }
+
+    // Returns the version and footer size announced by the fixed-size tail.
+    fn decode_tail(tail: &[u8; FOOTER_TAIL_SIZE]) -> Result<(u32, usize)> {
+        let magic = u64::from_le_bytes(tail[4..12].try_into().unwrap());
+        if magic != SST_MAGIC {
+            return Err(VaporDBError::Corruption("bad SSTable magic number".into()));
         }
 
-        Ok(Self { map, ttl_map })
+        let version = u32::from_le_bytes(tail[0..4].try_into().unwrap());
+        match version {
+            1 => Ok((version, FOOTER_SIZE_V1)),
+            2..=4 => Ok((version, FOOTER_SIZE_V2)),
+            _ => Err(VaporDBError::Corruption(format!(
+                "unsupported SSTable version {version}"
+            ))),
+        }
     }
 
-    pub fn write(path: &str, map: &HashMap<String, Option<Value>>, ttl_map: &HashMap<String, u64>) -> Result<()> {
+    fn decode(version: u32, buf: &[u8]) -> Self {
+        let field = |i: usize| u64::from_le_bytes(buf[i * 8..i * 8 + 8].try_into().unwrap());
+        let (filter_offset, filter_len) = if version >= 2 {
+            (field(3), field(4))
+        } else {
+            (0, 0)
+        };
+
+        Self {
+            index_offset: field(0),
+            index_len: field(1),
+            num_entries: field(2),
+            filter_offset,
+            filter_len,
+        }
+    }
+}
+
+/// Streams sorted entries into a new SSTable file. The table is built under a
+/// `.tmp` name and only renamed into place by `finish`, so readers never see
+/// a half-written file.
+pub struct SSTableWriter {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    writer: BufWriter<File>,
+    block: Vec<u8>,
+    last_key: Option<(String, SequenceNumber)>,
+    index: Vec<BlockHandle>,
+    key_hashes: Vec<u64>,
+    offset: u64,
+    num_entries: u64,
+    options: SSTableOptions,
+}
+
+impl SSTableWriter {
+    pub fn new(path: impl AsRef<Path>, options: SSTableOptions) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
         let file = OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(true)
-            .open(path)?;
-        let mut writer = BufWriter::new(file);
+            .open(&tmp_path)?;
 
-        for (key, value) in map {
-            if let Some(ttl) = ttl_map.get(key) {
-                if Self::current_timestamp() >= *ttl {
-                    continue; // Skip expired
-                }
-            }
+        Ok(Self {
+            path,
+            tmp_path,
+            writer: BufWriter::new(file),
+            block: Vec::with_capacity(BLOCK_SIZE),
+            last_key: None,
+            index: Vec::new(),
+            key_hashes: Vec::new(),
+            offset: 0,
+            num_entries: 0,
+            options,
+        })
+    }
+
+    /// Entries must be added in strictly increasing internal key order: by
+    /// key, then by descending sequence number.
+    pub fn add(&mut self, entry: &SSTableEntry) -> Result<()> {
+        if let Some((last_key, last_seq)) = &self.last_key
+            && entry.cmp_internal(last_key, *last_seq) != Ordering::Greater
+        {
+            return Err(VaporDBError::Internal(format!(
+                "SSTable keys out of order: {:?}@{} after {:?}@{}",
+                entry.key, entry.seq, last_key, last_seq
+            )));
+        }
+        let new_user_key = self
+            .last_key
+            .as_ref()
+            .is_none_or(|(last_key, _)| *last_key != entry.key);
+
+        let encoded = encode(entry)?;
+        self.block
+            .extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+        self.block.extend_from_slice(&encoded);
+        self.last_key = Some((entry.key.clone(), entry.seq));
+        self.num_entries += 1;
+        if self.options.bloom_bits_per_key > 0 && new_user_key {
+            self.key_hashes.push(bloom::hash_key(&entry.key));
+        }
+
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+
+        self.writer.write_all(&self.block)?;
+        let (last_key, last_seq) = self.last_key.clone().unwrap_or_default();
+        self.index.push(BlockHandle {
+            last_key,
+            last_seq,
+            offset: self.offset,
+            len: self.block.len() as u64,
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Bytes of data written so far, excluding the filter, index and footer.
+    pub fn data_size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.finish_block()?;
+
+        let filter = if self.options.bloom_bits_per_key > 0 {
+            BloomFilter::build(&self.key_hashes, self.options.bloom_bits_per_key).encode()
+        } else {
+            Vec::new()
+        };
+        self.writer.write_all(&filter)?;
+        let filter_offset = self.offset;
+        self.offset += filter.len() as u64;
+
+        let index = encode(&self.index)?;
+        self.writer.write_all(&index)?;
+
+        let footer = Footer {
+            index_offset: self.offset,
+            index_len: index.len() as u64,
+            num_entries: self.num_entries,
+            filter_offset,
+            filter_len: filter.len() as u64,
+        };
+        self.writer.write_all(&footer.encode())?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        fs::rename(&self.tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+pub struct SSTable {
+    path: PathBuf,
+    file: Mutex<File>,
+    index: Vec<BlockHandle>,
+    filter: Option<BloomFilter>,
+    filter_stats: Arc<FilterStats>,
+    num_entries: u64,
+    version: u32,
+    clock: Arc<dyn Clock>,
+}
+
+impl SSTable {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::open(&path)?;
+
+        let file_len = file.metadata()?.len();
+        let too_small = || {
+            VaporDBError::Corruption(format!("{} is too small to be an SSTable", path.display()))
+        };
+        if file_len < FOOTER_TAIL_SIZE as u64 {
+            return Err(too_small());
+        }
+
+        let mut tail = [0u8; FOOTER_TAIL_SIZE];
+        file.seek(SeekFrom::Start(file_len - FOOTER_TAIL_SIZE as u64))?;
+        file.read_exact(&mut tail)?;
+        let (version, footer_size) = Footer::decode_tail(&tail)?;
+        if file_len < footer_size as u64 {
+            return Err(too_small());
+        }
+
+        let mut footer_buf = vec![0u8; footer_size];
+        file.seek(SeekFrom::Start(file_len - footer_size as u64))?;
+        file.read_exact(&mut footer_buf)?;
+        let footer = Footer::decode(version, &footer_buf);
+
+        let mut index_buf = vec![0u8; footer.index_len as usize];
+        file.seek(SeekFrom::Start(footer.index_offset))?;
+        file.read_exact(&mut index_buf)?;
+        let index: Vec<BlockHandle> = if version >= 3 {
+            decode(&index_buf)?
+        } else {
+            decode::<Vec<BlockHandleV2>>(&index_buf)?
+                .into_iter()
+                .map(|handle| BlockHandle {
+                    last_key: handle.last_key,
+                    last_seq: 0,
+                    offset: handle.offset,
+                    len: handle.len,
+                })
+                .collect()
+        };
+
+        let filter = if footer.filter_len > 0 {
+            let mut filter_buf = vec![0u8; footer.filter_len as usize];
+            file.seek(SeekFrom::Start(footer.filter_offset))?;
+            file.read_exact(&mut filter_buf)?;
+            BloomFilter::decode(&filter_buf)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+            index,
+            filter,
+            filter_stats: Arc::default(),
+            num_entries: footer.num_entries,
+            version,
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// Reports filter hits and misses into `stats` instead of a private counter.
+    pub fn with_filter_stats(mut self, stats: Arc<FilterStats>) -> Self {
+        self.filter_stats = stats;
+        self
+    }
+
+    /// Decides which entries have expired by `clock` instead of the system
+    /// clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    pub fn has_filter(&self) -> bool {
+        self.filter.is_some()
+    }
+
+    /// False means the key is definitely not in this table.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.filter
+            .as_ref()
+            .is_none_or(|filter| filter.may_contain(key))
+    }
 
+    /// Writes `map` as a new SSTable of unversioned entries. Entries whose TTL
+    /// is at or before `now` (epoch milliseconds) become tombstones.
+    pub fn write(
+        path: impl AsRef<Path>,
+        map: &HashMap<String, Option<Value>>,
+        ttl_map: &HashMap<String, u64>,
+        now: u64,
+        options: SSTableOptions,
+    ) -> Result<()> {
+        let mut keys: Vec<&String> = map.keys().collect();
+        keys.sort();
+
+        let mut writer = SSTableWriter::new(path, options)?;
+        for key in keys {
             let entry = SSTableEntry {
                 key: key.clone(),
-                value: value.clone(),
+                seq: 0,
+                value: map[key].clone(),
                 ttl: ttl_map.get(key).cloned(),
             };
-            writeln!(writer, "{}", serde_json::to_string(&entry)?)?;
+            writer.add(&entry.expire(now))?;
         }
+        writer.finish()
+    }
 
-        Ok(())
+    /// Legacy tables are text: the first line has no NUL bytes and is either a
+    /// JSON object or a tab-separated pair. Block-based tables always start
+    /// with a little-endian length prefix, which contains NULs.
+    pub fn is_legacy(path: impl AsRef<Path>) -> Result<bool> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut first_line = Vec::new();
+        reader
+            .by_ref()
+            .take(BLOCK_SIZE as u64)
+            .read_until(b'\n', &mut first_line)?;
+
+        Ok(!first_line.contains(&0)
+            && (first_line.first() == Some(&b'{') || first_line.contains(&b'\t')))
     }
 
-    pub fn get(&self, key: &str) -> Option<Value> {
-        if let Some(ttl) = self.ttl_map.get(key) {
-            if Self::current_timestamp() >= *ttl {
-                return None;
+    /// Rewrites an SSTable from before the block format in place and opens
+    /// it. Both old layouts are understood: `key\t{json value}` lines written
+    /// by `MemTable` flushes and one JSON `SSTableEntry` per line. Later lines
+    /// win, and lines that parse as neither are reported and dropped. Entries
+    /// that have expired by `clock` are written as tombstones.
+    pub fn recover_legacy(
+        path: impl AsRef<Path>,
+        options: SSTableOptions,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let reader = BufReader::new(File::open(path)?);
+
+        let mut entries = BTreeMap::new();
+        let mut skipped = 0;
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            if let Ok(entry) = serde_json::from_str::<SSTableEntry>(&line) {
+                entries.insert(entry.key.clone(), entry);
+                continue;
+            }
+
+            let parsed = line
+                .split_once('\t')
+                .and_then(|(key, json)| Some((key, serde_json::from_str::<Value>(json).ok()?)));
+            match parsed {
+                Some((key, value)) => {
+                    entries.insert(
+                        key.to_string(),
+                        SSTableEntry {
+                            key: key.to_string(),
+                            seq: 0,
+                            value: Some(value),
+                            ttl: None,
+                        },
+                    );
+                }
+                None => skipped += 1,
             }
         }
 
-        self.map.get(key).cloned().flatten()
+        if entries.is_empty() && skipped > 0 {
+            return Err(VaporDBError::Corruption(format!(
+                "{} is neither a legacy nor a block-based SSTable",
+                path.display()
+            )));
+        }
+        if skipped > 0 {
+            eprintln!(
+                "Dropped {skipped} malformed line(s) while recovering {}",
+                path.display()
+            );
+        }
+
+        let now = clock.now_millis();
+        let mut writer = SSTableWriter::new(path, options)?;
+        for entry in entries.into_values() {
+            writer.add(&entry.expire(now))?;
+        }
+        writer.finish()?;
+
+        Ok(SSTable::open(path)?.with_clock(clock))
     }
 
-    pub fn insert(&mut self, key: String, value: Value, ttl: Option<u64>) {
-        self.map.insert(key.clone(), Some(value));
-        if let Some(t) = ttl {
-            self.ttl_map.insert(key, t);
-        } else {
-            self.ttl_map.remove(&key);
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn read_block(&self, handle: &BlockHandle) -> Result<Vec<SSTableEntry>> {
+        let mut buf = vec![0u8; handle.len as usize];
+        {
+            let mut file = self.file.lock();
+            file.seek(SeekFrom::Start(handle.offset))?;
+            file.read_exact(&mut buf)?;
         }
+
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            if pos + 4 > buf.len() {
+                return Err(VaporDBError::Corruption("truncated SSTable block".into()));
+            }
+            let len = u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap()) as usize;
+            pos += 4;
+            if pos + len > buf.len() {
+                return Err(VaporDBError::Corruption("truncated SSTable block".into()));
+            }
+            let mut entry: SSTableEntry = if self.version >= 3 {
+                decode(&buf[pos..pos + len])?
+            } else {
+                decode::<EntryV2>(&buf[pos..pos + len])?.into()
+            };
+            if self.version < 4 {
+                entry.ttl = entry.ttl.map(|secs| secs.saturating_mul(1000));
+            }
+            entries.push(entry);
+            pos += len;
+        }
+        Ok(entries)
     }
 
-    pub fn delete(&mut self, key: &str) {
-        self.map.insert(key.to_string(), None);
-        self.ttl_map.remove(key);
+    /// Returns the newest raw entry for `key`, including tombstones and
+    /// expired entries.
+    pub fn get_entry(&self, key: &str) -> Result<Option<SSTableEntry>> {
+        self.get_entry_at(key, MAX_SEQUENCE)
+    }
+
+    /// Returns the newest raw entry for `key` written at or before `seq`.
+    pub fn get_entry_at(&self, key: &str, seq: SequenceNumber) -> Result<Option<SSTableEntry>> {
+        if !self.may_contain(key) {
+            self.filter_stats.record_hit();
+            return Ok(None);
+        }
+
+        // The first block ending at or after the seek key holds the first
+        // entry at or after it
+        let block_idx = self
+            .index
+            .partition_point(|handle| handle.cmp_internal(key, seq) == Ordering::Less);
+        let Some(handle) = self.index.get(block_idx) else {
+            return Ok(None);
+        };
+
+        let mut entries = self.read_block(handle)?;
+        let pos = entries.partition_point(|entry| entry.cmp_internal(key, seq) == Ordering::Less);
+        let found = (pos < entries.len() && entries[pos].key == key)
+            .then(|| entries.swap_remove(pos));
+        if self.filter.is_some() {
+            self.filter_stats.record_miss(found.is_some());
+        }
+        Ok(found)
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Value>> {
+        match self.get_entry(key)? {
+            Some(entry) if !entry.is_expired(self.clock.now_millis()) => Ok(entry.value),
+            _ => Ok(None),
+        }
+    }
+
+    /// Iterates over every entry, including every version of a key, in
+    /// internal key order one block at a time.
+    pub fn iter(&self) -> SSTableIter<'_> {
+        SSTableIter {
+            sst: self,
+            next_block: 0,
+            entries: Vec::new().into_iter(),
+        }
+    }
+
+    pub fn smallest_key(&self) -> Result<Option<String>> {
+        match self.index.first() {
+            Some(handle) => Ok(self.read_block(handle)?.into_iter().next().map(|e| e.key)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn largest_key(&self) -> Option<&str> {
+        self.index.last().map(|handle| handle.last_key.as_str())
     }
 
     pub fn size(&self) -> usize {
-        self.map.len()
+        self.num_entries as usize
     }
 
-    pub fn new() -> Self {
-        Self {
-            map: HashMap::new(),
-            ttl_map: HashMap::new(),
+    pub fn compact(
+        sst1: &SSTable,
+        sst2: &SSTable,
+        output_path: &str,
+        options: SSTableOptions,
+    ) -> Result<()> {
+        SSTable::merge(&[sst1, sst2], output_path, options)
+    }
+
+    /// K-way merges `ssts` into a new SSTable. When a key appears in several
+    /// inputs, the one that comes later in the slice wins.
+    pub fn merge(
+        ssts: &[&SSTable],
+        output_path: impl AsRef<Path>,
+        options: SSTableOptions,
+    ) -> Result<()> {
+        let mut writer = SSTableWriter::new(output_path, options)?;
+        let mut versions = VersionFilter::new(&[]);
+        for entry in MergingIter::new(ssts)? {
+            let entry = entry?;
+            if versions.keep(&entry.key, entry.seq) {
+                writer.add(&entry)?;
+            }
         }
+        writer.finish()
     }
+}
 
-    pub fn merge(ssts: &[SSTable]) -> SSTable {
-        let mut combined_map = HashMap::new();
-        let mut combined_ttl = HashMap::new();
+/// Yields every version of every key across several SSTables in internal key
+/// order: by key, then newest first. When several inputs hold the same
+/// version, which only happens with tables from before sequence numbers, the
+/// one later in the slice wins. Entries expired when the merge started, by
+/// the clock of the first input, come out as tombstones.
+pub struct MergingIter<'a> {
+    iters: Vec<SSTableIter<'a>>,
+    heap: BinaryHeap<HeapItem>,
+    now: u64,
+}
 
-        for sst in ssts {
-            for (key, value) in &sst.map {
-                combined_map.insert(key.clone(), value.clone());
+impl<'a> MergingIter<'a> {
+    pub fn new(ssts: &[&'a SSTable]) -> Result<Self> {
+        let mut iters: Vec<SSTableIter> = ssts.iter().map(|sst| sst.iter()).collect();
+        let mut heap = BinaryHeap::new();
+        for (source, iter) in iters.iter_mut().enumerate() {
+            if let Some(entry) = iter.next() {
+                heap.push(HeapItem {
+                    entry: entry?,
+                    source,
+                });
             }
-            for (key, ttl) in &sst.ttl_map {
-                combined_ttl.insert(key.clone(), *ttl);
+        }
+        let now = ssts.first().map_or(0, |sst| sst.clock.now_millis());
+        Ok(Self { iters, heap, now })
+    }
+
+    fn advance(&mut self, source: usize) -> Result<()> {
+        if let Some(next) = self.iters[source].next() {
+            self.heap.push(HeapItem {
+                entry: next?,
+                source,
+            });
+        }
+        Ok(())
+    }
+
+    fn next_entry(&mut self) -> Result<Option<SSTableEntry>> {
+        let Some(HeapItem { entry, source }) = self.heap.pop() else {
+            return Ok(None);
+        };
+
+        self.advance(source)?;
+        while let Some(top) = self.heap.peek() {
+            if top.entry.key != entry.key || top.entry.seq != entry.seq {
+                break;
+            }
+            let older = self.heap.pop().unwrap();
+            self.advance(older.source)?;
+        }
+
+        Ok(Some(entry.expire(self.now)))
+    }
+}
+
+impl Iterator for MergingIter<'_> {
+    type Item = Result<SSTableEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+pub struct SSTableIter<'a> {
+    sst: &'a SSTable,
+    next_block: usize,
+    entries: std::vec::IntoIter<SSTableEntry>,
+}
+
+impl Iterator for SSTableIter<'_> {
+    type Item = Result<SSTableEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
             }
+
+            let handle = self.sst.index.get(self.next_block)?;
+            self.next_block += 1;
+            match self.sst.read_block(handle) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Walks the entries of one table that fall inside a key range, forwards or
+/// backwards, one block at a time. Holds its own reference to the table so it
+/// can outlive the version it was taken from.
+pub struct RangeIter {
+    sst: Arc<SSTable>,
+    range: KeyRange,
+    reverse: bool,
+    // Blocks not read yet
+    blocks: std::ops::Range<usize>,
+    entries: std::vec::IntoIter<SSTableEntry>,
+}
+
+impl RangeIter {
+    pub fn new(sst: Arc<SSTable>, range: KeyRange, reverse: bool) -> Self {
+        // Blocks ending before the start key can't hold anything in range, and
+        // neither can blocks after the first one ending past the end key. A
+        // block ending at an included end key may leave older versions of it
+        // to the next block, so that one is read too
+        let first = match &range.start {
+            Bound::Included(key) | Bound::Excluded(key) => sst
+                .index
+                .partition_point(|handle| handle.last_key.as_str() < key.as_str()),
+            Bound::Unbounded => 0,
+        };
+        let last = match &range.end {
+            Bound::Included(key) => sst
+                .index
+                .partition_point(|handle| handle.last_key.as_str() <= key.as_str()),
+            Bound::Excluded(key) => sst
+                .index
+                .partition_point(|handle| handle.last_key.as_str() < key.as_str()),
+            Bound::Unbounded => sst.index.len(),
+        };
+        let last = (last + 1).min(sst.index.len());
+        Self {
+            sst,
+            range,
+            reverse,
+            blocks: first..last.max(first),
+            entries: Vec::new().into_iter(),
         }
+    }
 
-        SSTable {
-            map: combined_map,
-            ttl_map: combined_ttl,
+    fn next_entry(&mut self) -> Result<Option<SSTableEntry>> {
+        loop {
+            for entry in self.entries.by_ref() {
+                let (skip, stop) = if self.reverse {
+                    (
+                        self.range.is_after_end(&entry.key),
+                        self.range.is_before_start(&entry.key),
+                    )
+                } else {
+                    (
+                        self.range.is_before_start(&entry.key),
+                        self.range.is_after_end(&entry.key),
+                    )
+                };
+                if stop {
+                    self.blocks = 0..0;
+                    self.entries = Vec::new().into_iter();
+                    return Ok(None);
+                }
+                if !skip {
+                    return Ok(Some(entry));
+                }
+            }
+
+            let block = if self.reverse {
+                self.blocks.next_back()
+            } else {
+                self.blocks.next()
+            };
+            let Some(block) = block else {
+                return Ok(None);
+            };
+            let mut entries = self.sst.read_block(&self.sst.index[block])?;
+            if self.reverse {
+                entries.reverse();
+            }
+            self.entries = entries.into_iter();
         }
     }
+}
+
+impl Iterator for RangeIter {
+    type Item = Result<SSTableEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+// Min-heap ordering on internal key; for equal internal keys the newest
+// source pops first.
+struct HeapItem {
+    entry: SSTableEntry,
+    source: usize,
+}
+
+impl Ord for HeapItem {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .entry
+            .cmp_internal(&self.entry.key, self.entry.seq)
+            .then(self.source.cmp(&other.source))
+    }
+}
 
-    pub fn compact(sst1: &SSTable, sst2: &SSTable, output_path: &str) -> Result<()> {
-        let merged = SSTable::merge(&[sst1.clone(), sst2.clone()]);
-        SSTable::write(output_path, &merged.map, &merged.ttl_map)
+impl PartialOrd for HeapItem {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
+
+impl PartialEq for HeapItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapItem {}
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    bincode::serialize(value).map_err(|e| VaporDBError::Internal(e.to_string()))
+}
+
+fn decode<T: for<'de> Deserialize<'de>>(bytes: &[u8]) -> Result<T> {
+    bincode::deserialize(bytes).map_err(|e| VaporDBError::Corruption(e.to_string()))
+}
@@ -0,0 +1,376 @@
+// An append-only log of field-value entries under ever-increasing IDs of the
+// form `ms-seq`: the time the entry was added in epoch milliseconds, and a
+// counter for entries added within the same millisecond.
+//
+// Consumer groups share out a stream's entries. Each group remembers the last
+// entry it delivered and keeps every delivered entry in its pending entries
+// list until a consumer acknowledges it, so entries a consumer took but never
+// finished can be claimed by another one.
+
+use crate::error::{Result, VaporDBError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Bound;
+use std::str::FromStr;
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    pub fn new(ms: u64, seq: u64) -> Self {
+        Self { ms, seq }
+    }
+
+    // Reads `ms-seq`, or `ms` alone with `seq` filled in
+    fn parse_with_seq(s: &str, seq: u64) -> Result<Self> {
+        let invalid = || {
+            VaporDBError::InvalidArgument(format!("invalid stream ID '{s}', expected ms-seq or ms"))
+        };
+        let (ms, seq) = match s.split_once('-') {
+            Some((ms, seq)) => (ms, seq.parse().map_err(|_| invalid())?),
+            None => (s, seq),
+        };
+        Ok(Self {
+            ms: ms.parse().map_err(|_| invalid())?,
+            seq,
+        })
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+impl FromStr for StreamId {
+    type Err = VaporDBError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse_with_seq(s, 0)
+    }
+}
+
+impl TryFrom<String> for StreamId {
+    type Error = VaporDBError;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<StreamId> for String {
+    fn from(id: StreamId) -> Self {
+        id.to_string()
+    }
+}
+
+/// Parses the low end of an XRANGE: `-` for the first entry, `(id` to
+/// exclude the ID, or an ID whose sequence number defaults to 0.
+pub fn parse_range_start(s: &str) -> Result<Bound<StreamId>> {
+    parse_bound(s, "-", 0)
+}
+
+/// Parses the high end of an XRANGE: `+` for the last entry, `(id` to
+/// exclude the ID, or an ID whose sequence number defaults to the highest.
+pub fn parse_range_end(s: &str) -> Result<Bound<StreamId>> {
+    parse_bound(s, "+", u64::MAX)
+}
+
+fn parse_bound(s: &str, open: &str, seq: u64) -> Result<Bound<StreamId>> {
+    if s == open {
+        return Ok(Bound::Unbounded);
+    }
+    match s.strip_prefix('(') {
+        Some(id) => Ok(Bound::Excluded(StreamId::parse_with_seq(id, seq)?)),
+        None => Ok(Bound::Included(StreamId::parse_with_seq(s, seq)?)),
+    }
+}
+
+/// Parses the IDs a client sent, such as those given to XACK or XCLAIM.
+pub fn parse_ids(ids: &[String]) -> Result<Vec<StreamId>> {
+    ids.iter().map(|id| id.parse()).collect()
+}
+
+/// An entry as commands return it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamEntry {
+    pub id: StreamId,
+    pub fields: Vec<(String, String)>,
+}
+
+/// A delivered entry no consumer has acknowledged yet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingEntry {
+    pub consumer: String,
+    pub delivered_at: u64, // epoch milliseconds of the latest delivery
+    pub deliveries: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConsumerGroup {
+    last_delivered: StreamId,
+    pending: BTreeMap<StreamId, PendingEntry>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, Vec<(String, String)>>,
+    last_id: StreamId, // kept when the entry itself is trimmed
+    groups: BTreeMap<String, ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    /// Appends an entry under `id`, or under a fresh ID for the time `now` if
+    /// `id` is `None`. IDs must increase.
+    pub fn add(
+        &mut self,
+        id: Option<StreamId>,
+        fields: Vec<(String, String)>,
+        now: u64,
+    ) -> Result<StreamId> {
+        if fields.is_empty() {
+            return Err(VaporDBError::InvalidArgument(
+                "a stream entry needs at least one field".into(),
+            ));
+        }
+        let id = match id {
+            Some(id) if id > self.last_id => id,
+            Some(id) => {
+                return Err(VaporDBError::InvalidArgument(format!(
+                    "stream ID {id} is not greater than the last one, {}",
+                    self.last_id
+                )));
+            }
+            // The clock going backwards must not reorder entries
+            None if now > self.last_id.ms => StreamId::new(now, 0),
+            None => {
+                let seq = self
+                    .last_id
+                    .seq
+                    .checked_add(1)
+                    .ok_or_else(|| VaporDBError::InvalidArgument("stream IDs are exhausted".into()))?;
+                StreamId::new(self.last_id.ms, seq)
+            }
+        };
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        Ok(id)
+    }
+
+    /// Drops the oldest entries until at most `max_len` are left, returning
+    /// how many were dropped. Pending entries lists still name them.
+    pub fn trim(&mut self, max_len: usize) -> usize {
+        let mut trimmed = 0;
+        while self.entries.len() > max_len {
+            self.entries.pop_first();
+            trimmed += 1;
+        }
+        trimmed
+    }
+
+    /// Entries with IDs between `start` and `end`, newest first if `rev`,
+    /// at most `count` of them.
+    pub fn range(
+        &self,
+        start: Bound<StreamId>,
+        end: Bound<StreamId>,
+        rev: bool,
+        count: Option<usize>,
+    ) -> Vec<StreamEntry> {
+        if is_empty_range(start, end) {
+            return Vec::new();
+        }
+        let range = self.entries.range((start, end));
+        let count = count.unwrap_or(usize::MAX);
+        let to_entry = |(id, fields): (&StreamId, &Vec<(String, String)>)| StreamEntry {
+            id: *id,
+            fields: fields.clone(),
+        };
+        if rev {
+            range.rev().take(count).map(to_entry).collect()
+        } else {
+            range.take(count).map(to_entry).collect()
+        }
+    }
+
+    /// Creates a group that delivers the entries after `start`, or only
+    /// those added from now on if `start` is `None`.
+    pub fn create_group(&mut self, name: String, start: Option<StreamId>) -> Result<()> {
+        if self.groups.contains_key(&name) {
+            return Err(VaporDBError::InvalidArgument(format!(
+                "consumer group '{name}' already exists"
+            )));
+        }
+        let group = ConsumerGroup {
+            last_delivered: start.unwrap_or(self.last_id),
+            pending: BTreeMap::new(),
+        };
+        self.groups.insert(name, group);
+        Ok(())
+    }
+
+    /// With `after` as `None`, delivers to `consumer` up to `count` entries
+    /// its group has not delivered yet and adds them to the pending entries
+    /// list. Otherwise returns the entries after `after` already delivered to
+    /// `consumer` and not yet acknowledged, to pick up where it left off.
+    pub fn read_group(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        after: Option<StreamId>,
+        count: Option<usize>,
+        now: u64,
+    ) -> Result<Vec<StreamEntry>> {
+        let count = count.unwrap_or(usize::MAX);
+        let group = self
+            .groups
+            .get_mut(group)
+            .ok_or_else(|| no_such_group(group))?;
+
+        if let Some(after) = after {
+            let pending = group
+                .pending
+                .range((Bound::Excluded(after), Bound::Unbounded))
+                .filter(|(_, pending)| pending.consumer == consumer)
+                .filter_map(|(id, _)| {
+                    let fields = self.entries.get(id)?;
+                    Some(StreamEntry {
+                        id: *id,
+                        fields: fields.clone(),
+                    })
+                })
+                .take(count)
+                .collect();
+            return Ok(pending);
+        }
+
+        let entries: Vec<StreamEntry> = self
+            .entries
+            .range((Bound::Excluded(group.last_delivered), Bound::Unbounded))
+            .take(count)
+            .map(|(id, fields)| StreamEntry {
+                id: *id,
+                fields: fields.clone(),
+            })
+            .collect();
+        for entry in &entries {
+            group.last_delivered = entry.id;
+            group.pending.insert(
+                entry.id,
+                PendingEntry {
+                    consumer: consumer.to_string(),
+                    delivered_at: now,
+                    deliveries: 1,
+                },
+            );
+        }
+        Ok(entries)
+    }
+
+    /// Removes `ids` from `group`'s pending entries list, returning how many
+    /// were pending.
+    pub fn ack(&mut self, group: &str, ids: &[StreamId]) -> Result<usize> {
+        let group = self
+            .groups
+            .get_mut(group)
+            .ok_or_else(|| no_such_group(group))?;
+        Ok(ids
+            .iter()
+            .filter(|id| group.pending.remove(id).is_some())
+            .count())
+    }
+
+    /// `group`'s pending entries list, oldest entry first.
+    pub fn pending(&self, group: &str) -> Result<Vec<(StreamId, PendingEntry)>> {
+        let group = self.groups.get(group).ok_or_else(|| no_such_group(group))?;
+        Ok(group
+            .pending
+            .iter()
+            .map(|(id, pending)| (*id, pending.clone()))
+            .collect())
+    }
+
+    /// Hands the pending entries among `ids` that were last delivered at
+    /// least `min_idle` milliseconds before `now` over to `consumer`, and
+    /// returns those still in the stream. A claimed entry that was trimmed
+    /// away is dropped from the pending entries list.
+    pub fn claim(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        min_idle: u64,
+        ids: &[StreamId],
+        now: u64,
+    ) -> Result<Vec<StreamEntry>> {
+        let group = self
+            .groups
+            .get_mut(group)
+            .ok_or_else(|| no_such_group(group))?;
+        let mut claimed = Vec::new();
+        for id in ids {
+            let Some(pending) = group.pending.get_mut(id) else {
+                continue;
+            };
+            if now.saturating_sub(pending.delivered_at) < min_idle {
+                continue;
+            }
+            let Some(fields) = self.entries.get(id) else {
+                group.pending.remove(id);
+                continue;
+            };
+            pending.consumer = consumer.to_string();
+            pending.delivered_at = now;
+            pending.deliveries += 1;
+            claimed.push(StreamEntry {
+                id: *id,
+                fields: fields.clone(),
+            });
+        }
+        Ok(claimed)
+    }
+}
+
+fn no_such_group(group: &str) -> VaporDBError {
+    VaporDBError::InvalidArgument(format!("no such consumer group '{group}'"))
+}
+
+// `BTreeMap::range` panics on a range that ends before it starts, or on a
+// start and end that are the same excluded ID
+fn is_empty_range(start: Bound<StreamId>, end: Bound<StreamId>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s) | Bound::Excluded(s), Bound::Excluded(e))
+        | (Bound::Excluded(s), Bound::Included(e)) => s >= e,
+        _ => false,
+    }
+}
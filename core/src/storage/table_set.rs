@@ -0,0 +1,213 @@
+use crate::clock::Clock;
+use crate::error::{Result, VaporDBError};
+use crate::options::VaporDBOptions;
+use crate::storage::bloom::FilterStats;
+use crate::storage::internal_key::{MAX_SEQUENCE, SequenceNumber};
+use crate::storage::manifest::{FileMeta, Manifest, VersionEdit, table_path};
+use crate::storage::sst::{SSTable, SSTableEntry};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The live SSTables of a database together with the MANIFEST that records
+/// them. Shared between `VaporDB` and its compaction worker.
+pub struct TableSet {
+    manifest: Manifest,
+    tables: HashMap<u64, Arc<SSTable>>,
+    filter_stats: Arc<FilterStats>,
+    clock: Arc<dyn Clock>,
+}
+
+impl TableSet {
+    pub fn open(options: &VaporDBOptions, filter_stats: Arc<FilterStats>) -> Result<Self> {
+        fs::create_dir_all(&options.sst_dir)?;
+        let manifest = if Manifest::exists(&options.sst_dir) {
+            Manifest::open(&options.sst_dir)?
+        } else {
+            Manifest::create(&options.sst_dir, adopt_sstables(options)?)?
+        };
+
+        let mut table_set = Self {
+            manifest,
+            tables: HashMap::new(),
+            filter_stats,
+            clock: Arc::clone(&options.clock),
+        };
+        let numbers: Vec<u64> = table_set
+            .manifest
+            .version()
+            .files()
+            .map(|file| file.number)
+            .collect();
+        for number in numbers {
+            let sst = table_set.open_table(number)?;
+            table_set.tables.insert(number, sst);
+        }
+
+        remove_obsolete_files(&table_set.manifest, &options.sst_dir)?;
+        Ok(table_set)
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// The clock every table decides expiry by.
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    pub fn table(&self, number: u64) -> Option<Arc<SSTable>> {
+        self.tables.get(&number).cloned()
+    }
+
+    /// The newest live SSTable.
+    pub fn first(&self) -> Option<Arc<SSTable>> {
+        let file = self.manifest.version().files().next()?;
+        self.table(file.number)
+    }
+
+    /// Reserves a file number for a table about to be written.
+    pub fn new_table_path(&mut self) -> (u64, PathBuf) {
+        let number = self.manifest.new_file_number();
+        (number, self.manifest.sst_path(number))
+    }
+
+    // Probes the tables that may hold `key`, newest data first.
+    pub fn get_entry(&self, key: &str) -> Result<Option<SSTableEntry>> {
+        self.get_entry_at(key, MAX_SEQUENCE)
+    }
+
+    /// Like `get_entry`, but only sees versions written at or before `seq`.
+    pub fn get_entry_at(&self, key: &str, seq: SequenceNumber) -> Result<Option<SSTableEntry>> {
+        // A file's newest version is not necessarily the newest overall once
+        // snapshots are in play, so every candidate file is consulted
+        let mut newest: Option<SSTableEntry> = None;
+        for file in self.manifest.version().files_for_key(key) {
+            if newest.as_ref().is_some_and(|e| e.seq > file.largest_seq) {
+                continue;
+            }
+            if let Some(entry) = self.tables[&file.number].get_entry_at(key, seq)?
+                && newest.as_ref().is_none_or(|e| entry.seq > e.seq)
+            {
+                newest = Some(entry);
+            }
+        }
+        Ok(newest)
+    }
+
+    /// The expiry of every key whose newest version in the tables is a value
+    /// that expires, in epoch milliseconds, including expiries already past.
+    pub fn expirations(&self) -> Result<HashMap<String, u64>> {
+        // The newest version of each key and when it expires, if it does
+        let mut newest: HashMap<String, (SequenceNumber, Option<u64>)> = HashMap::new();
+        for file in self.manifest.version().files() {
+            for entry in self.tables[&file.number].iter() {
+                let entry = entry?;
+                let expire_at = entry.value.as_ref().and(entry.ttl);
+                match newest.get_mut(&entry.key) {
+                    Some(version) if version.0 >= entry.seq => {}
+                    Some(version) => *version = (entry.seq, expire_at),
+                    None => {
+                        newest.insert(entry.key, (entry.seq, expire_at));
+                    }
+                }
+            }
+        }
+        Ok(newest
+            .into_iter()
+            .filter_map(|(key, (_, expire_at))| Some((key, expire_at?)))
+            .collect())
+    }
+
+    /// Opens the tables `edit` adds, records the edit in the manifest and
+    /// deletes the files it drops. Readers still holding a dropped table keep
+    /// their open file handle.
+    pub fn install(&mut self, edit: VersionEdit) -> Result<()> {
+        let mut added = Vec::with_capacity(edit.added.len());
+        for file in &edit.added {
+            added.push((file.number, self.open_table(file.number)?));
+        }
+        let deleted = edit.deleted.clone();
+
+        self.manifest.log_and_apply(edit)?;
+
+        self.tables.extend(added);
+        for number in deleted {
+            self.tables.remove(&number);
+            let _ = fs::remove_file(self.manifest.sst_path(number));
+        }
+        Ok(())
+    }
+
+    fn open_table(&self, number: u64) -> Result<Arc<SSTable>> {
+        let sst = SSTable::open(self.manifest.sst_path(number))?
+            .with_filter_stats(Arc::clone(&self.filter_stats))
+            .with_clock(Arc::clone(&self.clock));
+        Ok(Arc::new(sst))
+    }
+}
+
+// Brings SSTables written before the MANIFEST existed under its control.
+// They are renumbered in name order (oldest first) and placed in level 0.
+// Nothing is recorded until the manifest is created, so a crash part way
+// through is simply redone on the next open.
+fn adopt_sstables(options: &VaporDBOptions) -> Result<Vec<FileMeta>> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(&options.sst_dir)? {
+        let path = entry?.path();
+        if path.extension().map(|ext| ext == "sst").unwrap_or(false) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut files = vec![];
+    for (i, path) in paths.into_iter().enumerate() {
+        let number = i as u64 + 1;
+        let new_path = table_path(&options.sst_dir, number);
+        if path != new_path {
+            fs::rename(&path, &new_path)?;
+        }
+
+        let sst = match SSTable::open(&new_path) {
+            Ok(sst) => sst.with_clock(Arc::clone(&options.clock)),
+            Err(VaporDBError::Corruption(_)) if SSTable::is_legacy(&new_path)? => {
+                println!("Rewriting legacy SSTable {}", path.display());
+                SSTable::recover_legacy(
+                    &new_path,
+                    options.sst_options(),
+                    Arc::clone(&options.clock),
+                )?
+            }
+            Err(e) => return Err(e),
+        };
+        files.push(FileMeta::from_table(number, 0, &sst, number, number)?);
+    }
+    Ok(files)
+}
+
+// Deletes half-written tables and tables that were never logged to, or have
+// since been dropped from, the manifest.
+fn remove_obsolete_files(manifest: &Manifest, dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let live = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("tmp") => false,
+            Some("sst") => path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+                .is_some_and(|number| {
+                    manifest.version().contains(number) && manifest.sst_path(number) == path
+                }),
+            _ => true,
+        };
+        if !live {
+            println!("Removing obsolete file {}", path.display());
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
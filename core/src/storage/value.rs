@@ -1,3 +1,5 @@
+use crate::storage::sorted_set::SortedSet;
+use crate::storage::stream::Stream;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 
@@ -7,4 +9,20 @@ pub enum Value {
     Hash(HashMap<String, String>),
     List(Vec<String>),
     Set(HashSet<String>),
+    SortedSet(SortedSet),
+    Stream(Stream),
+}
+
+impl Value {
+    /// The type's name as type mismatch errors spell it.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Hash(_) => "hash",
+            Value::List(_) => "list",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "sorted set",
+            Value::Stream(_) => "stream",
+        }
+    }
 }
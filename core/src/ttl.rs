@@ -1,50 +1,125 @@
+use crate::clock::{Clock, SystemClock};
 use parking_lot::RwLock;
-use std::collections::HashMap;
-use std::time::{SystemTime, UNIX_EPOCH, Duration};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
 
+// Every expiry is kept twice: by key for lookups, and ordered by time so that
+// finding the keys due for deletion costs as much as there are such keys,
+// however many others carry a TTL.
 #[derive(Debug)]
 pub struct ExpirationTable {
-    pub expirations: RwLock<HashMap<String, u64>>,
+    state: RwLock<Expirations>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for ExpirationTable {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Expirations {
+    by_key: HashMap<String, u64>,
+    by_time: BTreeSet<(u64, String)>,
 }
 
 impl ExpirationTable {
     pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A table telling the time from `clock`.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
         Self {
-            expirations: RwLock::new(HashMap::new()),
+            state: RwLock::new(Expirations::default()),
+            clock,
         }
     }
 
-    pub fn current_timestamp() -> u64 {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// The current time in epoch milliseconds, the unit of every expiry
+    /// time.
+    pub fn now(&self) -> u64 {
+        self.clock.now_millis()
     }
 
     pub fn set(&self, key: String, ttl: Duration) {
-        let expire_at = Self::current_timestamp() + ttl.as_secs();
-        self.expirations.write().insert(key, expire_at);
+        self.set_at(key, self.expire_at(ttl));
+    }
+
+    /// Expires `key` at `expire_at`, in epoch milliseconds.
+    pub fn set_at(&self, key: String, expire_at: u64) {
+        let mut state = self.state.write();
+        if let Some(old) = state.by_key.insert(key.clone(), expire_at) {
+            state.by_time.remove(&(old, key.clone()));
+        }
+        state.by_time.insert((expire_at, key));
+    }
+
+    /// When a key set now with `ttl` expires, in epoch milliseconds. Sub-
+    /// millisecond TTLs round up, so a key never expires early.
+    pub fn expire_at(&self, ttl: Duration) -> u64 {
+        let millis = ttl.as_nanos().div_ceil(1_000_000);
+        self.now().saturating_add(millis.try_into().unwrap_or(u64::MAX))
+    }
+
+    pub fn get(&self, key: &str) -> Option<u64> {
+        self.state.read().by_key.get(key).copied()
     }
 
     pub fn is_expired(&self, key: &str) -> bool {
-        if let Some(&timestamp) = self.expirations.read().get(key) {
-            return Self::current_timestamp() >= timestamp;
+        if let Some(&timestamp) = self.state.read().by_key.get(key) {
+            return self.now() >= timestamp;
         }
         false
     }
 
     pub fn get_expired_keys(&self) -> Vec<String> {
-        let now = Self::current_timestamp();
-        let expirations = self.expirations.read();
-        expirations
+        let now = self.now();
+        self.state
+            .read()
+            .by_time
             .iter()
-            .filter(|(_, expiry)| now >= **expiry)
-            .map(|(k, _)| k.clone())
+            .take_while(|(expiry, _)| *expiry <= now)
+            .map(|(_, k)| k.clone())
             .collect()
     }
 
+    /// Up to `limit` keys that expired by `now`, soonest first. They stay in
+    /// the table until removed, so a key whose delete fails is not lost.
+    pub fn due(&self, now: u64, limit: usize) -> Vec<String> {
+        self.state
+            .read()
+            .by_time
+            .iter()
+            .take_while(|(expiry, _)| *expiry <= now)
+            .take(limit)
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+
+    /// The soonest expiry in the table.
+    pub fn next_expiry(&self) -> Option<u64> {
+        self.state.read().by_time.first().map(|(expiry, _)| *expiry)
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.read().by_key.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
     pub fn remove(&self, key: &str) {
-        self.expirations.write().remove(key);
+        let mut state = self.state.write();
+        if let Some(expiry) = state.by_key.remove(key) {
+            state.by_time.remove(&(expiry, key.to_string()));
+        }
     }
 }
@@ -0,0 +1,133 @@
+// Change data capture tails the WAL. Every mutation is logged with its
+// sequence number before it is acknowledged, so reading the segments in order
+// yields the whole history of the database that retention has kept, and
+// a consumer only has to remember the next sequence number it wants in order
+// to pick up where it left off.
+
+use crate::error::{Result, VaporDBError};
+use crate::storage::internal_key::SequenceNumber;
+use crate::wal::group_commit::GroupCommit;
+use crate::wal::wal::{self, WalRecord};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Committed WAL records in sequence order, starting from a cursor.
+pub struct ChangeStream {
+    path: PathBuf,
+    commit: Arc<GroupCommit>,
+    cursor: SequenceNumber,       // the next sequence number to hand out
+    position: Option<(u64, u64)>, // segment and offset to read on from
+    buffered: VecDeque<WalRecord>,
+}
+
+impl ChangeStream {
+    /// Streams the log at `path` from `cursor` on; 0 starts at the oldest
+    /// record still retained.
+    pub fn new(path: impl Into<PathBuf>, commit: Arc<GroupCommit>, cursor: SequenceNumber) -> Self {
+        Self {
+            path: path.into(),
+            commit,
+            cursor,
+            position: None,
+            buffered: VecDeque::new(),
+        }
+    }
+
+    /// The sequence number to resume from after a restart, one past the last
+    /// record returned.
+    pub fn cursor(&self) -> SequenceNumber {
+        self.cursor
+    }
+
+    /// The next committed record, or `None` if the stream has caught up.
+    pub fn try_next(&mut self) -> Result<Option<WalRecord>> {
+        if self.buffered.is_empty() {
+            self.fill()?;
+        }
+        let record = self.buffered.pop_front();
+        if let Some(record) = &record {
+            self.cursor = record.seq + 1;
+        }
+        Ok(record)
+    }
+
+    /// Like `try_next`, but waits up to `timeout` for a record to commit.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<WalRecord>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            // Taken before reading, so a commit landing in between still
+            // wakes the wait below
+            let seen = self.commit.committed();
+            if let Some(record) = self.try_next()? {
+                return Ok(Some(record));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            self.commit.wait_committed(seen, deadline - now);
+        }
+    }
+
+    // Reads committed records from where the last read stopped, moving on
+    // to the next segment once the current one is sealed.
+    fn fill(&mut self) -> Result<()> {
+        let committed = self.commit.committed();
+        if committed < self.cursor {
+            return Ok(());
+        }
+        let numbers = wal::segment_numbers(&self.path)?;
+        // The segment last read from may have been purged since, once
+        // everything in it was consumed and flushed
+        let saved = self.position.filter(|(number, _)| numbers.contains(number));
+        let (mut number, mut offset) = match saved {
+            Some(position) => position,
+            None => match numbers.first() {
+                Some(&first) => (first, 0),
+                None => return Ok(()),
+            },
+        };
+
+        // Reading from the oldest segment, the first record at or past the
+        // cursor must be the cursor itself, unless an older record turns up
+        // first, proving nothing the cursor needs was purged
+        let mut unproven = saved.is_none() && self.cursor > 0;
+        loop {
+            let records = wal::read_segment_from(&wal::segment_path(&self.path, number), offset)?;
+            let mut uncommitted = false;
+            for (record, end) in records {
+                if record.seq > committed {
+                    uncommitted = true;
+                    break;
+                }
+                offset = end;
+                if record.seq < self.cursor {
+                    unproven = false;
+                    continue;
+                }
+                if unproven && record.seq > self.cursor {
+                    return Err(VaporDBError::SequenceUnavailable(self.cursor));
+                }
+                unproven = false;
+                self.buffered.push_back(record);
+            }
+
+            let next = numbers.iter().find(|&&n| n > number);
+            match next {
+                // A newer segment exists, so this one is sealed
+                Some(&next) if !uncommitted => {
+                    number = next;
+                    offset = 0;
+                }
+                _ => break,
+            }
+        }
+        // Until a record settles it, the next read checks again
+        if !unproven {
+            self.position = Some((number, offset));
+        }
+        Ok(())
+    }
+}
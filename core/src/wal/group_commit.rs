@@ -0,0 +1,205 @@
+// Group commit lets writers that append to the WAL at the same time share one
+// fsync. Appends only hand records to the OS; a writer that needs its record
+// on disk either finds that a sync already covered it, waits for the one in
+// progress, or becomes the leader and syncs everything written so far on
+// behalf of whoever is waiting behind it.
+
+use crate::error::Result;
+use crate::storage::internal_key::SequenceNumber;
+use crate::wal::wal::Durability;
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use std::fs::File;
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often `Durability::EverySec` syncs the log unless told otherwise.
+pub const EVERYSEC_INTERVAL: Duration = Duration::from_secs(1);
+
+type DurableHook = Box<dyn Fn(SequenceNumber) + Send + Sync>;
+
+pub struct GroupCommit {
+    durability: Durability,
+    state: Mutex<CommitState>,
+    // Signalled whenever `written` or `durable` moves
+    advanced: Condvar,
+    on_durable: Mutex<Option<DurableHook>>,
+}
+
+struct CommitState {
+    file: Arc<File>,         // the segment taking appends
+    written: SequenceNumber, // newest record handed to the OS
+    durable: SequenceNumber, // newest record known to be on disk
+    syncing: bool,           // a leader is inside fsync
+    syncs: u64,
+}
+
+impl GroupCommit {
+    pub(crate) fn new(durability: Durability, file: File) -> Self {
+        Self {
+            durability,
+            state: Mutex::new(CommitState {
+                file: Arc::new(file),
+                written: 0,
+                durable: 0,
+                syncing: false,
+                syncs: 0,
+            }),
+            advanced: Condvar::new(),
+            on_durable: Mutex::new(None),
+        }
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// Returns once the write numbered `seq` is as durable as the policy
+    /// promises: on disk under `Always`, handed to the OS otherwise.
+    pub fn commit(&self, seq: SequenceNumber) -> Result<()> {
+        if self.durability == Durability::Always {
+            self.wait_durable(seq)?;
+        }
+        Ok(())
+    }
+
+    /// Calls `hook` with the newest write that keeps the policy's promise
+    /// each time that moves: once synced under `Always` and `EverySec`, once
+    /// handed to the OS under `Os`.
+    pub fn on_durable(&self, hook: impl Fn(SequenceNumber) + Send + Sync + 'static) {
+        *self.on_durable.lock() = Some(Box::new(hook));
+    }
+
+    /// Forces every record written so far to disk.
+    pub fn sync(&self) -> Result<()> {
+        let written = self.state.lock().written;
+        self.wait_durable(written)
+    }
+
+    /// The newest write known to be on disk.
+    pub fn durable(&self) -> SequenceNumber {
+        self.state.lock().durable
+    }
+
+    /// How many fsyncs the log has issued.
+    pub fn syncs(&self) -> u64 {
+        self.state.lock().syncs
+    }
+
+    /// The newest write that may be acknowledged, and so handed to change
+    /// streams: the newest durable one under `Always`, the newest written
+    /// one otherwise.
+    pub fn committed(&self) -> SequenceNumber {
+        self.committed_in(&self.state.lock())
+    }
+
+    /// Blocks until a write newer than `seq` commits or `timeout` passes,
+    /// returning the newest committed write.
+    pub fn wait_committed(&self, seq: SequenceNumber, timeout: Duration) -> SequenceNumber {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock();
+        while self.committed_in(&state) <= seq {
+            if self.advanced.wait_until(&mut state, deadline).timed_out() {
+                break;
+            }
+        }
+        self.committed_in(&state)
+    }
+
+    fn committed_in(&self, state: &CommitState) -> SequenceNumber {
+        match self.durability {
+            Durability::Always => state.durable,
+            _ => state.written,
+        }
+    }
+
+    pub(crate) fn written(&self, seq: SequenceNumber) {
+        let mut state = self.state.lock();
+        state.written = state.written.max(seq);
+        self.advanced.notify_all();
+        if self.durability == Durability::Os {
+            self.notify_durable(state.written);
+        }
+    }
+
+    // Writes replayed from the log were on disk before this process started
+    pub(crate) fn recovered(&self, seq: SequenceNumber) {
+        let mut state = self.state.lock();
+        state.written = state.written.max(seq);
+        state.durable = state.durable.max(seq);
+    }
+
+    // Called with the old segment fully synced, so nothing waits on it
+    pub(crate) fn switch_file(&self, file: File) {
+        self.state.lock().file = Arc::new(file);
+    }
+
+    fn wait_durable(&self, seq: SequenceNumber) -> Result<()> {
+        let mut state = self.state.lock();
+        while state.durable < seq {
+            if state.syncing {
+                self.advanced.wait(&mut state);
+                continue;
+            }
+
+            state.syncing = true;
+            let target = state.written;
+            let file = Arc::clone(&state.file);
+            let result = MutexGuard::unlocked(&mut state, || file.sync_data());
+            state.syncing = false;
+            self.advanced.notify_all();
+            result?;
+            state.durable = state.durable.max(target);
+            state.syncs += 1;
+            self.notify_durable(state.durable);
+        }
+        Ok(())
+    }
+
+    // Called with the state locked, so hooks see the sequence only grow
+    fn notify_durable(&self, seq: SequenceNumber) {
+        if let Some(hook) = self.on_durable.lock().as_ref() {
+            hook(seq);
+        }
+    }
+}
+
+/// Syncs the log every `interval` for `Durability::EverySec`.
+pub struct SyncWorker {
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl SyncWorker {
+    pub fn start(commit: Arc<GroupCommit>, interval: Duration) -> Self {
+        let (sender, receiver) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            loop {
+                match receiver.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => {
+                        if let Err(e) = commit.sync() {
+                            eprintln!("WAL sync failed: {e}");
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                    Ok(()) => {}
+                }
+            }
+        });
+
+        Self {
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for SyncWorker {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
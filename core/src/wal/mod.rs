@@ -1 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod wal;
+pub mod group_commit;
+pub mod cdc;
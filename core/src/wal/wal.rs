@@ -1,72 +1,660 @@
-use crate::error::{VaporDBError, Result};
+use crate::clock::{Clock, SystemClock};
+use crate::error::{Result, VaporDBError};
+use crate::storage::Value;
+use crate::storage::internal_key::SequenceNumber;
+use crate::wal::group_commit::{EVERYSEC_INTERVAL, GroupCommit, SyncWorker};
 use serde::{Deserialize, Serialize};
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::path::PathBuf;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
+// A WAL file starts with this magic and a format version, followed by
+// records framed as
+//
+//   crc32c: u32 | len: u32 | type: u8 | payload: [u8; len]
+//
+// where the payload is a bincode-encoded `WalRecord` and the checksum covers
+// everything after itself. Version 2 segments hold records without a
+// timestamp and are still read as they are, and segments before version 4
+// log expiry times in epoch seconds, which are scaled to milliseconds on
+// read. Version 1 logs framed records
+// with a bare length prefix, and logs from before sequence numbers have no
+// header and hold bare `LogEntry` records; both are rewritten in the current
+// format on open. So is a log from before segments, which lives in a single
+// unnumbered file.
+const WAL_MAGIC: [u8; 8] = *b"VAPORWAL";
+const WAL_FORMAT_VERSION: u32 = 4;
+const WAL_HEADER_SIZE: usize = WAL_MAGIC.len() + 4;
+const FRAME_HEADER_SIZE: usize = 9;
+
+#[repr(u8)]
+enum RecordType {
+    // Zero is never written, so a zeroed region never passes for a record
+    Full = 1,
+}
+
+// Variants are encoded by position, so new ones only ever go at the end.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum LogEntry {
+    /// A string value. Only written by older versions, which also logged
+    /// hashes this way as JSON.
     Set(String, String),
     Del(String),
+    /// A value of any type, replayed exactly as it was written.
+    Put(String, Value),
+    /// Like `Put`, for a key that expires at the given epoch millisecond.
+    PutExpiring(String, Value, u64),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WalRecord {
+    pub seq: SequenceNumber,
+    pub timestamp: u64, // epoch milliseconds when logged, 0 if unknown
+    pub entry: LogEntry,
+}
+
+// Record layout of format versions 1 and 2
+#[derive(Deserialize)]
+struct RecordV2 {
+    seq: SequenceNumber,
+    entry: LogEntry,
+}
+
+impl From<RecordV2> for WalRecord {
+    fn from(record: RecordV2) -> Self {
+        Self {
+            seq: record.seq,
+            timestamp: 0,
+            entry: record.entry,
+        }
+    }
+}
+
+/// How replay treats a corrupt record that is followed by more of the log.
+/// A torn record at the very end is always dropped: it was never
+/// acknowledged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WalRecoveryMode {
+    /// Refuse to open the database.
+    #[default]
+    Fail,
+    /// Drop the corrupt record and keep replaying after it.
+    Skip,
+    /// Replay up to the corrupt record and discard the rest of the log.
+    Truncate,
+}
+
+/// When appended records are forced to disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// fsync before a write is acknowledged. Writers waiting at the same
+    /// time share one fsync.
+    Always,
+    /// fsync in the background once a second, so a power loss costs at most
+    /// about a second of writes. Keyspace events wait for that sync, so they
+    /// lag writes by up to a second.
+    #[default]
+    EverySec,
+    /// Leave it to the OS. Writes survive the process crashing but not the
+    /// machine.
+    Os,
+}
+
+impl FromStr for Durability {
+    type Err = VaporDBError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Ok(Self::Always),
+            "everysec" => Ok(Self::EverySec),
+            "os" => Ok(Self::Os),
+            _ => Err(VaporDBError::Internal(format!(
+                "unknown durability '{s}', expected always, everysec or os"
+            ))),
+        }
+    }
+}
+
+/// What replay threw away to get a consistent log.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RecoveryReport {
+    pub torn_tail_bytes: u64,   // incomplete record at the end of the log
+    pub skipped_records: usize, // corrupt records dropped by `Skip`
+    pub skipped_bytes: u64,
+    pub truncated_bytes: u64, // log discarded by `Truncate`
 }
 
+impl RecoveryReport {
+    pub fn is_clean(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn merge(&mut self, other: RecoveryReport) {
+        self.torn_tail_bytes += other.torn_tail_bytes;
+        self.skipped_records += other.skipped_records;
+        self.skipped_bytes += other.skipped_bytes;
+        self.truncated_bytes += other.truncated_bytes;
+    }
+}
+
+/// An append-only log split into numbered segment files named after the
+/// log's path, e.g. `vapordb.wal.000003`. A new segment is started whenever
+/// the active MemTable is frozen, so a segment can be deleted as soon as the
+/// MemTables holding its writes have been flushed.
 pub struct WriteAheadLog {
     path: PathBuf,
+    number: u64,
     writer: BufWriter<File>,
+    commit: Arc<GroupCommit>,
+    _syncer: Option<SyncWorker>,
+    clock: Arc<dyn Clock>,
 }
 
 impl WriteAheadLog {
-    pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
+    /// Replays every segment numbered `first_live` or later, oldest first,
+    /// handling corruption according to `mode`, and starts a new segment for
+    /// appends, synced according to `durability`, every `sync_interval`
+    /// under `EverySec`. Older segments only hold flushed writes and are left
+    /// for `purge_segments`.
+    pub fn open(
+        path: impl Into<PathBuf>,
+        first_live: u64,
+        mode: WalRecoveryMode,
+        durability: Durability,
+        sync_interval: Duration,
+    ) -> Result<(Self, Vec<WalRecord>, RecoveryReport)> {
         let path = path.into();
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&path)
-            .map_err(|e| VaporDBError::Internal(e.to_string()))?;
 
+        let mut records = Vec::new();
+        let mut report = RecoveryReport::default();
+        let numbers = segment_numbers(&path)?;
+        let newest = numbers.last().copied();
+        for &number in numbers.iter().filter(|&&number| number >= first_live) {
+            let segment = segment_path(&path, number);
+            let (segment, segment_report) =
+                recover_segment(&segment, mode, Some(number) == newest)?;
+            records.extend(segment);
+            report.merge(segment_report);
+        }
+
+        let number = numbers.last().map_or(first_live, |&last| last + 1).max(1);
+        let wal = Self::create(
+            &segment_path(&path, number),
+            path,
+            number,
+            durability,
+            sync_interval,
+        )?;
+        Ok((wal, records, report))
+    }
+
+    // Starts the log numbered `number` in a fresh file at `segment`.
+    fn create(
+        segment: &Path,
+        path: PathBuf,
+        number: u64,
+        durability: Durability,
+        sync_interval: Duration,
+    ) -> Result<Self> {
+        let writer = create_segment(segment)?;
+        let commit = Arc::new(GroupCommit::new(durability, writer.get_ref().try_clone()?));
+        let syncer = (durability == Durability::EverySec)
+            .then(|| SyncWorker::start(Arc::clone(&commit), sync_interval));
         Ok(Self {
             path,
-            writer: BufWriter::new(file),
+            number,
+            writer,
+            commit,
+            _syncer: syncer,
+            clock: Arc::new(SystemClock),
         })
     }
 
-    pub fn append(&mut self, entry: LogEntry) -> Result<()> {
-        let encoded =
-            bincode::serialize(&entry).map_err(|e| VaporDBError::Internal(e.to_string()))?;
+    /// Stamps records with the time from `clock` instead of the system
+    /// clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
 
-        self.writer
-            .write_all(&(encoded.len() as u32).to_le_bytes())
-            .map_err(|e| VaporDBError::Internal(e.to_string()))?;
+    /// Rewrites a log from an older format in the current one and turns it
+    /// into a segment numbered after any existing ones. Entries from before
+    /// sequence numbers are numbered from `first_seq`.
+    pub fn upgrade_legacy(path: impl AsRef<Path>, first_seq: SequenceNumber) -> Result<()> {
+        let path = path.as_ref();
+        if !path.is_file() {
+            return Ok(());
+        }
+        let records: Vec<WalRecord> = match header_version(path)? {
+            Some(2..=WAL_FORMAT_VERSION) => read_frames(path, WalRecoveryMode::Fail, true)?.records,
+            Some(1) => read_unframed::<RecordV2>(path, WAL_HEADER_SIZE)?
+                .into_iter()
+                .map(WalRecord::from)
+                .collect(),
+            Some(version) => {
+                return Err(VaporDBError::Corruption(format!(
+                    "unsupported WAL format version {version}"
+                )));
+            }
+            None => (first_seq..)
+                .zip(read_unframed::<LogEntry>(path, 0)?)
+                .map(|(seq, entry)| WalRecord {
+                    seq,
+                    timestamp: 0,
+                    entry,
+                })
+                .collect(),
+        };
+
+        let number = segment_numbers(path)?.last().map_or(1, |&last| last + 1);
+        let segment = segment_path(path, number);
+        let mut tmp_path = segment.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        {
+            let _ = fs::remove_file(&tmp_path);
+            let mut wal = Self::create(
+                &tmp_path,
+                path.to_path_buf(),
+                number,
+                Durability::Os,
+                EVERYSEC_INTERVAL,
+            )?;
+            for record in &records {
+                wal.append_record(record)?;
+            }
+            wal.writer.get_ref().sync_all()?;
+        }
+        fs::rename(&tmp_path, &segment)?;
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    /// The path segments are named after.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The segment currently taking appends.
+    pub fn number(&self) -> u64 {
+        self.number
+    }
+
+    /// Tracks which appends are on disk and lets writers wait for theirs.
+    pub fn group_commit(&self) -> Arc<GroupCommit> {
+        Arc::clone(&self.commit)
+    }
+
+    /// Closes the current segment and starts the next one, returning its
+    /// number.
+    pub fn rotate(&mut self) -> Result<u64> {
+        self.writer.flush()?;
+        // Nothing that is owed a sync may be left behind in the old segment
+        if self.commit.durability() != Durability::Os {
+            self.commit.sync()?;
+        }
+        let number = self.number + 1;
+        self.writer = create_segment(&segment_path(&self.path, number))?;
+        self.commit.switch_file(self.writer.get_ref().try_clone()?);
+        self.number = number;
+        Ok(number)
+    }
+
+    pub fn append(&mut self, seq: SequenceNumber, entry: LogEntry) -> Result<()> {
+        self.append_record(&WalRecord {
+            seq,
+            timestamp: self.clock.now_millis(),
+            entry,
+        })
+    }
+
+    /// Appends a record as it is, keeping its timestamp.
+    pub fn append_record(&mut self, record: &WalRecord) -> Result<()> {
+        let payload =
+            bincode::serialize(record).map_err(|e| VaporDBError::Internal(e.to_string()))?;
+
+        // One write per record keeps a crash from interleaving frames
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&[0; 4]);
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.push(RecordType::Full as u8);
+        frame.extend_from_slice(&payload);
+        let crc = crc32c::crc32c(&frame[4..]);
+        frame[..4].copy_from_slice(&crc.to_le_bytes());
 
         self.writer
-            .write_all(&encoded)
+            .write_all(&frame)
             .map_err(|e| VaporDBError::Internal(e.to_string()))?;
 
         self.writer
             .flush()
             .map_err(|e| VaporDBError::Internal(e.to_string()))?;
+        self.commit.written(record.seq);
 
         Ok(())
     }
+}
 
-    pub fn load_entries(&self) -> Result<Vec<LogEntry>> {
-        let mut entries = Vec::new();
+impl Drop for WriteAheadLog {
+    fn drop(&mut self) {
+        if self.commit.durability() != Durability::Os {
+            let _ = self.writer.flush();
+            if let Err(e) = self.commit.sync() {
+                eprintln!("WAL sync on close failed: {e}");
+            }
+        }
+    }
+}
+
+pub fn segment_path(path: &Path, number: u64) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{number:06}"));
+    PathBuf::from(name)
+}
+
+/// Numbers of the segments that exist for the log at `path`, oldest first.
+pub fn segment_numbers(path: &Path) -> Result<Vec<u64>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let Some(prefix) = path.file_name().and_then(|name| name.to_str()) else {
+        return Ok(Vec::new());
+    };
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
 
-        let file = File::open(&self.path).map_err(|e| VaporDBError::Internal(e.to_string()))?;
-        let mut reader = BufReader::new(file);
-        let mut len_buf = [0u8; 4];
+    let mut numbers = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let number: Option<u64> = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(prefix))
+            .and_then(|rest| rest.strip_prefix('.'))
+            .filter(|digits| !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|digits| digits.parse().ok());
+        numbers.extend(number);
+    }
+    numbers.sort_unstable();
+    Ok(numbers)
+}
 
-        while reader.read_exact(&mut len_buf).is_ok() {
-            let len = u32::from_le_bytes(len_buf) as usize;
-            let mut data = vec![0u8; len];
-            reader
-                .read_exact(&mut data)
-                .map_err(|e| VaporDBError::Internal(e.to_string()))?;
-            let entry: LogEntry =
-                bincode::deserialize(&data).map_err(|e| VaporDBError::Internal(e.to_string()))?;
-            entries.push(entry);
+/// Deletes the segments numbered below `number`, whose writes are all in
+/// SSTables.
+pub fn remove_segments_before(path: &Path, number: u64) -> Result<()> {
+    purge_segments(path, number, Duration::ZERO, None)
+}
+
+/// Like `remove_segments_before`, but keeps segments written to within
+/// `retention` so change streams can still read them, and moves segments
+/// into `archive` instead of deleting them when one is given.
+pub fn purge_segments(
+    path: &Path,
+    number: u64,
+    retention: Duration,
+    archive: Option<&Path>,
+) -> Result<()> {
+    for old in segment_numbers(path)? {
+        if old >= number {
+            continue;
+        }
+        let segment = segment_path(path, old);
+        let age = fs::metadata(&segment)?
+            .modified()?
+            .elapsed()
+            .unwrap_or_default();
+        if age < retention {
+            continue;
         }
+        match archive {
+            Some(archive) => archive_segment(&segment, archive)?,
+            None => fs::remove_file(segment)?,
+        }
+    }
+    Ok(())
+}
+
+fn archive_segment(segment: &Path, archive: &Path) -> Result<()> {
+    fs::create_dir_all(archive)?;
+    let name = segment
+        .file_name()
+        .ok_or_else(|| VaporDBError::Internal(format!("bad segment {}", segment.display())))?;
+    let archived = archive.join(name);
+    // A rename cannot cross filesystems, so fall back to copying
+    if fs::rename(segment, &archived).is_err() {
+        fs::copy(segment, &archived)?;
+        File::open(&archived)?.sync_all()?;
+        fs::remove_file(segment)?;
+    }
+    Ok(())
+}
+
+/// Every intact record in the segments of the log at `path`, oldest first.
+/// A torn tail is ignored, and so is anything after a corrupt record.
+pub fn read_log(path: &Path) -> Result<Vec<WalRecord>> {
+    let mut records = Vec::new();
+    let numbers = segment_numbers(path)?;
+    let newest = numbers.last().copied();
+    for number in numbers {
+        let segment = segment_path(path, number);
+        let replay = read_frames(&segment, WalRecoveryMode::Truncate, Some(number) == newest)?;
+        records.extend(replay.records);
+    }
+    Ok(records)
+}
 
-        Ok(entries)
+/// Reads the complete, intact records of a segment from `offset`, which must
+/// fall on a frame boundary, along with the offset just past each one. Stops
+/// at the first frame that is incomplete or does not check out, as the tail
+/// of a segment still being written may be.
+pub fn read_segment_from(path: &Path, offset: u64) -> Result<Vec<(WalRecord, u64)>> {
+    let version = frame_version(path)?;
+    let mut file = File::open(path)?;
+    let offset = offset.max(WAL_HEADER_SIZE as u64);
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while data.len() - pos >= FRAME_HEADER_SIZE {
+        let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap());
+        let end = pos + FRAME_HEADER_SIZE + len as usize;
+        let Some(record) = data
+            .get(pos..end)
+            .and_then(|frame| decode_frame(frame, version))
+        else {
+            break;
+        };
+        pos = end;
+        records.push((record, offset + pos as u64));
     }
+    Ok(records)
+}
+
+fn create_segment(path: &Path) -> Result<BufWriter<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| VaporDBError::Internal(e.to_string()))?;
+    let is_new = file.metadata()?.len() == 0;
+
+    let mut writer = BufWriter::new(file);
+    if is_new {
+        writer.write_all(&WAL_MAGIC)?;
+        writer.write_all(&WAL_FORMAT_VERSION.to_le_bytes())?;
+        writer.flush()?;
+    }
+    Ok(writer)
+}
+
+// Reads a segment back for replay. Whatever was discarded is cut off the end
+// of the file, so it is not found again next time.
+fn recover_segment(
+    path: &Path,
+    mode: WalRecoveryMode,
+    newest: bool,
+) -> Result<(Vec<WalRecord>, RecoveryReport)> {
+    let replay = read_frames(path, mode, newest)?;
+    if replay.valid_len < replay.file_len {
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(replay.valid_len)?;
+        file.sync_all()?;
+    }
+    Ok((replay.records, replay.report))
+}
+
+struct Replay {
+    records: Vec<WalRecord>,
+    report: RecoveryReport,
+    valid_len: u64,
+    file_len: u64,
+}
+
+fn header_version(path: &Path) -> Result<Option<u32>> {
+    let mut header = [0u8; WAL_HEADER_SIZE];
+    let mut file = File::open(path)?;
+    if file.read_exact(&mut header).is_err() || header[..WAL_MAGIC.len()] != WAL_MAGIC {
+        return Ok(None);
+    }
+    let version = u32::from_le_bytes(header[WAL_MAGIC.len()..].try_into().unwrap());
+    Ok(Some(version))
+}
+
+// The version of a log holding checksummed frames
+fn frame_version(path: &Path) -> Result<u32> {
+    match header_version(path)? {
+        Some(version @ 2..=WAL_FORMAT_VERSION) => Ok(version),
+        _ => Err(VaporDBError::Corruption(format!(
+            "{} is not a write-ahead log",
+            path.display()
+        ))),
+    }
+}
+
+// Reads every frame of a segment. Only the last record of the `newest`
+// segment can have been cut short by a crash; a damaged record anywhere else
+// is corruption, handled according to `mode`.
+fn read_frames(path: &Path, mode: WalRecoveryMode, newest: bool) -> Result<Replay> {
+    let version = frame_version(path)?;
+    let data = fs::read(path)?;
+
+    let mut records = Vec::new();
+    let mut report = RecoveryReport::default();
+    let mut pos = WAL_HEADER_SIZE;
+    let mut valid_len = pos;
+
+    while pos < data.len() {
+        let remaining = data.len() - pos;
+        let frame_len = (remaining >= FRAME_HEADER_SIZE).then(|| {
+            let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap());
+            FRAME_HEADER_SIZE + len as usize
+        });
+        let record = frame_len
+            .filter(|&len| len <= remaining)
+            .and_then(|len| Some((len, decode_frame(&data[pos..pos + len], version)?)));
+        if let Some((frame_len, record)) = record {
+            records.push(record);
+            pos += frame_len;
+            valid_len = pos;
+            continue;
+        }
+
+        // The record is incomplete or fails its checksum. A damaged length
+        // can point anywhere, so whether it was the last record is told by
+        // looking for an intact one after it
+        let next = next_intact_frame(&data, pos, version);
+        if newest && next.is_none() {
+            report.torn_tail_bytes = remaining as u64;
+            break;
+        }
+
+        match mode {
+            WalRecoveryMode::Fail => {
+                return Err(VaporDBError::Corruption(format!(
+                    "corrupt WAL record at offset {pos} in {}",
+                    path.display()
+                )));
+            }
+            WalRecoveryMode::Skip => {
+                let end = next.unwrap_or(data.len());
+                report.skipped_records += 1;
+                report.skipped_bytes += (end - pos) as u64;
+                pos = end;
+                valid_len = pos;
+            }
+            WalRecoveryMode::Truncate => {
+                report.truncated_bytes = remaining as u64;
+                break;
+            }
+        }
+    }
+
+    Ok(Replay {
+        records,
+        report,
+        valid_len: valid_len as u64,
+        file_len: data.len() as u64,
+    })
+}
+
+// Where the first intact frame after the damaged one at `pos` starts, if
+// there is one
+fn next_intact_frame(data: &[u8], pos: usize, version: u32) -> Option<usize> {
+    let last_start = data.len().checked_sub(FRAME_HEADER_SIZE)?;
+    (pos + 1..=last_start).find(|&start| {
+        let len = u32::from_le_bytes(data[start + 4..start + 8].try_into().unwrap());
+        data.get(start..start + FRAME_HEADER_SIZE + len as usize)
+            .and_then(|frame| decode_frame(frame, version))
+            .is_some()
+    })
+}
+
+fn decode_frame(frame: &[u8], version: u32) -> Option<WalRecord> {
+    let crc = u32::from_le_bytes(frame[..4].try_into().unwrap());
+    if crc32c::crc32c(&frame[4..]) != crc || frame[8] != RecordType::Full as u8 {
+        return None;
+    }
+    let payload = &frame[FRAME_HEADER_SIZE..];
+    let mut record: WalRecord = match version {
+        2 => bincode::deserialize::<RecordV2>(payload).ok()?.into(),
+        _ => bincode::deserialize(payload).ok()?,
+    };
+    if version < 4
+        && let LogEntry::PutExpiring(_, _, expire_at) = &mut record.entry
+    {
+        *expire_at = expire_at.saturating_mul(1000);
+    }
+    Some(record)
+}
+
+// Reads length-prefixed records without checksums, as older formats wrote
+// them. A record cut short at the end is dropped.
+fn read_unframed<T: for<'de> Deserialize<'de>>(path: &Path, skip: usize) -> Result<Vec<T>> {
+    let mut records = Vec::new();
+
+    let file = File::open(path).map_err(|e| VaporDBError::Internal(e.to_string()))?;
+    let mut reader = BufReader::new(file);
+    let mut skipped = vec![0u8; skip];
+    reader.read_exact(&mut skipped)?;
+    let mut len_buf = [0u8; 4];
+
+    while reader.read_exact(&mut len_buf).is_ok() {
+        let len = u32::from_le_bytes(len_buf) as usize;
+        let mut data = vec![0u8; len];
+        if reader.read_exact(&mut data).is_err() {
+            break;
+        }
+        let record: T =
+            bincode::deserialize(&data).map_err(|e| VaporDBError::Corruption(e.to_string()))?;
+        records.push(record);
+    }
+
+    Ok(records)
 }
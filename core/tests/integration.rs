@@ -0,0 +1,2449 @@
+use core::backup::RecoveryTarget;
+use core::clock::{Clock, MockClock, SystemClock};
+use core::command::{Command, ExpireCondition, ZAddFlags, ZRange};
+use core::db::VaporDB;
+use core::error::VaporDBError;
+use core::events::{EventClass, EventKind, KeyEvent};
+use core::options::VaporDBOptions;
+use core::snapshot::Snapshot;
+use core::storage::bloom::FilterStats;
+use core::storage::compaction::CompactionStyle;
+use core::storage::memtable::MemTable;
+use core::storage::sorted_set::{LexBound, RangeBy, ScoreBound, SortedSet};
+use core::storage::sst::{SSTable, SSTableEntry, SSTableOptions, SSTableWriter};
+use core::storage::stream::{StreamEntry, StreamId, parse_range_end, parse_range_start};
+use core::storage::{Storage, Value};
+use core::ttl::ExpirationTable;
+use core::wal::wal::{self, Durability, LogEntry, WalRecoveryMode};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Barrier, Mutex};
+use std::time::Duration;
+
+fn open_db(dir: &Path, flush_threshold: usize) -> VaporDB {
+    let options = VaporDBOptions {
+        sst_dir: dir.join("sstables"),
+        flush_threshold,
+        ..Default::default()
+    };
+    VaporDB::open(dir.join("db.wal").to_str().unwrap(), options).unwrap()
+}
+
+// Like `open_db`, but time only moves when `clock` is advanced
+fn open_db_with_clock(dir: &Path, flush_threshold: usize, clock: &Arc<MockClock>) -> VaporDB {
+    let options = VaporDBOptions {
+        sst_dir: dir.join("sstables"),
+        flush_threshold,
+        clock: clock.clone(),
+        ..Default::default()
+    };
+    VaporDB::open(dir.join("db.wal").to_str().unwrap(), options).unwrap()
+}
+
+fn level_sizes(db: &VaporDB) -> Vec<usize> {
+    let tables = db.tables();
+    let tables = tables.read();
+    tables
+        .manifest()
+        .version()
+        .levels
+        .iter()
+        .map(|level| level.len())
+        .collect()
+}
+
+fn remove_wal(dir: &Path) {
+    let wal_path = dir.join("db.wal");
+    for number in wal::segment_numbers(&wal_path).unwrap() {
+        std::fs::remove_file(wal::segment_path(&wal_path, number)).unwrap();
+    }
+}
+
+fn string_entry(key: &str, value: &str) -> SSTableEntry {
+    SSTableEntry {
+        key: key.into(),
+        seq: 0,
+        value: Some(Value::String(value.into())),
+        ttl: None,
+    }
+}
+
+fn scan_keys(scan: impl Iterator<Item = core::error::Result<(String, Value)>>) -> Vec<String> {
+    scan.map(|entry| entry.unwrap().0).collect()
+}
+
+#[test]
+fn test_sstable_point_lookups_across_blocks() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("table.sst");
+
+    let mut writer = SSTableWriter::new(&path, SSTableOptions::default()).unwrap();
+    for i in 0..5000 {
+        writer
+            .add(&string_entry(&format!("key{i:05}"), &format!("value{i}")))
+            .unwrap();
+    }
+    writer.finish().unwrap();
+
+    let sst = SSTable::open(&path).unwrap();
+    assert_eq!(sst.size(), 5000);
+
+    for i in [0, 1, 2499, 4999] {
+        let value = sst.get(&format!("key{i:05}")).unwrap();
+        assert!(matches!(value, Some(Value::String(v)) if v == format!("value{i}")));
+    }
+    assert!(sst.get("key99999").unwrap().is_none());
+    assert!(sst.get("aaa").unwrap().is_none());
+
+    let keys: Vec<String> = sst.iter().map(|e| e.unwrap().key).collect();
+    let mut sorted = keys.clone();
+    sorted.sort();
+    assert_eq!(keys, sorted);
+    assert_eq!(keys.len(), 5000);
+}
+
+#[test]
+fn test_sstable_rejects_out_of_order_keys_and_bad_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("table.sst");
+
+    let mut writer = SSTableWriter::new(&path, SSTableOptions::default()).unwrap();
+    writer.add(&string_entry("b", "1")).unwrap();
+    assert!(writer.add(&string_entry("a", "2")).is_err());
+
+    let bogus = dir.path().join("bogus.sst");
+    std::fs::write(&bogus, "stress_key_1\t{\"String\":\"v\"}\n".repeat(4)).unwrap();
+    assert!(SSTable::open(&bogus).is_err());
+}
+
+#[test]
+fn test_sstable_merge_prefers_newer_input() {
+    let dir = tempfile::tempdir().unwrap();
+    let old_path = dir.path().join("old.sst");
+    let new_path = dir.path().join("new.sst");
+    let out_path = dir.path().join("merged.sst");
+
+    let mut old = HashMap::new();
+    old.insert("a".to_string(), Some(Value::String("old".into())));
+    old.insert("b".to_string(), Some(Value::String("old".into())));
+    SSTable::write(
+        &old_path,
+        &old,
+        &HashMap::new(),
+        0,
+        SSTableOptions::default(),
+    )
+    .unwrap();
+
+    let mut new = HashMap::new();
+    new.insert("b".to_string(), Some(Value::String("new".into())));
+    new.insert("c".to_string(), None);
+    SSTable::write(
+        &new_path,
+        &new,
+        &HashMap::new(),
+        0,
+        SSTableOptions::default(),
+    )
+    .unwrap();
+
+    let old = SSTable::open(&old_path).unwrap();
+    let new = SSTable::open(&new_path).unwrap();
+    SSTable::merge(&[&old, &new], &out_path, SSTableOptions::default()).unwrap();
+
+    let merged = SSTable::open(&out_path).unwrap();
+    assert_eq!(merged.size(), 3);
+    assert!(matches!(merged.get("b").unwrap(), Some(Value::String(v)) if v == "new"));
+    assert!(merged.get_entry("c").unwrap().unwrap().value.is_none());
+}
+
+#[test]
+fn test_memtable_flush_carries_tombstones_and_ttls() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("flush.sst");
+
+    let ttl = Arc::new(ExpirationTable::new());
+    let memtable = MemTable::with_expiration_table(Arc::clone(&ttl));
+    memtable
+        .set("live".into(), Value::String("v".into()))
+        .unwrap();
+    memtable.insert(
+        10,
+        "expiring".into(),
+        Some(Value::String("v".into())),
+        Some(ttl.expire_at(Duration::from_secs(60))),
+    );
+    memtable.del("deleted").unwrap();
+
+    memtable
+        .flush_to_sstable(&path, SSTableOptions::default(), &[])
+        .unwrap();
+
+    let sst = SSTable::open(&path).unwrap();
+    assert_eq!(sst.size(), 3);
+    assert!(sst.get_entry("deleted").unwrap().unwrap().value.is_none());
+    assert!(sst.get_entry("expiring").unwrap().unwrap().ttl.is_some());
+    assert!(matches!(sst.get("live").unwrap(), Some(Value::String(v)) if v == "v"));
+}
+
+#[test]
+fn test_legacy_sstable_is_rewritten_in_block_format() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("1750085256.sst");
+    std::fs::write(
+        &path,
+        "b\t{\"String\":\"old\"}\n\
+         a\t{\"List\":[\"x\",\"y\"]}\n\
+         b\t{\"String\":\"new\"}\n\
+         this line is garbage\n",
+    )
+    .unwrap();
+
+    assert!(SSTable::open(&path).is_err());
+    assert!(SSTable::is_legacy(&path).unwrap());
+
+    let sst =
+        SSTable::recover_legacy(&path, SSTableOptions::default(), Arc::new(SystemClock)).unwrap();
+    assert_eq!(sst.size(), 2);
+    assert!(matches!(sst.get("b").unwrap(), Some(Value::String(v)) if v == "new"));
+    assert!(matches!(sst.get("a").unwrap(), Some(Value::List(l)) if l == ["x", "y"]));
+
+    // The file on disk is now in the new format
+    assert!(!SSTable::is_legacy(&path).unwrap());
+    assert_eq!(SSTable::open(&path).unwrap().size(), 2);
+}
+
+#[test]
+fn test_bloom_filter_skips_absent_keys() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("filtered.sst");
+    let stats = Arc::new(FilterStats::default());
+
+    let mut writer = SSTableWriter::new(&path, SSTableOptions::default()).unwrap();
+    for i in 0..1000 {
+        writer
+            .add(&string_entry(&format!("key{i:04}"), "v"))
+            .unwrap();
+    }
+    writer.finish().unwrap();
+
+    let sst = SSTable::open(&path)
+        .unwrap()
+        .with_filter_stats(Arc::clone(&stats));
+    assert!(sst.has_filter());
+
+    // No false negatives
+    for i in 0..1000 {
+        assert!(sst.get_entry(&format!("key{i:04}")).unwrap().is_some());
+    }
+    assert_eq!(stats.misses(), 1000);
+    assert_eq!(stats.false_positives(), 0);
+
+    for i in 0..1000 {
+        assert!(sst.get_entry(&format!("absent{i}")).unwrap().is_none());
+    }
+    assert!(stats.hits() > 950, "only {} lookups filtered", stats.hits());
+    assert!(stats.false_positive_rate() < 0.05);
+
+    // Filters can be switched off
+    let unfiltered = dir.path().join("unfiltered.sst");
+    let mut writer = SSTableWriter::new(
+        &unfiltered,
+        SSTableOptions {
+            bloom_bits_per_key: 0,
+        },
+    )
+    .unwrap();
+    writer.add(&string_entry("a", "v")).unwrap();
+    writer.finish().unwrap();
+    assert!(!SSTable::open(&unfiltered).unwrap().has_filter());
+}
+
+#[test]
+fn test_manifest_tracks_tables_across_reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let sst_dir = dir.path().join("sstables");
+
+    // A table from before the manifest existed is adopted on first open
+    std::fs::create_dir_all(&sst_dir).unwrap();
+    std::fs::write(
+        sst_dir.join("1750085256.sst"),
+        "legacy\t{\"String\":\"old\"}\n",
+    )
+    .unwrap();
+
+    {
+        let mut db = open_db(dir.path(), 2);
+        assert_eq!(level_sizes(&db)[0], 1);
+        for i in 0..4 {
+            db.execute(Command::Set(format!("k{i}"), format!("v{i}")))
+                .unwrap();
+        }
+        db.flush().unwrap();
+        assert_eq!(level_sizes(&db)[0], 3);
+    }
+    remove_wal(dir.path());
+
+    // Leftovers from a crash mid-flush are not part of the database
+    std::fs::write(sst_dir.join("000099.sst"), "orphan").unwrap();
+    std::fs::write(sst_dir.join("000100.sst.tmp"), "partial").unwrap();
+
+    let mut db = open_db(dir.path(), 1000);
+    assert_eq!(level_sizes(&db)[0], 3);
+    assert!(!sst_dir.join("000099.sst").exists());
+    assert!(!sst_dir.join("000100.sst.tmp").exists());
+    assert!(!sst_dir.join("1750085256.sst").exists());
+
+    assert_eq!(
+        db.execute(Command::Get("legacy".into())).unwrap(),
+        Some("old".into())
+    );
+    for i in 0..4 {
+        assert_eq!(
+            db.execute(Command::Get(format!("k{i}"))).unwrap(),
+            Some(format!("v{i}"))
+        );
+    }
+}
+
+#[test]
+fn test_leveled_compaction_keeps_levels_sorted_and_drops_tombstones() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        flush_threshold: 50,
+        level0_compaction_trigger: 2,
+        level_base_bytes: 8 * 1024,
+        target_file_size: 4 * 1024,
+        ..Default::default()
+    };
+    let mut db = VaporDB::open(dir.path().join("db.wal").to_str().unwrap(), options).unwrap();
+
+    for i in 0..2000 {
+        db.execute(Command::Set(format!("key{:05}", i % 700), format!("v{i}")))
+            .unwrap();
+    }
+    for i in 0..100 {
+        db.execute(Command::Del(format!("key{i:05}"))).unwrap();
+    }
+    // Push the deletes out of the MemTable
+    for i in 0..50 {
+        db.execute(Command::Set(format!("pad{i:02}"), "x".into()))
+            .unwrap();
+    }
+    db.compact().unwrap();
+
+    let sizes = level_sizes(&db);
+    assert!(sizes[0] < 2, "level 0 not compacted: {sizes:?}");
+    assert!(sizes[2] > 0, "nothing reached level 2: {sizes:?}");
+
+    {
+        let tables = db.tables();
+        let tables = tables.read();
+        let version = tables.manifest().version();
+        for level in version.levels.iter().skip(1) {
+            for pair in level.windows(2) {
+                assert!(pair[0].largest_key < pair[1].smallest_key);
+            }
+        }
+
+        // Only live tables are left on disk, and the deletes were applied
+        // all the way down
+        let on_disk = std::fs::read_dir(dir.path().join("sstables"))
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension().unwrap_or_default() == "sst")
+            .count();
+        assert_eq!(on_disk, version.files().count());
+        let bottom = version.levels.iter().rposition(|l| !l.is_empty()).unwrap();
+        for file in &version.levels[bottom] {
+            let sst = tables.table(file.number).unwrap();
+            assert!(sst.iter().all(|e| e.unwrap().value.is_some()));
+        }
+    }
+
+    for i in 0..700 {
+        let expected = if i < 100 {
+            None
+        } else {
+            let last = (0..2000).filter(|n| n % 700 == i).max().unwrap();
+            Some(format!("v{last}"))
+        };
+        assert_eq!(
+            db.execute(Command::Get(format!("key{i:05}"))).unwrap(),
+            expected
+        );
+    }
+}
+
+#[test]
+fn test_size_tiered_compaction_merges_similar_runs_in_level_0() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        flush_threshold: 20,
+        compaction_style: CompactionStyle::SizeTiered,
+        size_tiered_min_merge_width: 3,
+        ..Default::default()
+    };
+    let mut db = VaporDB::open(dir.path().join("db.wal").to_str().unwrap(), options).unwrap();
+
+    // Every round rewrites the same keys, so newer runs must keep winning
+    for round in 0..9 {
+        for i in 0..20 {
+            db.execute(Command::Set(format!("key{i:02}"), format!("r{round}")))
+                .unwrap();
+        }
+    }
+    db.compact().unwrap();
+
+    let sizes = level_sizes(&db);
+    assert!(sizes[0] < 3, "runs not merged: {sizes:?}");
+    assert!(sizes[1..].iter().all(|&n| n == 0));
+    for i in 0..20 {
+        assert_eq!(
+            db.execute(Command::Get(format!("key{i:02}"))).unwrap(),
+            Some("r8".into())
+        );
+    }
+}
+
+#[test]
+fn test_fifo_compaction_drops_oldest_files_over_budget() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        flush_threshold: 20,
+        compaction_style: CompactionStyle::Fifo,
+        fifo_max_bytes: 1,
+        ..Default::default()
+    };
+    let mut db = VaporDB::open(dir.path().join("db.wal").to_str().unwrap(), options).unwrap();
+
+    for i in 0..100 {
+        db.execute(Command::Set(format!("key{i:03}"), "v".into()))
+            .unwrap();
+    }
+    db.compact().unwrap();
+
+    // Everything flushed is over budget and gone; nothing was merged
+    assert_eq!(level_sizes(&db).iter().sum::<usize>(), 0);
+    assert_eq!(db.execute(Command::Get("key000".into())).unwrap(), None);
+}
+
+#[test]
+fn test_merge_keeps_highest_sequence_number() {
+    let dir = tempfile::tempdir().unwrap();
+    let versioned = |key: &str, seq: u64, value: Option<&str>| SSTableEntry {
+        key: key.into(),
+        seq,
+        value: value.map(|v| Value::String(v.into())),
+        ttl: None,
+    };
+
+    // Several versions of one key live side by side, newest first
+    let a_path = dir.path().join("a.sst");
+    let mut writer = SSTableWriter::new(&a_path, SSTableOptions::default()).unwrap();
+    writer.add(&versioned("k", 9, Some("nine"))).unwrap();
+    writer.add(&versioned("k", 4, Some("four"))).unwrap();
+    assert!(writer.add(&versioned("k", 5, Some("five"))).is_err());
+    writer.add(&versioned("x", 1, None)).unwrap();
+    writer.finish().unwrap();
+
+    let b_path = dir.path().join("b.sst");
+    let mut writer = SSTableWriter::new(&b_path, SSTableOptions::default()).unwrap();
+    writer.add(&versioned("k", 6, Some("six"))).unwrap();
+    writer.add(&versioned("x", 2, Some("two"))).unwrap();
+    writer.finish().unwrap();
+
+    let a = SSTable::open(&a_path).unwrap();
+    let b = SSTable::open(&b_path).unwrap();
+    assert_eq!(a.get_entry("k").unwrap().unwrap().seq, 9);
+    assert_eq!(a.get_entry_at("k", 8).unwrap().unwrap().seq, 4);
+    assert!(a.get_entry_at("k", 3).unwrap().is_none());
+
+    // The newer version wins whatever the order of the inputs
+    let out_path = dir.path().join("merged.sst");
+    SSTable::merge(&[&a, &b], &out_path, SSTableOptions::default()).unwrap();
+    let merged = SSTable::open(&out_path).unwrap();
+    assert_eq!(merged.size(), 2);
+    assert!(matches!(merged.get("k").unwrap(), Some(Value::String(v)) if v == "nine"));
+    assert!(matches!(merged.get("x").unwrap(), Some(Value::String(v)) if v == "two"));
+}
+
+#[test]
+fn test_writes_are_stamped_with_sequence_numbers_across_reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let wal_path = dir.path().join("db.wal");
+
+    // A log from before sequence numbers: bare length-prefixed entries
+    let mut legacy = Vec::new();
+    for entry in [
+        LogEntry::Set("a".into(), "1".into()),
+        LogEntry::Set("a".into(), "2".into()),
+    ] {
+        let encoded = bincode::serialize(&entry).unwrap();
+        legacy.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+        legacy.extend_from_slice(&encoded);
+    }
+    std::fs::write(&wal_path, legacy).unwrap();
+
+    let last = {
+        let mut db = open_db(dir.path(), 3);
+        assert_eq!(db.last_sequence(), 2);
+        assert_eq!(
+            db.execute(Command::Get("a".into())).unwrap(),
+            Some("2".into())
+        );
+        db.execute(Command::Set("a".into(), "3".into())).unwrap();
+        db.execute(Command::Set("b".into(), "1".into())).unwrap();
+        db.last_sequence()
+    };
+    assert_eq!(last, 4);
+
+    let mut db = open_db(dir.path(), 3);
+    assert_eq!(db.last_sequence(), last);
+    assert_eq!(
+        db.execute(Command::Get("a".into())).unwrap(),
+        Some("3".into())
+    );
+    db.execute(Command::Del("a".into())).unwrap();
+    assert_eq!(db.last_sequence(), last + 1);
+    assert_eq!(db.execute(Command::Get("a".into())).unwrap(), None);
+}
+
+#[test]
+fn test_snapshot_reads_survive_writes_flushes_and_compactions() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        flush_threshold: 25,
+        level0_compaction_trigger: 2,
+        ..Default::default()
+    };
+    let mut db = VaporDB::open(dir.path().join("db.wal").to_str().unwrap(), options).unwrap();
+
+    for i in 0..50 {
+        db.execute(Command::Set(format!("key{i:02}"), "old".into()))
+            .unwrap();
+    }
+    let snapshot = db.snapshot();
+
+    for i in 0..50 {
+        if i % 2 == 0 {
+            db.execute(Command::Set(format!("key{i:02}"), "new".into()))
+                .unwrap();
+        } else {
+            db.execute(Command::Del(format!("key{i:02}"))).unwrap();
+        }
+    }
+    db.execute(Command::Set("added".into(), "new".into()))
+        .unwrap();
+    db.compact().unwrap();
+    assert!(level_sizes(&db)[1..].iter().sum::<usize>() > 0);
+
+    for i in 0..50 {
+        let key = format!("key{i:02}");
+        assert!(matches!(snapshot.get(&key).unwrap(), Some(Value::String(v)) if v == "old"));
+        let current = db.execute(Command::Get(key)).unwrap();
+        assert_eq!(current, (i % 2 == 0).then(|| "new".to_string()));
+    }
+    assert!(snapshot.get("added").unwrap().is_none());
+
+    // Once the snapshot is gone, compaction is free to drop what it pinned
+    drop(snapshot);
+    for i in 0..50 {
+        db.execute(Command::Set(format!("key{i:02}"), "newer".into()))
+            .unwrap();
+    }
+    db.compact().unwrap();
+
+    let tables = db.tables();
+    let tables = tables.read();
+    for file in tables.manifest().version().files() {
+        let sst = tables.table(file.number).unwrap();
+        assert!(sst.iter().all(|e| {
+            let e = e.unwrap();
+            !matches!(e.value, Some(Value::String(v)) if v == "old")
+        }));
+    }
+}
+
+#[test]
+fn test_snapshots_keep_the_expiry_each_version_was_written_with() {
+    let dir = tempfile::tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        clock: clock.clone(),
+        ..Default::default()
+    };
+    let mut db = VaporDB::open(dir.path().join("db.wal").to_str().unwrap(), options).unwrap();
+
+    db.execute(Command::Set("forever".into(), "v".into()))
+        .unwrap();
+    db.execute(Command::PSetEx("brief".into(), 1_000, "v".into()))
+        .unwrap();
+    let snapshot = db.snapshot();
+
+    // Changing the expiries afterwards makes new versions
+    db.execute(Command::PExpire("forever".into(), 500, None))
+        .unwrap();
+    db.execute(Command::Persist("brief".into())).unwrap();
+    clock.advance(Duration::from_millis(2_000));
+
+    let check = |db: &mut VaporDB, snapshot: &Snapshot| {
+        assert!(snapshot.get("forever").unwrap().is_some());
+        assert!(snapshot.get("brief").unwrap().is_none());
+        assert_eq!(db.execute(Command::Get("forever".into())).unwrap(), None);
+        assert_eq!(
+            db.execute(Command::Get("brief".into())).unwrap(),
+            Some("v".into())
+        );
+    };
+    check(&mut db, &snapshot);
+
+    // The versions the snapshot pins reach the SSTable with their own TTLs
+    db.flush().unwrap();
+    check(&mut db, &snapshot);
+    let tables = db.tables();
+    let ttls: Vec<(String, Option<u64>)> = {
+        let tables = tables.read();
+        let mut ttls = Vec::new();
+        for file in tables.manifest().version().files() {
+            for entry in tables.table(file.number).unwrap().iter() {
+                let entry = entry.unwrap();
+                if entry.value.is_some() {
+                    ttls.push((entry.key, entry.ttl));
+                }
+            }
+        }
+        ttls
+    };
+    assert!(ttls.contains(&("forever".to_string(), None)));
+    assert!(ttls.contains(&("brief".to_string(), None)));
+    assert!(
+        !ttls
+            .iter()
+            .any(|(key, ttl)| key == "forever" && ttl.is_some())
+    );
+}
+
+#[test]
+fn test_snapshot_scans_find_versions_past_a_block_boundary() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        ..Default::default()
+    };
+    let mut db = VaporDB::open(dir.path().join("db.wal").to_str().unwrap(), options).unwrap();
+
+    // Sized so that a block ends between the two versions of "b": the first
+    // block is "a" and the new "b", and the pinned old "b" opens the next one
+    let filler = "x".repeat(2_000);
+    db.execute(Command::Set("a".into(), filler.clone()))
+        .unwrap();
+    db.execute(Command::Set("b".into(), "old".into())).unwrap();
+    let snapshot = db.snapshot();
+    db.execute(Command::Set("b".into(), filler.repeat(2)))
+        .unwrap();
+    db.execute(Command::Set("c".into(), "v".into())).unwrap();
+    db.flush().unwrap();
+
+    let values: Vec<(String, Value)> = snapshot
+        .scan("a".to_string()..="b".to_string())
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(values.len(), 2);
+    assert!(matches!(&values[1], (k, Value::String(v)) if k == "b" && v == "old"));
+    let reversed: Vec<String> = scan_keys(snapshot.scan("a".to_string()..="b".to_string()).rev());
+    assert_eq!(reversed, ["b", "a"]);
+}
+
+#[test]
+fn test_scans_merge_memtable_and_sstables_in_key_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        flush_threshold: 10,
+        clock: clock.clone(),
+        ..Default::default()
+    };
+    let mut db = VaporDB::open(dir.path().join("db.wal").to_str().unwrap(), options).unwrap();
+
+    for i in 0..30 {
+        db.execute(Command::Set(format!("user:{i:02}"), format!("v{i}")))
+            .unwrap();
+        db.execute(Command::Set(format!("item:{i:02}"), format!("v{i}")))
+            .unwrap();
+    }
+    for i in (0..30).step_by(3) {
+        db.execute(Command::Del(format!("user:{i:02}"))).unwrap();
+    }
+    db.execute(Command::Set("user:04".into(), "rewritten".into()))
+        .unwrap();
+    db.execute(Command::PSetEx("user:05".into(), 1, "expiring".into()))
+        .unwrap();
+    clock.advance(Duration::from_millis(1));
+    db.compact().unwrap();
+    assert!(level_sizes(&db).iter().sum::<usize>() > 0);
+
+    let snapshot = db.snapshot();
+    db.execute(Command::Set("user:07".into(), "after".into()))
+        .unwrap();
+    db.execute(Command::Del("user:08".into())).unwrap();
+
+    let expected: Vec<String> = (0..30)
+        .filter(|i| i % 3 != 0 && *i != 5)
+        .map(|i| format!("user:{i:02}"))
+        .collect();
+    let live: Vec<String> = expected
+        .iter()
+        .filter(|k| *k != "user:08")
+        .cloned()
+        .collect();
+    assert_eq!(scan_keys(db.scan_prefix("user:")), live);
+    let mut reversed = live.clone();
+    reversed.reverse();
+    assert_eq!(scan_keys(db.scan_prefix("user:").rev()), reversed);
+
+    let values: Vec<(String, Value)> = db
+        .scan("user:04".to_string().."user:08".to_string())
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(values.len(), 2);
+    assert!(matches!(&values[0], (k, Value::String(v)) if k == "user:04" && v == "rewritten"));
+    assert!(matches!(&values[1], (k, Value::String(v)) if k == "user:07" && v == "after"));
+
+    // Both ends of one scan meet in the middle without repeating a key
+    let mut scan = db.scan(..);
+    let first = scan.next().unwrap().unwrap().0;
+    let last = scan.next_back().unwrap().unwrap().0;
+    assert_eq!((first.as_str(), last.as_str()), ("item:00", "user:29"));
+    assert_eq!(scan.count(), 30 + live.len() - 2);
+
+    // The snapshot sees neither the later write nor the later delete
+    assert_eq!(scan_keys(snapshot.scan_prefix("user:")), expected);
+    let value = snapshot.scan_prefix("user:07").next().unwrap().unwrap().1;
+    assert!(matches!(value, Value::String(v) if v == "v7"));
+}
+
+#[test]
+fn test_full_memtables_are_frozen_and_flushed_in_the_background() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        flush_threshold: 10,
+        max_immutable_memtables: 1,
+        ..Default::default()
+    };
+    let mut db = VaporDB::open(dir.path().join("db.wal").to_str().unwrap(), options).unwrap();
+
+    for i in 0..200 {
+        db.execute(Command::Set(format!("key{i:03}"), format!("v{i}")))
+            .unwrap();
+        // Writers stall rather than let frozen MemTables pile up
+        let memtables = db.memtables();
+        let memtables = memtables.read();
+        assert!(memtables.immutable_count() <= 1);
+        assert!(memtables.active().len() < 10);
+    }
+
+    // Every key is readable wherever it currently lives
+    for i in 0..200 {
+        assert_eq!(
+            db.execute(Command::Get(format!("key{i:03}"))).unwrap(),
+            Some(format!("v{i}"))
+        );
+    }
+
+    db.flush().unwrap();
+    {
+        let memtables = db.memtables();
+        let memtables = memtables.read();
+        assert_eq!(memtables.immutable_count(), 0);
+        assert!(memtables.active().is_empty());
+    }
+    assert_eq!(scan_keys(db.scan(..)).len(), 200);
+}
+
+#[test]
+fn test_wal_replay_restores_every_data_type() {
+    let dir = tempfile::tempdir().unwrap();
+    {
+        let mut db = open_db(dir.path(), 1000);
+        db.execute(Command::Set("string".into(), "s".into()))
+            .unwrap();
+        db.execute(Command::HSet("hash".into(), "f1".into(), "a".into()))
+            .unwrap();
+        db.execute(Command::HSet("hash".into(), "f2".into(), "b".into()))
+            .unwrap();
+        db.execute(Command::HDel("hash".into(), "f1".into()))
+            .unwrap();
+        for value in ["x", "y", "z"] {
+            db.execute(Command::RPush("list".into(), value.into()))
+                .unwrap();
+        }
+        db.execute(Command::LPush("list".into(), "w".into()))
+            .unwrap();
+        db.execute(Command::LPop("list".into())).unwrap();
+        db.execute(Command::RPop("list".into())).unwrap();
+        for value in ["m1", "m2", "m3"] {
+            db.execute(Command::SAdd("set".into(), value.into()))
+                .unwrap();
+        }
+        db.execute(Command::SRem("set".into(), "m2".into()))
+            .unwrap();
+        db.execute(Command::SAdd("gone".into(), "only".into()))
+            .unwrap();
+        db.execute(Command::SRem("gone".into(), "only".into()))
+            .unwrap();
+    }
+
+    let mut db = open_db(dir.path(), 1000);
+    assert_eq!(
+        db.execute(Command::Get("string".into())).unwrap(),
+        Some("s".into())
+    );
+    assert_eq!(
+        db.execute(Command::HGet("hash".into(), "f2".into()))
+            .unwrap(),
+        Some("b".into())
+    );
+    assert_eq!(
+        db.execute(Command::HGet("hash".into(), "f1".into()))
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        db.execute(Command::LRange("list".into(), 0, 10)).unwrap(),
+        Some(r#"["x","y"]"#.into())
+    );
+
+    let snapshot = db.snapshot();
+    let Some(Value::Set(set)) = snapshot.get("set").unwrap() else {
+        panic!("set not restored");
+    };
+    assert_eq!(set.len(), 2);
+    assert!(set.contains("m1") && set.contains("m3"));
+    assert!(snapshot.get("gone").unwrap().is_none());
+}
+
+fn open_with_recovery(dir: &Path, mode: WalRecoveryMode) -> core::error::Result<VaporDB> {
+    let options = VaporDBOptions {
+        sst_dir: dir.join("sstables"),
+        wal_recovery_mode: mode,
+        ..Default::default()
+    };
+    VaporDB::open(dir.join("db.wal").to_str().unwrap(), options)
+}
+
+#[test]
+fn test_wal_recovery_drops_torn_tail_and_handles_corruption_by_mode() {
+    let dir = tempfile::tempdir().unwrap();
+    let segment = wal::segment_path(&dir.path().join("db.wal"), 1);
+    {
+        let mut db = open_db(dir.path(), 1000);
+        for i in 0..10 {
+            db.execute(Command::Set(format!("k{i}"), format!("v{i}")))
+                .unwrap();
+        }
+    }
+    let intact = std::fs::read(&segment).unwrap();
+    let reset_wal = |data: &[u8]| {
+        remove_wal(dir.path());
+        std::fs::write(&segment, data).unwrap();
+    };
+
+    // A crash mid-append leaves part of the last record behind
+    reset_wal(&intact[..intact.len() - 3]);
+    {
+        let mut db = open_db(dir.path(), 1000);
+        assert!(db.wal_recovery_report().torn_tail_bytes > 0);
+        assert_eq!(
+            db.execute(Command::Get("k8".into())).unwrap(),
+            Some("v8".into())
+        );
+        assert_eq!(db.execute(Command::Get("k9".into())).unwrap(), None);
+        db.execute(Command::Set("k9".into(), "again".into()))
+            .unwrap();
+    }
+    {
+        let mut db = open_db(dir.path(), 1000);
+        assert!(db.wal_recovery_report().is_clean());
+        assert_eq!(
+            db.execute(Command::Get("k9".into())).unwrap(),
+            Some("again".into())
+        );
+    }
+
+    // Flip a bit inside the fourth record, which is followed by more log
+    let mut frames = Vec::new();
+    let mut pos = 12;
+    while pos < intact.len() {
+        let len = u32::from_le_bytes(intact[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        frames.push(pos);
+        pos += 9 + len;
+    }
+    let mut corrupt = intact.clone();
+    corrupt[frames[3] + 12] ^= 0x01;
+
+    reset_wal(&corrupt);
+    assert!(open_with_recovery(dir.path(), WalRecoveryMode::Fail).is_err());
+
+    let mut db = open_with_recovery(dir.path(), WalRecoveryMode::Skip).unwrap();
+    assert_eq!(db.wal_recovery_report().skipped_records, 1);
+    assert_eq!(db.execute(Command::Get("k3".into())).unwrap(), None);
+    assert_eq!(
+        db.execute(Command::Get("k9".into())).unwrap(),
+        Some("v9".into())
+    );
+    drop(db);
+
+    reset_wal(&corrupt);
+    let mut db = open_with_recovery(dir.path(), WalRecoveryMode::Truncate).unwrap();
+    assert_eq!(
+        db.wal_recovery_report().truncated_bytes,
+        (intact.len() - frames[3]) as u64
+    );
+    assert_eq!(
+        db.execute(Command::Get("k2".into())).unwrap(),
+        Some("v2".into())
+    );
+    assert_eq!(db.execute(Command::Get("k3".into())).unwrap(), None);
+    assert_eq!(db.execute(Command::Get("k9".into())).unwrap(), None);
+    drop(db);
+
+    // What was discarded is gone from the file too
+    let db = open_with_recovery(dir.path(), WalRecoveryMode::Fail).unwrap();
+    assert!(db.wal_recovery_report().is_clean());
+}
+
+#[test]
+fn test_wal_corrupt_length_mid_segment_is_not_mistaken_for_a_torn_tail() {
+    let dir = tempfile::tempdir().unwrap();
+    let segment = wal::segment_path(&dir.path().join("db.wal"), 1);
+    {
+        let mut db = open_db(dir.path(), 1000);
+        for i in 0..10 {
+            db.execute(Command::Set(format!("k{i}"), format!("v{i}")))
+                .unwrap();
+        }
+    }
+    let intact = std::fs::read(&segment).unwrap();
+    let mut frames = Vec::new();
+    let mut pos = 12;
+    while pos < intact.len() {
+        let len = u32::from_le_bytes(intact[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        frames.push(pos);
+        pos += 9 + len;
+    }
+
+    // The fourth record's length now points past the end of the file
+    let mut corrupt = intact.clone();
+    corrupt[frames[3] + 4..frames[3] + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+    remove_wal(dir.path());
+    std::fs::write(&segment, &corrupt).unwrap();
+
+    // Fail refuses to open, and leaves the records after it in place
+    assert!(open_with_recovery(dir.path(), WalRecoveryMode::Fail).is_err());
+    assert_eq!(std::fs::read(&segment).unwrap(), corrupt);
+
+    // Skip drops only the damaged record
+    let mut db = open_with_recovery(dir.path(), WalRecoveryMode::Skip).unwrap();
+    let report = db.wal_recovery_report();
+    assert_eq!(report.skipped_records, 1);
+    assert_eq!(report.torn_tail_bytes, 0);
+    assert_eq!(db.execute(Command::Get("k3".into())).unwrap(), None);
+    for i in [2, 4, 9] {
+        assert_eq!(
+            db.execute(Command::Get(format!("k{i}"))).unwrap(),
+            Some(format!("v{i}"))
+        );
+    }
+}
+
+#[test]
+fn test_flushed_wal_segments_are_deleted_and_not_replayed() {
+    let dir = tempfile::tempdir().unwrap();
+    let wal_path = dir.path().join("db.wal");
+    {
+        let mut db = open_db(dir.path(), 10);
+        for i in 0..25 {
+            db.execute(Command::Set(format!("key{i:02}"), format!("v{i}")))
+                .unwrap();
+        }
+        db.compact().unwrap();
+
+        // Two MemTables were frozen and flushed; only the live segment is left
+        assert_eq!(wal::segment_numbers(&wal_path).unwrap(), vec![3]);
+    }
+
+    let mut db = open_db(dir.path(), 10);
+    assert_eq!(db.memtable().len(), 5);
+    assert_eq!(wal::segment_numbers(&wal_path).unwrap(), vec![3, 4]);
+    for i in 0..25 {
+        assert_eq!(
+            db.execute(Command::Get(format!("key{i:02}"))).unwrap(),
+            Some(format!("v{i}"))
+        );
+    }
+}
+
+fn open_with_durability(dir: &Path, durability: Durability) -> VaporDB {
+    let options = VaporDBOptions {
+        sst_dir: dir.join("sstables"),
+        durability,
+        ..Default::default()
+    };
+    VaporDB::open(dir.join("db.wal").to_str().unwrap(), options).unwrap()
+}
+
+#[test]
+fn test_durability_policies_and_group_commit() {
+    let dir = tempfile::tempdir().unwrap();
+    {
+        let mut db = open_with_durability(dir.path(), Durability::Always);
+        let commit = db.group_commit();
+
+        // Every acknowledged write is on disk; reads never sync
+        db.execute(Command::Set("a".into(), "1".into())).unwrap();
+        db.execute(Command::Set("b".into(), "2".into())).unwrap();
+        db.execute(Command::Get("a".into())).unwrap();
+        assert_eq!(commit.syncs(), 2);
+        assert_eq!(commit.durable(), db.last_sequence());
+
+        // Writes waiting together share one fsync
+        let seqs: Vec<_> = (0..5)
+            .map(|i| {
+                let cmd = Command::Set(format!("deferred{i}"), i.to_string());
+                db.execute_deferred(cmd).unwrap().1
+            })
+            .collect();
+        assert!(commit.durable() < seqs[0]);
+        commit.commit(seqs[4]).unwrap();
+        for &seq in &seqs {
+            commit.commit(seq).unwrap();
+        }
+        assert_eq!(commit.syncs(), 3);
+
+        // Concurrent writers wait outside the lock and never need more
+        // fsyncs than writes
+        let db = Arc::new(Mutex::new(db));
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let db = Arc::clone(&db);
+                std::thread::spawn(move || {
+                    for j in 0..10 {
+                        let cmd = Command::Set(format!("writer{i}-{j}"), j.to_string());
+                        let (seq, commit) = {
+                            let mut db = db.lock().unwrap();
+                            (db.execute_deferred(cmd).unwrap().1, db.group_commit())
+                        };
+                        commit.commit(seq).unwrap();
+                        assert!(commit.durable() >= seq);
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert!(commit.syncs() <= 3 + 80);
+        assert_eq!(commit.durable(), db.lock().unwrap().last_sequence());
+    }
+
+    let mut db = open_with_durability(dir.path(), Durability::Os);
+    assert_eq!(
+        db.execute(Command::Get("writer7-9".into())).unwrap(),
+        Some("9".into())
+    );
+    db.execute(Command::Set("os".into(), "1".into())).unwrap();
+    assert_eq!(db.group_commit().syncs(), 0);
+    drop(db);
+
+    // A write is neither synced nor announced before the background sync
+    let open_every = |interval| {
+        let options = VaporDBOptions {
+            sst_dir: dir.path().join("sstables"),
+            durability: Durability::EverySec,
+            wal_sync_interval: interval,
+            ..Default::default()
+        };
+        VaporDB::open(dir.path().join("db.wal").to_str().unwrap(), options).unwrap()
+    };
+    let mut db = open_every(Duration::from_secs(3600));
+    let events = db.events().subscribe(EventClass::ALL);
+    db.execute(Command::Set("everysec".into(), "1".into()))
+        .unwrap();
+    assert!(events.try_recv().is_err());
+    assert!(db.group_commit().durable() < db.last_sequence());
+    drop(db);
+
+    // It catches up without anyone waiting, and only then announces it
+    let mut db = open_every(Duration::from_millis(10));
+    let events = db.events().subscribe(EventClass::ALL);
+    db.execute(Command::Set("everysec".into(), "2".into()))
+        .unwrap();
+    let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(event.key, "everysec");
+    assert_eq!(db.group_commit().durable(), db.last_sequence());
+
+    assert_eq!("always".parse::<Durability>().unwrap(), Durability::Always);
+    assert_eq!(
+        "EverySec".parse::<Durability>().unwrap(),
+        Durability::EverySec
+    );
+    assert!("sometimes".parse::<Durability>().is_err());
+}
+
+#[test]
+fn test_expirations_survive_restart_and_flush() {
+    let dir = tempfile::tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let expire_at;
+    {
+        let mut db = open_db_with_clock(dir.path(), 1000, &clock);
+        db.execute(Command::Set("short".into(), "old".into()))
+            .unwrap();
+        db.flush().unwrap();
+
+        db.set_with_expiration("long".into(), "kept".into(), 3600)
+            .unwrap();
+        db.set_with_expiration("short".into(), "new".into(), 1)
+            .unwrap();
+        expire_at = db.expiration_table().get("long").unwrap();
+    }
+    clock.advance(Duration::from_secs(1));
+
+    // Replay restores live expirations and drops the lapsed key without
+    // letting the flushed version come back
+    {
+        let mut db = open_db_with_clock(dir.path(), 1000, &clock);
+        assert_eq!(db.expiration_table().get("long"), Some(expire_at));
+        assert_eq!(db.expiration_table().get("short"), None);
+        assert_eq!(
+            db.execute(Command::Get("long".into())).unwrap(),
+            Some("kept".into())
+        );
+        assert_eq!(db.execute(Command::Get("short".into())).unwrap(), None);
+        db.flush().unwrap();
+    }
+
+    // Flushed SSTables carry the timestamp, so the key still expires
+    // without its WAL record
+    let ttls: Vec<_> = {
+        let db = open_db_with_clock(dir.path(), 1000, &clock);
+        let tables = db.tables();
+        let tables = tables.read();
+        tables
+            .manifest()
+            .version()
+            .files()
+            .flat_map(|file| {
+                let sst = tables.table(file.number).unwrap();
+                sst.iter().map(|entry| entry.unwrap()).collect::<Vec<_>>()
+            })
+            .filter(|entry| entry.key == "long")
+            .map(|entry| entry.ttl)
+            .collect()
+    };
+    assert_eq!(ttls, vec![Some(expire_at)]);
+}
+
+#[test]
+fn test_ttls_have_millisecond_precision() {
+    let dir = tempfile::tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let wal_path = dir.path().join("db.wal");
+
+    // A segment from before millisecond expiry times, logging epoch seconds
+    let legacy_at = clock.now_millis() / 1000 + 3600;
+    let record = wal::WalRecord {
+        seq: 1,
+        timestamp: 0,
+        entry: LogEntry::PutExpiring("legacy".into(), Value::String("old".into()), legacy_at),
+    };
+    let payload = bincode::serialize(&record).unwrap();
+    let mut body = (payload.len() as u32).to_le_bytes().to_vec();
+    body.push(1);
+    body.extend_from_slice(&payload);
+    let mut segment = b"VAPORWAL".to_vec();
+    segment.extend_from_slice(&3u32.to_le_bytes());
+    segment.extend_from_slice(&crc32c::crc32c(&body).to_le_bytes());
+    segment.extend_from_slice(&body);
+    std::fs::write(wal::segment_path(&wal_path, 1), segment).unwrap();
+
+    let later_at;
+    {
+        let mut db = open_db_with_clock(dir.path(), 1000, &clock);
+        assert_eq!(db.expiration_table().get("legacy"), Some(legacy_at * 1000));
+
+        db.execute(Command::PSetEx("soon".into(), 300, "v".into()))
+            .unwrap();
+        assert_eq!(db.expiration_table().get("soon"), Some(1_000_300));
+
+        db.execute(Command::Set("later".into(), "v".into()))
+            .unwrap();
+        assert_eq!(
+            db.execute(Command::PExpire("later".into(), 60_000, None))
+                .unwrap(),
+            Some("1".into())
+        );
+        assert_eq!(
+            db.execute(Command::PExpire("missing".into(), 60_000, None))
+                .unwrap(),
+            Some("0".into())
+        );
+        later_at = db.expiration_table().get("later").unwrap();
+
+        // Well short of a second, which whole-second TTLs could not express
+        clock.advance(Duration::from_millis(300));
+        assert_eq!(db.execute(Command::Get("soon".into())).unwrap(), None);
+        assert_eq!(
+            db.execute(Command::Get("later".into())).unwrap(),
+            Some("v".into())
+        );
+    }
+
+    // Replay and SSTables both keep the exact millisecond
+    let mut db = open_db_with_clock(dir.path(), 1000, &clock);
+    assert_eq!(db.expiration_table().get("later"), Some(later_at));
+    db.flush().unwrap();
+    let ttl = db
+        .sstable()
+        .and_then(|sst| sst.get_entry("later").unwrap())
+        .and_then(|entry| entry.ttl);
+    assert_eq!(ttl, Some(later_at));
+}
+
+#[test]
+fn test_expiry_commands_work_on_any_type() {
+    let dir = tempfile::tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let ttl = |db: &mut VaporDB, key: &str| db.execute(Command::Ttl(key.into())).unwrap().unwrap();
+    let expire = |db: &mut VaporDB, key: &str, secs, condition| {
+        db.execute(Command::Expire(key.into(), secs, condition))
+            .unwrap()
+            .unwrap()
+    };
+    {
+        let mut db = open_db_with_clock(dir.path(), 1000, &clock);
+        db.execute(Command::HSet("hash".into(), "f".into(), "v".into()))
+            .unwrap();
+        db.execute(Command::LPush("list".into(), "a".into()))
+            .unwrap();
+        db.execute(Command::SAdd("set".into(), "m".into())).unwrap();
+        db.execute(Command::Set("string".into(), "v".into()))
+            .unwrap();
+        assert_eq!(ttl(&mut db, "hash"), "-1");
+        assert_eq!(ttl(&mut db, "missing"), "-2");
+        assert_eq!(expire(&mut db, "missing", 100, None), "0");
+
+        // NX and XX look at whether there is an expiry, GT and LT compare
+        // with it, a key without one counting as never expiring
+        assert_eq!(expire(&mut db, "hash", 100, Some(ExpireCondition::Xx)), "0");
+        assert_eq!(expire(&mut db, "hash", 100, Some(ExpireCondition::Nx)), "1");
+        assert_eq!(expire(&mut db, "hash", 200, Some(ExpireCondition::Nx)), "0");
+        assert_eq!(expire(&mut db, "hash", 50, Some(ExpireCondition::Gt)), "0");
+        assert_eq!(expire(&mut db, "hash", 200, Some(ExpireCondition::Gt)), "1");
+        assert_eq!(expire(&mut db, "hash", 150, Some(ExpireCondition::Lt)), "1");
+        assert_eq!(ttl(&mut db, "hash"), "150");
+        assert_eq!(expire(&mut db, "list", 100, Some(ExpireCondition::Gt)), "0");
+        assert_eq!(expire(&mut db, "list", 100, Some(ExpireCondition::Lt)), "1");
+
+        // Changing a collection keeps its expiry, a plain set drops it
+        db.execute(Command::HSet("hash".into(), "g".into(), "w".into()))
+            .unwrap();
+        assert_eq!(ttl(&mut db, "hash"), "150");
+        assert_eq!(expire(&mut db, "string", 100, None), "1");
+        db.execute(Command::Set("string".into(), "w".into()))
+            .unwrap();
+        assert_eq!(ttl(&mut db, "string"), "-1");
+
+        assert_eq!(
+            db.execute(Command::Persist("list".into())).unwrap(),
+            Some("1".into())
+        );
+        assert_eq!(
+            db.execute(Command::Persist("list".into())).unwrap(),
+            Some("0".into())
+        );
+        assert_eq!(ttl(&mut db, "list"), "-1");
+
+        // An expired collection reads as missing, and a time in the past
+        // deletes the key at once
+        db.execute(Command::PExpire("set".into(), 100, None))
+            .unwrap();
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(ttl(&mut db, "set"), "-2");
+        assert_eq!(db.execute(Command::SMembers("set".into())).unwrap(), None);
+        assert_eq!(
+            db.execute(Command::ExpireAt("string".into(), 1, None))
+                .unwrap(),
+            Some("1".into())
+        );
+        assert_eq!(db.execute(Command::Get("string".into())).unwrap(), None);
+        db.flush().unwrap();
+    }
+
+    // Once only the SSTables hold the keys, their expiry comes from there
+    let mut db = open_db_with_clock(dir.path(), 1000, &clock);
+    let pttl: i64 = db
+        .execute(Command::PTtl("hash".into()))
+        .unwrap()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(pttl, 149_900);
+    assert_eq!(ttl(&mut db, "list"), "-1");
+    assert_eq!(expire(&mut db, "hash", 100, Some(ExpireCondition::Gt)), "0");
+    assert_eq!(
+        db.execute(Command::Persist("hash".into())).unwrap(),
+        Some("1".into())
+    );
+    assert_eq!(ttl(&mut db, "hash"), "-1");
+}
+
+#[test]
+fn test_active_expiration_deletes_due_keys_through_the_write_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        expire_cycle_budget: Duration::ZERO,
+        clock: clock.clone(),
+        ..Default::default()
+    };
+    let mut db = VaporDB::open(dir.path().join("db.wal").to_str().unwrap(), options).unwrap();
+    for i in 0..500 {
+        db.execute(Command::PSetEx(format!("short:{i:03}"), 20, "v".into()))
+            .unwrap();
+        db.execute(Command::PSetEx(
+            format!("long:{i:03}"),
+            3_600_000,
+            "v".into(),
+        ))
+        .unwrap();
+    }
+    let mut changes = db.subscribe(db.last_sequence() + 1);
+    clock.advance(Duration::from_millis(20));
+
+    // Without a time budget each cycle stops after its first batch
+    let first = db.clean_expired_keys().unwrap();
+    assert!(first > 0 && first < 500);
+    let mut removed = first;
+    while removed < 500 {
+        removed += db.clean_expired_keys().unwrap();
+    }
+    assert_eq!(removed, 500);
+    assert_eq!(db.clean_expired_keys().unwrap(), 0);
+    assert_eq!(db.expiration_table().len(), 500);
+
+    // Every expiry was logged as a delete
+    let mut deleted = Vec::new();
+    while let Some(record) = changes.try_next().unwrap() {
+        match record.entry {
+            LogEntry::Del(key) => deleted.push(key),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+    deleted.sort();
+    let expected: Vec<_> = (0..500).map(|i| format!("short:{i:03}")).collect();
+    assert_eq!(deleted, expected);
+    drop(db);
+
+    let mut db = open_db_with_clock(dir.path(), 1000, &clock);
+    assert_eq!(db.execute(Command::Get("short:007".into())).unwrap(), None);
+    assert_eq!(
+        db.execute(Command::Get("long:007".into())).unwrap(),
+        Some("v".into())
+    );
+}
+
+#[test]
+fn test_flushed_expirations_are_actively_expired_after_reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    {
+        let mut db = open_db_with_clock(dir.path(), 1000, &clock);
+        db.execute(Command::PSetEx("short".into(), 1000, "v".into()))
+            .unwrap();
+        db.execute(Command::PSetEx("rewritten".into(), 1000, "v".into()))
+            .unwrap();
+        db.execute(Command::Set("plain".into(), "v".into()))
+            .unwrap();
+        db.flush().unwrap();
+        db.execute(Command::Set("rewritten".into(), "w".into()))
+            .unwrap();
+    }
+
+    // Only the SSTables know when "short" expires, the WAL having been
+    // flushed, yet the key is back in the index
+    let mut db = open_db_with_clock(dir.path(), 1000, &clock);
+    assert_eq!(db.expiration_table().get("short"), Some(1_001_000));
+    assert_eq!(db.expiration_table().get("rewritten"), None);
+    assert_eq!(db.expiration_table().len(), 1);
+
+    let expired = db.events().subscribe([EventClass::Expired]);
+    let mut changes = db.subscribe(db.last_sequence() + 1);
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(db.clean_expired_keys().unwrap(), 1);
+    db.group_commit().sync().unwrap();
+    assert_eq!(expired.try_recv().unwrap().key, "short");
+    let record = changes.try_next().unwrap().unwrap();
+    assert!(matches!(record.entry, LogEntry::Del(key) if key == "short"));
+    assert!(db.expiration_table().is_empty());
+    drop(db);
+
+    let mut db = open_db_with_clock(dir.path(), 1000, &clock);
+    assert_eq!(db.execute(Command::Get("short".into())).unwrap(), None);
+    assert_eq!(
+        db.execute(Command::Get("rewritten".into())).unwrap(),
+        Some("w".into())
+    );
+    assert!(db.expiration_table().is_empty());
+}
+
+#[test]
+fn test_keyspace_events_cover_writes_expirations_and_evictions() {
+    let dir = tempfile::tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        clock: clock.clone(),
+        compaction_style: CompactionStyle::Fifo,
+        fifo_max_bytes: 1,
+        notify_events: EventClass::ALL
+            .into_iter()
+            .filter(|class| *class != EventClass::List)
+            .collect(),
+        durability: Durability::Always,
+        ..Default::default()
+    };
+    let mut db = VaporDB::open(dir.path().join("db.wal").to_str().unwrap(), options).unwrap();
+    let all = db.events().subscribe(EventClass::ALL);
+    let expired = db.events().subscribe([EventClass::Expired]);
+
+    // Nothing is announced before the write is on disk
+    let (_, seq) = db
+        .execute_deferred(Command::Set("a".into(), "1".into()))
+        .unwrap();
+    assert!(all.try_recv().is_err());
+    db.group_commit().commit(seq).unwrap();
+    db.execute(Command::HSet("h".into(), "f".into(), "v".into()))
+        .unwrap();
+    db.execute(Command::LPush("l".into(), "x".into())).unwrap();
+    db.execute(Command::Expire("h".into(), 3600, None)).unwrap();
+    db.execute(Command::Ttl("h".into())).unwrap();
+    db.execute(Command::Persist("h".into())).unwrap();
+    db.execute(Command::Expire("missing".into(), 10, None))
+        .unwrap();
+    db.execute(Command::Del("a".into())).unwrap();
+    db.execute(Command::Del("a".into())).unwrap();
+    db.execute(Command::HDel("h".into(), "missing".into()))
+        .unwrap();
+    db.execute(Command::Set("past".into(), "v".into())).unwrap();
+    db.execute(Command::ExpireAt("past".into(), 1, None))
+        .unwrap();
+    db.execute(Command::PSetEx("e".into(), 10, "v".into()))
+        .unwrap();
+    clock.advance(Duration::from_millis(10));
+    db.clean_expired_keys().unwrap();
+
+    // Reads, writes that changed nothing and disabled classes stay quiet,
+    // and an expiry already in the past reads as the delete it was
+    let event = |kind, key: &str| KeyEvent {
+        kind,
+        key: key.into(),
+    };
+    assert_eq!(
+        all.try_iter().collect::<Vec<_>>(),
+        vec![
+            event(EventKind::Set, "a"),
+            event(EventKind::HSet, "h"),
+            event(EventKind::Expire, "h"),
+            event(EventKind::Persist, "h"),
+            event(EventKind::Del, "a"),
+            event(EventKind::Set, "past"),
+            event(EventKind::Del, "past"),
+            event(EventKind::Set, "e"),
+            event(EventKind::Expired, "e"),
+        ]
+    );
+    assert_eq!(
+        expired.try_iter().collect::<Vec<_>>(),
+        vec![event(EventKind::Expired, "e")]
+    );
+
+    // FIFO compaction drops every flushed file, evicting what was live
+    db.flush().unwrap();
+    db.compact().unwrap();
+    let mut evicted: Vec<_> = all
+        .try_iter()
+        .filter(|event| event.kind == EventKind::Evicted)
+        .map(|event| event.key)
+        .collect();
+    evicted.sort();
+    assert_eq!(evicted, vec!["h".to_string(), "l".to_string()]);
+
+    db.events().set_enabled([]);
+    db.execute(Command::Set("quiet".into(), "v".into()))
+        .unwrap();
+    assert!(all.try_recv().is_err());
+}
+
+#[test]
+fn test_mock_clock_makes_expiry_deterministic() {
+    let dir = tempfile::tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        clock: clock.clone(),
+        ..Default::default()
+    };
+    let wal_path = dir.path().join("db.wal");
+    let open = || VaporDB::open(wal_path.to_str().unwrap(), options.clone()).unwrap();
+
+    let mut db = open();
+    db.execute(Command::PSetEx("a".into(), 1500, "v".into()))
+        .unwrap();
+    db.execute(Command::PSetEx("c".into(), 100, "v".into()))
+        .unwrap();
+    db.execute(Command::Set("b".into(), "v".into())).unwrap();
+    db.execute(Command::Expire("b".into(), 10, None)).unwrap();
+    assert_eq!(
+        db.execute(Command::PTtl("a".into())).unwrap(),
+        Some("1500".into())
+    );
+
+    // Nothing expires until the clock is moved, to the millisecond
+    clock.advance(Duration::from_millis(1499));
+    assert_eq!(
+        db.execute(Command::Get("a".into())).unwrap(),
+        Some("v".into())
+    );
+    assert_eq!(
+        db.execute(Command::PTtl("a".into())).unwrap(),
+        Some("1".into())
+    );
+    clock.advance(Duration::from_millis(1));
+    assert_eq!(db.execute(Command::Get("a".into())).unwrap(), None);
+    assert_eq!(
+        db.execute(Command::PTtl("a".into())).unwrap(),
+        Some("-2".into())
+    );
+    assert_eq!(db.clean_expired_keys().unwrap(), 1);
+    assert_eq!(db.execute(Command::Get("c".into())).unwrap(), None);
+
+    // Once flushed, the SSTables decide expiry by the same clock
+    db.flush().unwrap();
+    drop(db);
+    let mut db = open();
+    assert_eq!(
+        db.execute(Command::Ttl("b".into())).unwrap(),
+        Some("9".into())
+    );
+    let keys = |db: &VaporDB| -> Vec<String> { db.scan(..).map(|item| item.unwrap().0).collect() };
+    assert_eq!(keys(&db), vec!["b".to_string()]);
+
+    clock.advance(Duration::from_millis(8500));
+    assert!(keys(&db).is_empty());
+    assert_eq!(db.execute(Command::Get("b".into())).unwrap(), None);
+}
+
+#[test]
+fn test_sorted_sets_order_by_score_and_survive_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let zadd = |members: &[(f64, &str)], flags: &[&str]| {
+        Command::ZAdd(
+            "board".into(),
+            members.iter().map(|(s, m)| (*s, m.to_string())).collect(),
+            ZAddFlags::parse(flags).unwrap(),
+        )
+    };
+    let zrange = |by: RangeBy, rev: bool, limit: Option<(usize, usize)>| {
+        Command::ZRange(
+            "board".into(),
+            ZRange {
+                by,
+                rev,
+                limit,
+                with_scores: false,
+            },
+        )
+    };
+    let score = |s: &str| s.parse::<ScoreBound>().unwrap();
+
+    let mut db = open_db(dir.path(), 1000);
+    assert_eq!(
+        db.execute(zadd(
+            &[(30.0, "carol"), (10.0, "alice"), (20.0, "bob")],
+            &[]
+        ))
+        .unwrap(),
+        Some("3".into())
+    );
+    // NX never updates, XX never adds, GT only raises
+    assert_eq!(
+        db.execute(zadd(&[(1.0, "alice"), (40.0, "dave")], &["nx"]))
+            .unwrap(),
+        Some("1".into())
+    );
+    assert_eq!(
+        db.execute(zadd(&[(5.0, "erin"), (25.0, "bob")], &["xx"]))
+            .unwrap(),
+        Some("0".into())
+    );
+    db.execute(zadd(&[(15.0, "carol"), (45.0, "dave")], &["gt"]))
+        .unwrap();
+    assert_eq!(
+        db.execute(zadd(&[(5.0, "alice")], &["incr"])).unwrap(),
+        Some("15".into())
+    );
+    assert_eq!(
+        db.execute(zadd(&[(1.0, "alice")], &["incr", "lt"]))
+            .unwrap(),
+        None
+    );
+    assert!(ZAddFlags::parse(["nx", "gt"]).is_err());
+
+    // alice 15, bob 25, carol 30, dave 45
+    assert_eq!(
+        db.execute(Command::ZScore("board".into(), "bob".into()))
+            .unwrap(),
+        Some("25".into())
+    );
+    assert_eq!(
+        db.execute(Command::ZRank("board".into(), "carol".into()))
+            .unwrap(),
+        Some("2".into())
+    );
+    assert_eq!(
+        db.execute(zrange(RangeBy::Index(1, -1), false, None))
+            .unwrap(),
+        Some(r#"["bob","carol","dave"]"#.into())
+    );
+    assert_eq!(
+        db.execute(zrange(RangeBy::Index(0, 1), true, None))
+            .unwrap(),
+        Some(r#"["dave","carol"]"#.into())
+    );
+    assert_eq!(
+        db.execute(zrange(
+            RangeBy::Score(score("(15"), score("+inf")),
+            false,
+            Some((1, 1))
+        ))
+        .unwrap(),
+        Some(r#"["carol"]"#.into())
+    );
+    assert_eq!(
+        db.execute(zrange(
+            RangeBy::Score(score("-inf"), score("30")),
+            true,
+            None
+        ))
+        .unwrap(),
+        Some(r#"["carol","bob","alice"]"#.into())
+    );
+    assert!(
+        db.execute(zrange(RangeBy::Index(0, -1), false, Some((0, 1))))
+            .is_err()
+    );
+    assert_eq!(
+        db.execute(Command::ZPopMin("board".into(), 1)).unwrap(),
+        Some(r#"[["alice","15"]]"#.into())
+    );
+    assert_eq!(
+        db.execute(Command::ZRem(
+            "board".into(),
+            vec!["bob".into(), "nobody".into()]
+        ))
+        .unwrap(),
+        Some("1".into())
+    );
+    assert_eq!(
+        db.execute(Command::ZCard("board".into())).unwrap(),
+        Some("2".into())
+    );
+    db.execute(Command::Set("plain".into(), "v".into()))
+        .unwrap();
+    assert!(db.execute(Command::ZCard("plain".into())).is_err());
+
+    // Members sharing a score order by name, which lex ranges rely on
+    for member in ["d", "b", "a", "c", "e"] {
+        db.execute(Command::ZAdd(
+            "names".into(),
+            vec![(0.0, member.into())],
+            ZAddFlags::default(),
+        ))
+        .unwrap();
+    }
+    let lex = |s: &str| s.parse::<LexBound>().unwrap();
+    assert_eq!(
+        db.execute(Command::ZRange(
+            "names".into(),
+            ZRange {
+                by: RangeBy::Lex(lex("(a"), lex("[d")),
+                rev: false,
+                limit: None,
+                with_scores: true,
+            },
+        ))
+        .unwrap(),
+        Some(r#"[["b","0"],["c","0"],["d","0"]]"#.into())
+    );
+
+    // The set round-trips through the WAL and an SSTable
+    drop(db);
+    let mut db = open_db(dir.path(), 1000);
+    assert_eq!(
+        db.execute(Command::ZCard("names".into())).unwrap(),
+        Some("5".into())
+    );
+    db.flush().unwrap();
+    assert_eq!(
+        db.execute(Command::ZPopMax("names".into(), 2)).unwrap(),
+        Some(r#"[["e","0"],["d","0"]]"#.into())
+    );
+    drop(db);
+    let mut db = open_db(dir.path(), 1000);
+    assert_eq!(
+        db.execute(Command::ZRank("names".into(), "c".into()))
+            .unwrap(),
+        Some("2".into())
+    );
+
+    // Popping the last member deletes the key
+    db.execute(Command::ZPopMin("names".into(), 10)).unwrap();
+    assert_eq!(
+        db.execute(Command::ZCard("names".into())).unwrap(),
+        Some("0".into())
+    );
+    assert_eq!(
+        db.execute(Command::Ttl("names".into())).unwrap(),
+        Some("-2".into())
+    );
+}
+
+#[test]
+fn test_sorted_set_ranks_match_a_sorted_list_under_churn() {
+    let mut set = SortedSet::new();
+    let mut reference: Vec<(f64, String)> = Vec::new();
+    let mut seed: u64 = 42;
+    let mut next = || {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        seed >> 33
+    };
+    for _ in 0..5000 {
+        let member = format!("m{}", next() % 300);
+        let score = (next() % 50) as f64;
+        if next() % 3 == 0 {
+            assert_eq!(
+                set.remove(&member),
+                reference
+                    .iter()
+                    .position(|(_, m)| *m == member)
+                    .map(|i| reference.remove(i).0)
+            );
+        } else {
+            set.insert(member.clone(), score);
+            reference.retain(|(_, m)| *m != member);
+            reference.push((score, member));
+        }
+    }
+    reference.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+
+    assert_eq!(set.len(), reference.len());
+    for (rank, (score, member)) in reference.iter().enumerate() {
+        assert_eq!(set.rank(member), Some(rank));
+        assert_eq!(set.score(member), Some(*score));
+    }
+    let all: Vec<(String, f64)> = set
+        .iter()
+        .map(|(member, score)| (member.to_string(), score))
+        .collect();
+    let expected: Vec<(String, f64)> = reference
+        .iter()
+        .map(|(score, member)| (member.clone(), *score))
+        .collect();
+    assert_eq!(all, expected);
+
+    let middle = set.range(&RangeBy::Index(100, 109), false, None);
+    assert_eq!(middle.len(), 10);
+    assert_eq!(middle[0].0, reference[100].1);
+    let between = set.range(
+        &RangeBy::Score("(10".parse().unwrap(), "20".parse().unwrap()),
+        true,
+        None,
+    );
+    let expected: Vec<&str> = reference
+        .iter()
+        .rev()
+        .filter(|(score, _)| *score > 10.0 && *score <= 20.0)
+        .map(|(_, member)| member.as_str())
+        .collect();
+    assert_eq!(
+        between
+            .iter()
+            .map(|(member, _)| *member)
+            .collect::<Vec<_>>(),
+        expected
+    );
+
+    // The skiplist is rebuilt from the pairs it is stored as
+    let bytes = bincode::serialize(&set).unwrap();
+    let mut restored: SortedSet = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(restored.rank(&reference[7].1), Some(7));
+    let top = restored.pop_max(3);
+    assert_eq!(top[0].0, reference.last().unwrap().1);
+    assert_eq!(restored.len(), reference.len() - 3);
+}
+
+#[test]
+fn test_streams_append_with_ordered_ids_and_survive_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(1_000));
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        clock: clock.clone(),
+        ..Default::default()
+    };
+    let wal_path = dir.path().join("db.wal");
+    let open = || VaporDB::open(wal_path.to_str().unwrap(), options.clone()).unwrap();
+    let xadd = |id: Option<&str>, value: &str, max_len: Option<usize>| {
+        Command::XAdd(
+            "events".into(),
+            id.map(|id| id.parse().unwrap()),
+            vec![("n".into(), value.into())],
+            max_len,
+        )
+    };
+    let ids = |json: Option<String>| -> Vec<String> {
+        let entries: Vec<StreamEntry> = serde_json::from_str(&json.unwrap()).unwrap();
+        entries.iter().map(|entry| entry.id.to_string()).collect()
+    };
+
+    // IDs within the same millisecond count up, and never go backwards
+    let mut db = open();
+    assert_eq!(
+        db.execute(xadd(None, "1", None)).unwrap(),
+        Some("1000-0".into())
+    );
+    assert_eq!(
+        db.execute(xadd(None, "2", None)).unwrap(),
+        Some("1000-1".into())
+    );
+    clock.set(500);
+    assert_eq!(
+        db.execute(xadd(None, "3", None)).unwrap(),
+        Some("1000-2".into())
+    );
+    clock.set(2_000);
+    assert_eq!(
+        db.execute(xadd(None, "4", None)).unwrap(),
+        Some("2000-0".into())
+    );
+    assert_eq!(
+        db.execute(xadd(Some("2500-7"), "5", None)).unwrap(),
+        Some("2500-7".into())
+    );
+    assert!(db.execute(xadd(Some("2500-7"), "6", None)).is_err());
+    assert!(
+        db.execute(Command::XAdd("events".into(), None, vec![], None))
+            .is_err()
+    );
+
+    let range = |start: &str, end: &str| {
+        Command::XRange(
+            "events".into(),
+            parse_range_start(start).unwrap(),
+            parse_range_end(end).unwrap(),
+            None,
+        )
+    };
+    assert_eq!(
+        ids(db.execute(range("1000", "2000")).unwrap()),
+        ["1000-0", "1000-1", "1000-2", "2000-0"]
+    );
+    assert_eq!(
+        ids(db.execute(range("(1000-1", "+")).unwrap()),
+        ["1000-2", "2000-0", "2500-7"]
+    );
+    assert!(ids(db.execute(range("2000", "1000")).unwrap()).is_empty());
+    assert_eq!(
+        ids(db
+            .execute(Command::XRevRange(
+                "events".into(),
+                parse_range_start("-").unwrap(),
+                parse_range_end("+").unwrap(),
+                Some(2),
+            ))
+            .unwrap()),
+        ["2500-7", "2000-0"]
+    );
+    assert_eq!(
+        ids(db
+            .execute(Command::XRead(
+                "events".into(),
+                "1000-2".parse().unwrap(),
+                None
+            ))
+            .unwrap()),
+        ["2000-0", "2500-7"]
+    );
+    assert!(matches!(
+        db.execute(Command::HGet("events".into(), "n".into())),
+        Err(VaporDBError::TypeMismatch(_))
+    ));
+
+    // MAXLEN keeps the newest entries, and later IDs still follow the
+    // trimmed ones
+    db.execute(xadd(None, "6", Some(3))).unwrap();
+    assert_eq!(
+        db.execute(Command::XLen("events".into())).unwrap(),
+        Some("3".into())
+    );
+
+    // The stream round-trips through the WAL and an SSTable
+    drop(db);
+    let mut db = open();
+    db.flush().unwrap();
+    drop(db);
+    let mut db = open();
+    assert_eq!(
+        db.execute(range("-", "+")).unwrap(),
+        Some(
+            r#"[{"id":"2000-0","fields":[["n","4"]]},{"id":"2500-7","fields":[["n","5"]]},{"id":"2500-8","fields":[["n","6"]]}]"#
+                .into()
+        )
+    );
+    db.execute(xadd(None, "7", Some(0))).unwrap();
+    assert_eq!(
+        db.execute(Command::XLen("events".into())).unwrap(),
+        Some("0".into())
+    );
+    assert!(db.execute(xadd(Some("2500-8"), "8", None)).is_err());
+}
+
+#[test]
+fn test_consumer_groups_redeliver_unacknowledged_entries() {
+    let dir = tempfile::tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(10_000));
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        clock: clock.clone(),
+        ..Default::default()
+    };
+    let wal_path = dir.path().join("db.wal");
+    let open = || VaporDB::open(wal_path.to_str().unwrap(), options.clone()).unwrap();
+    let read_group = |consumer: &str, after: Option<&str>, count: Option<usize>| {
+        Command::XReadGroup(
+            "jobs".into(),
+            "workers".into(),
+            consumer.into(),
+            after.map(|id| id.parse().unwrap()),
+            count,
+        )
+    };
+    let ids = |json: Option<String>| -> Vec<String> {
+        let entries: Vec<StreamEntry> = serde_json::from_str(&json.unwrap()).unwrap();
+        entries.iter().map(|entry| entry.id.to_string()).collect()
+    };
+    let pending = |db: &mut VaporDB| -> Vec<serde_json::Value> {
+        let json = db
+            .execute(Command::XPending("jobs".into(), "workers".into()))
+            .unwrap()
+            .unwrap();
+        serde_json::from_str(&json).unwrap()
+    };
+
+    let mut db = open();
+    assert!(
+        db.execute(Command::XGroupCreate(
+            "jobs".into(),
+            "workers".into(),
+            None,
+            false
+        ))
+        .is_err()
+    );
+    db.execute(Command::XGroupCreate(
+        "jobs".into(),
+        "workers".into(),
+        None,
+        true,
+    ))
+    .unwrap();
+    assert!(
+        db.execute(Command::XGroupCreate(
+            "jobs".into(),
+            "workers".into(),
+            None,
+            false
+        ))
+        .is_err()
+    );
+    for job in ["a", "b", "c"] {
+        db.execute(Command::XAdd(
+            "jobs".into(),
+            None,
+            vec![("job".into(), job.into())],
+            None,
+        ))
+        .unwrap();
+    }
+
+    // Each entry goes to one consumer only
+    assert_eq!(
+        ids(db.execute(read_group("alice", None, Some(2))).unwrap()),
+        ["10000-0", "10000-1"]
+    );
+    assert_eq!(
+        ids(db.execute(read_group("bob", None, None)).unwrap()),
+        ["10000-2"]
+    );
+    assert!(ids(db.execute(read_group("bob", None, None)).unwrap()).is_empty());
+    assert!(
+        db.execute(Command::XReadGroup(
+            "jobs".into(),
+            "nobody".into(),
+            "bob".into(),
+            None,
+            None
+        ))
+        .is_err()
+    );
+
+    // bob finishes his entry; alice crashes holding hers
+    assert_eq!(
+        db.execute(Command::XAck(
+            "jobs".into(),
+            "workers".into(),
+            vec!["10000-2".parse().unwrap(), "10000-2".parse().unwrap()],
+        ))
+        .unwrap(),
+        Some("1".into())
+    );
+    clock.advance(Duration::from_millis(5_000));
+
+    // The pending entries list survives a restart
+    drop(db);
+    let mut db = open();
+    db.flush().unwrap();
+    let listed = pending(&mut db);
+    assert_eq!(listed.len(), 2);
+    assert_eq!(listed[0]["id"], "10000-0");
+    assert_eq!(listed[0]["consumer"], "alice");
+    assert_eq!(listed[0]["idle_ms"], 5_000);
+    assert_eq!(listed[0]["deliveries"], 1);
+
+    // alice can pick up where she left off after restarting
+    assert_eq!(
+        ids(db.execute(read_group("alice", Some("0"), None)).unwrap()),
+        ["10000-0", "10000-1"]
+    );
+    assert!(ids(db.execute(read_group("bob", Some("0"), None)).unwrap()).is_empty());
+
+    // Or bob claims what has been idle long enough
+    let claim = |min_idle: u64| {
+        Command::XClaim(
+            "jobs".into(),
+            "workers".into(),
+            "bob".into(),
+            min_idle,
+            vec!["10000-0".parse().unwrap(), "10000-1".parse().unwrap()],
+        )
+    };
+    assert!(ids(db.execute(claim(6_000)).unwrap()).is_empty());
+    assert_eq!(
+        ids(db.execute(claim(5_000)).unwrap()),
+        ["10000-0", "10000-1"]
+    );
+    assert!(ids(db.execute(claim(5_000)).unwrap()).is_empty());
+    let listed = pending(&mut db);
+    assert_eq!(listed[1]["consumer"], "bob");
+    assert_eq!(listed[1]["idle_ms"], 0);
+    assert_eq!(listed[1]["deliveries"], 2);
+
+    drop(db);
+    let mut db = open();
+    assert_eq!(
+        db.execute(Command::XAck(
+            "jobs".into(),
+            "workers".into(),
+            vec!["10000-0".parse().unwrap(), "10000-1".parse().unwrap()],
+        ))
+        .unwrap(),
+        Some("2".into())
+    );
+    assert!(pending(&mut db).is_empty());
+
+    // A group made from the start delivers the whole stream
+    db.execute(Command::XGroupCreate(
+        "jobs".into(),
+        "audit".into(),
+        Some(StreamId::MIN),
+        false,
+    ))
+    .unwrap();
+    assert_eq!(
+        ids(db
+            .execute(Command::XReadGroup(
+                "jobs".into(),
+                "audit".into(),
+                "carol".into(),
+                None,
+                None
+            ))
+            .unwrap()),
+        ["10000-0", "10000-1", "10000-2"]
+    );
+}
+
+#[test]
+fn test_counters_increment_strings_and_hash_fields_in_place() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut db = open_db(dir.path(), 1000);
+
+    assert_eq!(
+        db.execute(Command::Incr("hits".into())).unwrap(),
+        Some("1".into())
+    );
+    assert_eq!(
+        db.execute(Command::IncrBy("hits".into(), 41)).unwrap(),
+        Some("42".into())
+    );
+    assert_eq!(
+        db.execute(Command::Decr("hits".into())).unwrap(),
+        Some("41".into())
+    );
+    assert_eq!(
+        db.execute(Command::Get("hits".into())).unwrap(),
+        Some("41".into())
+    );
+
+    // Existing strings count if they parse, and keep their expiry
+    db.execute(Command::PSetEx("views".into(), 60_000, "-5".into()))
+        .unwrap();
+    assert_eq!(
+        db.execute(Command::IncrBy("views".into(), -5)).unwrap(),
+        Some("-10".into())
+    );
+    let ttl: i64 = db
+        .execute(Command::PTtl("views".into()))
+        .unwrap()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(ttl > 0);
+
+    // Bad values and overflow leave the value alone
+    db.execute(Command::Set("name".into(), "vapor".into()))
+        .unwrap();
+    assert!(matches!(
+        db.execute(Command::Incr("name".into())),
+        Err(VaporDBError::NotANumber(_))
+    ));
+    db.execute(Command::Set("max".into(), i64::MAX.to_string()))
+        .unwrap();
+    assert!(matches!(
+        db.execute(Command::Incr("max".into())),
+        Err(VaporDBError::Overflow(_))
+    ));
+    assert_eq!(
+        db.execute(Command::Get("max".into())).unwrap(),
+        Some(i64::MAX.to_string())
+    );
+    db.execute(Command::LPush("queue".into(), "1".into()))
+        .unwrap();
+    assert!(matches!(
+        db.execute(Command::Incr("queue".into())),
+        Err(VaporDBError::TypeMismatch(_))
+    ));
+
+    // Floats work on integers too, but integers do not take floats
+    assert_eq!(
+        db.execute(Command::IncrByFloat("hits".into(), 0.5))
+            .unwrap(),
+        Some("41.5".into())
+    );
+    assert!(matches!(
+        db.execute(Command::Incr("hits".into())),
+        Err(VaporDBError::NotANumber(_))
+    ));
+    db.execute(Command::Set("big".into(), f64::MAX.to_string()))
+        .unwrap();
+    assert!(matches!(
+        db.execute(Command::IncrByFloat("big".into(), f64::MAX)),
+        Err(VaporDBError::Overflow(_))
+    ));
+    assert!(matches!(
+        db.execute(Command::IncrByFloat("hits".into(), f64::NAN)),
+        Err(VaporDBError::NotANumber(_))
+    ));
+    assert!(matches!(
+        db.execute(Command::IncrByFloat("hits".into(), f64::INFINITY)),
+        Err(VaporDBError::NotANumber(_))
+    ));
+    db.execute(Command::Set("unbounded".into(), "inf".into()))
+        .unwrap();
+    assert!(matches!(
+        db.execute(Command::IncrByFloat("unbounded".into(), 1.0)),
+        Err(VaporDBError::NotANumber(_))
+    ));
+
+    // Results are the shortest form that reads back as the same value
+    let mut incr_float = |key: &str, by: f64| {
+        db.execute(Command::IncrByFloat(key.into(), by))
+            .unwrap()
+            .unwrap()
+    };
+    assert_eq!(incr_float("float", 0.1), "0.1");
+    assert_eq!(incr_float("float", 0.2), "0.30000000000000004");
+    assert_eq!(incr_float("float", -0.30000000000000004), "0");
+    assert_eq!(incr_float("float", 3.0), "3");
+    assert_eq!(incr_float("float", 1e21), "1e21");
+    assert_eq!(incr_float("float", 1e21), "2e21");
+    assert_eq!(incr_float("float", -2e21), "0");
+    assert_eq!(incr_float("float", 1.5e-7), "1.5e-7");
+    assert_eq!(incr_float("float", -1e300), "-1e300");
+
+    // Hash fields, alongside the fields left untouched
+    db.execute(Command::HSet("stats".into(), "owner".into(), "me".into()))
+        .unwrap();
+    assert_eq!(
+        db.execute(Command::HIncrBy("stats".into(), "runs".into(), 3))
+            .unwrap(),
+        Some("3".into())
+    );
+    assert_eq!(
+        db.execute(Command::HIncrByFloat("stats".into(), "load".into(), 2.25))
+            .unwrap(),
+        Some("2.25".into())
+    );
+    assert!(matches!(
+        db.execute(Command::HIncrBy("stats".into(), "owner".into(), 1)),
+        Err(VaporDBError::NotANumber(_))
+    ));
+    assert!(matches!(
+        db.execute(Command::HIncrBy("hits".into(), "runs".into(), 1)),
+        Err(VaporDBError::TypeMismatch(_))
+    ));
+
+    // The results are logged as plain values
+    drop(db);
+    let mut db = open_db(dir.path(), 1000);
+    assert_eq!(
+        db.execute(Command::Get("hits".into())).unwrap(),
+        Some("41.5".into())
+    );
+    assert_eq!(
+        db.execute(Command::HGet("stats".into(), "runs".into()))
+            .unwrap(),
+        Some("3".into())
+    );
+    assert_eq!(
+        db.execute(Command::HGet("stats".into(), "owner".into()))
+            .unwrap(),
+        Some("me".into())
+    );
+}
+
+fn change_keys(stream: &mut core::wal::cdc::ChangeStream) -> Vec<String> {
+    let mut keys = Vec::new();
+    while let Some(record) = stream.try_next().unwrap() {
+        match record.entry {
+            LogEntry::Put(key, _) | LogEntry::PutExpiring(key, _, _) | LogEntry::Del(key) => {
+                keys.push(key)
+            }
+            LogEntry::Set(key, _) => keys.push(key),
+        }
+    }
+    keys
+}
+
+#[test]
+fn test_change_stream_tails_the_wal_and_resumes_from_its_cursor() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        wal_retention: Duration::from_secs(3600),
+        ..Default::default()
+    };
+    let wal_path = dir.path().join("db.wal");
+    let mut db = VaporDB::open(wal_path.to_str().unwrap(), options.clone()).unwrap();
+
+    db.execute(Command::Set("a".into(), "1".into())).unwrap();
+    db.execute(Command::HSet("h".into(), "f".into(), "v".into()))
+        .unwrap();
+    db.execute(Command::Del("a".into())).unwrap();
+
+    let mut stream = db.subscribe(0);
+    assert_eq!(change_keys(&mut stream), vec!["a", "h", "a"]);
+    let cursor = stream.cursor();
+    assert_eq!(cursor, db.last_sequence() + 1);
+
+    // Flushed segments are retained, so a stream still reads across them
+    db.execute(Command::Set("b".into(), "2".into())).unwrap();
+    db.flush().unwrap();
+    db.execute(Command::Set("c".into(), "3".into())).unwrap();
+    assert_eq!(change_keys(&mut stream), vec!["b", "c"]);
+
+    // A waiting stream wakes up for a write from another thread
+    let db = Arc::new(Mutex::new(db));
+    let ready = Arc::new(Barrier::new(2));
+    let writer = {
+        let db = Arc::clone(&db);
+        let ready = Arc::clone(&ready);
+        std::thread::spawn(move || {
+            ready.wait();
+            let mut db = db.lock().unwrap();
+            db.execute(Command::Set("d".into(), "4".into())).unwrap();
+        })
+    };
+    ready.wait();
+    let record = stream
+        .next_timeout(Duration::from_secs(5))
+        .unwrap()
+        .unwrap();
+    assert!(matches!(record.entry, LogEntry::Put(key, _) if key == "d"));
+    writer.join().unwrap();
+    assert!(
+        stream
+            .next_timeout(Duration::from_millis(10))
+            .unwrap()
+            .is_none()
+    );
+    drop(db);
+
+    // Cursors survive a restart
+    let db = VaporDB::open(wal_path.to_str().unwrap(), options.clone()).unwrap();
+    let mut resumed = db.subscribe(cursor);
+    assert_eq!(change_keys(&mut resumed), vec!["b", "c", "d"]);
+    drop(db);
+
+    // Without retention the flushed history is gone, and a cursor into it
+    // is refused rather than silently skipping writes
+    let options = VaporDBOptions {
+        wal_retention: Duration::ZERO,
+        ..options
+    };
+    let mut db = VaporDB::open(wal_path.to_str().unwrap(), options).unwrap();
+    db.flush().unwrap();
+    db.execute(Command::Set("e".into(), "5".into())).unwrap();
+    assert!(matches!(
+        db.subscribe(1).try_next(),
+        Err(core::error::VaporDBError::SequenceUnavailable(1))
+    ));
+    assert_eq!(change_keys(&mut db.subscribe(0)), vec!["e"]);
+}
+
+#[test]
+fn test_caught_up_change_stream_survives_its_segment_being_purged() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut db = open_db(dir.path(), 1000);
+    db.execute(Command::Set("a".into(), "1".into())).unwrap();
+    let mut stream = db.subscribe(0);
+    assert_eq!(change_keys(&mut stream), vec!["a"]);
+
+    // Without retention the flush purges the segment the stream last read,
+    // but it had already consumed everything in it
+    db.flush().unwrap();
+    db.execute(Command::Set("b".into(), "2".into())).unwrap();
+    assert_eq!(change_keys(&mut stream), vec!["b"]);
+    db.flush().unwrap();
+    assert!(stream.try_next().unwrap().is_none());
+    db.execute(Command::Set("c".into(), "3".into())).unwrap();
+    assert_eq!(change_keys(&mut stream), vec!["c"]);
+
+    // A stream that fell behind a purge is still refused
+    let mut behind = db.subscribe(stream.cursor() - 2);
+    assert!(matches!(
+        behind.try_next(),
+        Err(core::error::VaporDBError::SequenceUnavailable(_))
+    ));
+}
+
+#[test]
+fn test_point_in_time_recovery_from_backup_and_archived_wal() {
+    let dir = tempfile::tempdir().unwrap();
+    let archive = dir.path().join("archive");
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        wal_archive_dir: Some(archive.clone()),
+        clock: clock.clone(),
+        ..Default::default()
+    };
+    let wal_path = dir.path().join("db.wal");
+    let mut db = VaporDB::open(wal_path.to_str().unwrap(), options).unwrap();
+
+    db.execute(Command::Set("a".into(), "1".into())).unwrap();
+    db.execute(Command::Set("b".into(), "2".into())).unwrap();
+    let info = db.backup(dir.path().join("backup")).unwrap();
+    assert_eq!(info.last_sequence, db.last_sequence());
+    assert!(db.backup(dir.path().join("backup")).is_err());
+
+    // Flushed segments land in the archive rather than being deleted
+    db.execute(Command::Set("c".into(), "3".into())).unwrap();
+    db.flush().unwrap();
+    assert!(
+        !wal::segment_numbers(&archive.join("db.wal"))
+            .unwrap()
+            .is_empty()
+    );
+    db.execute(Command::Set("d".into(), "4".into())).unwrap();
+    let good_seq = db.last_sequence();
+    // WAL records are stamped by the same clock
+    clock.advance(Duration::from_millis(1));
+    let good_time = clock.now_millis();
+    clock.advance(Duration::from_millis(1));
+
+    // The mistake
+    for key in ["a", "b", "c", "d"] {
+        db.execute(Command::Del(key.into())).unwrap();
+    }
+    drop(db);
+
+    let logs = vec![archive.join("db.wal"), wal_path.clone()];
+    let restore = |name: &str, target| {
+        let target_dir = dir.path().join(name);
+        let options = VaporDBOptions {
+            sst_dir: target_dir.join("sstables"),
+            ..Default::default()
+        };
+        let wal = target_dir.join("db.wal");
+        VaporDB::restore(
+            dir.path().join("backup"),
+            &logs,
+            target,
+            wal.to_str().unwrap(),
+            options,
+        )
+    };
+
+    for (name, target) in [
+        ("by_sequence", RecoveryTarget::Sequence(good_seq)),
+        ("by_time", RecoveryTarget::Time(good_time)),
+    ] {
+        let (mut restored, report) = restore(name, target).unwrap();
+        assert_eq!(report.base_sequence, info.last_sequence);
+        assert_eq!(report.replayed, 2);
+        assert_eq!(report.last_sequence, good_seq);
+        for (key, value) in [("a", "1"), ("b", "2"), ("c", "3"), ("d", "4")] {
+            assert_eq!(
+                restored.execute(Command::Get(key.into())).unwrap(),
+                Some(value.into())
+            );
+        }
+    }
+
+    // Stopping before the backup is impossible, and nothing is restored over
+    // an existing database
+    assert!(restore("too_early", RecoveryTarget::Sequence(1)).is_err());
+    assert!(restore("by_sequence", RecoveryTarget::Sequence(good_seq)).is_err());
+}
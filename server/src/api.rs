@@ -1,18 +1,39 @@
 use warp::Filter;
+use warp::http::Method;
 use std::sync::{Arc, Mutex};
 use core::db::VaporDB;
+use crate::{cdc, events};
 use crate::{handler::{handle_command, handle_rejection}};
 
 pub fn routes(
     db: Arc<Mutex<VaporDB>>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let changes = cdc::routes(db.clone());
+    let events = events::routes(db.clone());
     let db_filter = warp::any().map(move || db.clone());
 
-    warp::post()
+    let cmd = warp::post()
         .and(warp::path("cmd"))
         .and(warp::body::json()) // this returns Result<T, warp::Rejection>
         .and(db_filter)
         .and_then(handle_command) // must return Result<impl Reply, warp::Rejection>
-        .recover(handle_rejection)
+        .recover(handle_rejection);
+
+    changes
+        .or(events)
+        .or(cmd)
         .boxed() // Box the filter to help type inference
 }
+
+/// Everything the server binary serves: `routes` behind the CORS policy
+/// for the web console.
+pub fn app(
+    db: Arc<Mutex<VaporDB>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let cors = warp::cors()
+        .allow_origin("http://localhost:5173")
+        .allow_methods(&[Method::GET, Method::POST])
+        .allow_headers(vec!["Content-Type"]);
+
+    routes(db).with(cors)
+}
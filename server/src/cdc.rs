@@ -0,0 +1,129 @@
+// Change data capture over HTTP. `GET /changes?from=N` long-polls for the
+// writes from sequence number N on and returns them with the cursor to ask
+// for next; `/changes/ws?from=N` pushes them over a WebSocket as they commit.
+
+use crate::handler::{RejectionWrapper, handle_rejection};
+use core::db::VaporDB;
+use core::error::VaporDBError;
+use core::storage::internal_key::SequenceNumber;
+use core::wal::cdc::ChangeStream;
+use core::wal::wal::WalRecord;
+use futures_util::SinkExt;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use warp::ws::{Message, WebSocket, Ws};
+use warp::{Filter, Rejection, Reply};
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_WAIT: Duration = Duration::from_secs(30);
+// How long a WebSocket stream waits for a write before checking the client
+// is still there
+const IDLE_PING: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize)]
+pub struct ChangesQuery {
+    #[serde(default)]
+    pub from: SequenceNumber, // 0 starts at the oldest retained write
+    pub limit: Option<usize>,
+    pub wait_ms: Option<u64>, // how long to wait when nothing is pending
+}
+
+#[derive(Serialize)]
+pub struct ChangesResponse {
+    pub records: Vec<WalRecord>,
+    pub cursor: SequenceNumber, // pass as `from` to continue
+}
+
+pub fn routes(
+    db: Arc<Mutex<VaporDB>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let db_filter = warp::any().map(move || db.clone());
+
+    let poll = warp::get()
+        .and(warp::path::end())
+        .and(warp::query::<ChangesQuery>())
+        .and(db_filter.clone())
+        .and_then(poll_changes);
+
+    let ws = warp::path!("ws")
+        .and(warp::ws())
+        .and(warp::query::<ChangesQuery>())
+        .and(db_filter)
+        .map(|ws: Ws, query: ChangesQuery, db: Arc<Mutex<VaporDB>>| {
+            let stream = db.lock().unwrap().subscribe(query.from);
+            ws.on_upgrade(move |socket| push_changes(socket, stream))
+        });
+
+    // Recovering under the prefix leaves other paths to the other routes
+    warp::path("changes").and(poll.or(ws).recover(handle_rejection))
+}
+
+pub async fn poll_changes(
+    query: ChangesQuery,
+    db: Arc<Mutex<VaporDB>>,
+) -> Result<impl Reply, Rejection> {
+    let mut stream = db.lock().unwrap().subscribe(query.from);
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).max(1);
+    let wait = query
+        .wait_ms
+        .map_or(Duration::ZERO, Duration::from_millis)
+        .min(MAX_WAIT);
+
+    let read = tokio::task::spawn_blocking(move || -> core::error::Result<_> {
+        let mut records = Vec::new();
+        // Only the first record is waited for; the rest are whatever has
+        // already committed
+        if let Some(record) = stream.next_timeout(wait)? {
+            records.push(record);
+            while records.len() < limit {
+                match stream.try_next()? {
+                    Some(record) => records.push(record),
+                    None => break,
+                }
+            }
+        }
+        Ok(ChangesResponse {
+            records,
+            cursor: stream.cursor(),
+        })
+    })
+    .await
+    .map_err(|e| warp::reject::custom(RejectionWrapper(VaporDBError::Internal(e.to_string()))))?
+    .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?;
+
+    Ok(warp::reply::json(&read))
+}
+
+// Sends each record as a JSON text message until the client goes away or
+// the stream fails, in which case the error is sent before closing.
+async fn push_changes(mut socket: WebSocket, mut stream: ChangeStream) {
+    loop {
+        let next = tokio::task::spawn_blocking(move || {
+            let next = stream.next_timeout(IDLE_PING);
+            (stream, next)
+        })
+        .await;
+        let Ok((returned, next)) = next else {
+            break;
+        };
+        stream = returned;
+
+        let message = match next {
+            Ok(Some(record)) => match serde_json::to_string(&record) {
+                Ok(json) => Message::text(json),
+                Err(_) => break,
+            },
+            Ok(None) => Message::ping(Vec::new()),
+            Err(e) => {
+                let error = serde_json::json!({ "error": e.to_string() });
+                let _ = socket.send(Message::text(error.to_string())).await;
+                break;
+            }
+        };
+        if socket.send(message).await.is_err() {
+            break;
+        }
+    }
+    let _ = socket.close().await;
+}
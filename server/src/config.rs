@@ -0,0 +1,53 @@
+use core::error::{Result, VaporDBError};
+use core::events::EventClass;
+use core::options::VaporDBOptions;
+use core::wal::wal::Durability;
+use std::time::Duration;
+
+/// Server settings, read from the environment.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub durability: Durability,         // VAPORDB_DURABILITY: always, everysec or os
+    pub notify_events: Vec<EventClass>, // VAPORDB_NOTIFY_EVENTS: comma-separated classes, or all
+    pub wal_retention: Duration,        // VAPORDB_WAL_RETENTION: seconds flushed WAL segments are kept
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            durability: Durability::default(),
+            notify_events: EventClass::ALL.to_vec(),
+            wal_retention: VaporDBOptions::default().wal_retention,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Result<Self> {
+        let mut config = Self::default();
+        if let Ok(durability) = std::env::var("VAPORDB_DURABILITY") {
+            config.durability = durability.parse()?;
+        }
+        if let Ok(events) = std::env::var("VAPORDB_NOTIFY_EVENTS") {
+            config.notify_events = EventClass::parse_list(&events)?;
+        }
+        if let Ok(retention) = std::env::var("VAPORDB_WAL_RETENTION") {
+            let secs = retention.trim().parse().map_err(|_| {
+                VaporDBError::Internal(format!(
+                    "invalid WAL retention '{retention}', expected a number of seconds"
+                ))
+            })?;
+            config.wal_retention = Duration::from_secs(secs);
+        }
+        Ok(config)
+    }
+
+    pub fn db_options(&self) -> VaporDBOptions {
+        VaporDBOptions {
+            durability: self.durability,
+            notify_events: self.notify_events.clone(),
+            wal_retention: self.wal_retention,
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,80 @@
+// Keyspace notifications over a WebSocket. `/events/ws?classes=expired,hash`
+// pushes every event of the listed classes, or of every class if none are
+// given, as a JSON text message from the moment the socket opens.
+
+use crate::handler::{RejectionWrapper, handle_rejection};
+use core::db::VaporDB;
+use core::events::{EventClass, KeyEvent};
+use futures_util::SinkExt;
+use serde::Deserialize;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use warp::ws::{Message, WebSocket, Ws};
+use warp::{Filter, Rejection, Reply};
+
+// How long the socket waits for an event before checking the client is
+// still there
+const IDLE_PING: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    pub classes: Option<String>, // comma-separated, every class if absent
+}
+
+pub fn routes(
+    db: Arc<Mutex<VaporDB>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let db_filter = warp::any().map(move || db.clone());
+
+    let ws = warp::path!("ws")
+        .and(warp::ws())
+        .and(warp::query::<EventsQuery>())
+        .and(db_filter)
+        .and_then(subscribe_events);
+
+    warp::path("events").and(ws.recover(handle_rejection))
+}
+
+pub async fn subscribe_events(
+    ws: Ws,
+    query: EventsQuery,
+    db: Arc<Mutex<VaporDB>>,
+) -> Result<impl Reply, Rejection> {
+    let classes = match query.classes {
+        Some(classes) => EventClass::parse_list(&classes)
+            .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?,
+        None => EventClass::ALL.to_vec(),
+    };
+    let events = db.lock().unwrap().events().subscribe(classes);
+    Ok(ws.on_upgrade(move |socket| push_events(socket, events)))
+}
+
+// Sends each event as a JSON text message until the client goes away.
+// Dropping the receiver unsubscribes it.
+async fn push_events(mut socket: WebSocket, mut events: Receiver<KeyEvent>) {
+    loop {
+        let next = tokio::task::spawn_blocking(move || {
+            let next = events.recv_timeout(IDLE_PING);
+            (events, next)
+        })
+        .await;
+        let Ok((returned, next)) = next else {
+            break;
+        };
+        events = returned;
+
+        let message = match next {
+            Ok(event) => match serde_json::to_string(&event) {
+                Ok(json) => Message::text(json),
+                Err(_) => break,
+            },
+            Err(RecvTimeoutError::Timeout) => Message::ping(Vec::new()),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if socket.send(message).await.is_err() {
+            break;
+        }
+    }
+    let _ = socket.close().await;
+}
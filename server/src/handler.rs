@@ -1,7 +1,13 @@
 use warp::{http::StatusCode, Rejection, Reply};
 use core::db::VaporDB;
-use core::command::Command;
+use core::command::{Command, ZRange};
 use cli::utils::{ClientCommand, Response};
+use core::storage::sorted_set::parse_scored_members;
+use core::storage::stream::{parse_ids, parse_range_end, parse_range_start, StreamId};
+use core::error::VaporDBError;
+use core::storage::internal_key::SequenceNumber;
+use core::wal::group_commit::GroupCommit;
+use core::wal::wal::Durability;
 use std::sync::{Arc, Mutex};
 
 #[derive(Debug)]
@@ -13,21 +19,43 @@ pub async fn handle_command(
     cmd: ClientCommand,
     db: Arc<Mutex<VaporDB>>,
 ) -> Result<impl Reply, Rejection> {
-    let mut db = db.lock().unwrap();
+    let mut seq = 0;
+    let (result, commit) = {
+        let mut db = db.lock().unwrap();
+        (run_command(&mut db, &mut seq, cmd)?, db.group_commit())
+    };
+
+    // Unlocked first so that writers arriving meanwhile can share the fsync
+    wait_durable(commit, seq)
+        .await
+        .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?;
+
+    let resp = Response {
+        result,
+        error: None,
+    };
 
+    Ok(warp::reply::json(&resp))
+}
+
+fn run_command(
+    db: &mut VaporDB,
+    seq: &mut SequenceNumber,
+    cmd: ClientCommand,
+) -> Result<Option<String>, Rejection> {
     let result = match cmd {
         // === String ===
         ClientCommand::Get { key } => {
-            db.execute(Command::Get(key.to_string()))
+            execute(db, seq, Command::Get(key.to_string()))
                 .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
         }
         ClientCommand::Set { key, value } => {
-            db.execute(Command::Set(key.to_string(), value.to_string()))
+            execute(db, seq, Command::Set(key.to_string(), value.to_string()))
                 .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?;
             None
         }
         ClientCommand::Del { key } => {
-            db.execute(Command::Del(key.to_string()))
+            execute(db, seq, Command::Del(key.to_string()))
                 .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?;
             None
         }
@@ -39,7 +67,7 @@ pub async fn handle_command(
 
         // === Hash ===
         ClientCommand::HSet { key, field, value } => {
-            db.execute(Command::HSet(
+            execute(db, seq, Command::HSet(
                 key.to_string(),
                 field.to_string(),
                 value.to_string(),
@@ -48,14 +76,14 @@ pub async fn handle_command(
             None
         }
         ClientCommand::HGet { key, field } => {
-            db.execute(Command::HGet(
+            execute(db, seq, Command::HGet(
                 key.to_string(),
                 field.to_string(),
             ))
             .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
         }
         ClientCommand::HDel { key, field } => {
-            db.execute(Command::HDel(
+            execute(db, seq, Command::HDel(
                 key.to_string(),
                 field.to_string(),
             ))
@@ -65,7 +93,7 @@ pub async fn handle_command(
 
         // === List ===
         ClientCommand::LPush { key, value } => {
-            db.execute(Command::LPush(
+            execute(db, seq, Command::LPush(
                 key.to_string(),
                 value.to_string(),
             ))
@@ -73,7 +101,7 @@ pub async fn handle_command(
             None
         }
         ClientCommand::RPush { key, value } => {
-            db.execute(Command::RPush(
+            execute(db, seq, Command::RPush(
                 key.to_string(),
                 value.to_string(),
             ))
@@ -81,15 +109,15 @@ pub async fn handle_command(
             None
         }
         ClientCommand::LPop { key } => {
-            db.execute(Command::LPop(key.to_string()))
+            execute(db, seq, Command::LPop(key.to_string()))
                 .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
         }
         ClientCommand::RPop { key } => {
-            db.execute(Command::RPop(key.to_string()))
+            execute(db, seq, Command::RPop(key.to_string()))
                 .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
         }
         ClientCommand::LRange { key, start, end } => {
-            db.execute(Command::LRange(
+            execute(db, seq, Command::LRange(
                 key.to_string(),
                 start,
                 end,
@@ -99,7 +127,7 @@ pub async fn handle_command(
 
         // === Set ===
         ClientCommand::SAdd { key, value } => {
-            db.execute(Command::SAdd(
+            execute(db, seq, Command::SAdd(
                 key.to_string(),
                 value.to_string(),
             ))
@@ -107,7 +135,7 @@ pub async fn handle_command(
             None
         }
         ClientCommand::SRem { key, value } => {
-            db.execute(Command::SRem(
+            execute(db, seq, Command::SRem(
                 key.to_string(),
                 value.to_string(),
             ))
@@ -115,17 +143,199 @@ pub async fn handle_command(
             None
         }
         ClientCommand::SMembers { key } => {
-            db.execute(Command::SMembers(key.to_string()))
+            execute(db, seq, Command::SMembers(key.to_string()))
                 .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
         }
-    };
 
-    let resp = Response {
-        result,
-        error: None,
+        // === Expiration ===
+        ClientCommand::PSetEx { key, value, ttl_ms } => {
+            execute(db, seq, Command::PSetEx(
+                key.to_string(),
+                ttl_ms,
+                value.to_string(),
+            ))
+            .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?;
+            None
+        }
+        ClientCommand::Expire { key, ttl_secs, condition } => {
+            execute(db, seq, Command::Expire(key.to_string(), ttl_secs, condition))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::PExpire { key, ttl_ms, condition } => {
+            execute(db, seq, Command::PExpire(key.to_string(), ttl_ms, condition))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::ExpireAt { key, timestamp, condition } => {
+            execute(db, seq, Command::ExpireAt(key.to_string(), timestamp, condition))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::PExpireAt { key, timestamp_ms, condition } => {
+            execute(db, seq, Command::PExpireAt(key.to_string(), timestamp_ms, condition))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::Ttl { key } => {
+            execute(db, seq, Command::Ttl(key.to_string()))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::PTtl { key } => {
+            execute(db, seq, Command::PTtl(key.to_string()))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::Persist { key } => {
+            execute(db, seq, Command::Persist(key.to_string()))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+
+        // === Sorted set ===
+        ClientCommand::ZAdd { key, members, flags } => {
+            let members = parse_scored_members(members)
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?;
+            execute(db, seq, Command::ZAdd(key, members, flags))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::ZRem { key, members } => {
+            execute(db, seq, Command::ZRem(key, members))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::ZScore { key, member } => {
+            execute(db, seq, Command::ZScore(key, member))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::ZRank { key, member } => {
+            execute(db, seq, Command::ZRank(key, member))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::ZRange { key, by, rev, limit, with_scores } => {
+            let range = ZRange { by, rev, limit, with_scores };
+            execute(db, seq, Command::ZRange(key, range))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::ZPopMin { key, count } => {
+            execute(db, seq, Command::ZPopMin(key, count.unwrap_or(1)))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::ZPopMax { key, count } => {
+            execute(db, seq, Command::ZPopMax(key, count.unwrap_or(1)))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::ZCard { key } => {
+            execute(db, seq, Command::ZCard(key))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+
+        // === Stream ===
+        ClientCommand::XAdd { key, id, fields, max_len } => {
+            let id = id.as_deref().map(str::parse::<StreamId>).transpose()
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?;
+            execute(db, seq, Command::XAdd(key, id, fields, max_len))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::XRange { key, start, end, rev, count } => {
+            let start = parse_range_start(&start)
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?;
+            let end = parse_range_end(&end)
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?;
+            let cmd = if rev {
+                Command::XRevRange(key, start, end, count)
+            } else {
+                Command::XRange(key, start, end, count)
+            };
+            execute(db, seq, cmd)
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::XLen { key } => {
+            execute(db, seq, Command::XLen(key))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::XGroupCreate { key, group, start, mkstream } => {
+            let start = start.as_deref().map(str::parse::<StreamId>).transpose()
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?;
+            execute(db, seq, Command::XGroupCreate(key, group, start, mkstream))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::XReadGroup { key, group, consumer, id, count } => {
+            let id = id.as_deref().map(str::parse::<StreamId>).transpose()
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?;
+            execute(db, seq, Command::XReadGroup(key, group, consumer, id, count))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::XAck { key, group, ids } => {
+            let ids = parse_ids(&ids)
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?;
+            execute(db, seq, Command::XAck(key, group, ids))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::XPending { key, group } => {
+            execute(db, seq, Command::XPending(key, group))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::XClaim { key, group, consumer, min_idle_ms, ids } => {
+            let ids = parse_ids(&ids)
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?;
+            execute(db, seq, Command::XClaim(key, group, consumer, min_idle_ms, ids))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+
+        // === Counters ===
+        ClientCommand::Incr { key } => {
+            execute(db, seq, Command::Incr(key))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::Decr { key } => {
+            execute(db, seq, Command::Decr(key))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::IncrBy { key, by } => {
+            execute(db, seq, Command::IncrBy(key, by))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::IncrByFloat { key, by } => {
+            execute(db, seq, Command::IncrByFloat(key, by))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::HIncrBy { key, field, by } => {
+            execute(db, seq, Command::HIncrBy(key, field, by))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
+        ClientCommand::HIncrByFloat { key, field, by } => {
+            execute(db, seq, Command::HIncrByFloat(key, field, by))
+                .map_err(|e| warp::reject::custom(RejectionWrapper(e)))?
+        }
     };
 
-    Ok(warp::reply::json(&resp))
+    Ok(result)
+}
+
+/// Runs `cmd` without waiting for the WAL, recording in `seq` the write to
+/// pass to `wait_durable` once the database is unlocked.
+pub fn execute(
+    db: &mut VaporDB,
+    seq: &mut SequenceNumber,
+    cmd: Command,
+) -> core::error::Result<Option<String>> {
+    let (result, written) = db.execute_deferred(cmd)?;
+    *seq = (*seq).max(written);
+    Ok(result)
+}
+
+/// Waits, off the async runtime, until the write numbered `seq` may be
+/// acknowledged under the WAL's durability policy.
+pub async fn wait_durable(commit: Arc<GroupCommit>, seq: SequenceNumber) -> core::error::Result<()> {
+    if seq == 0 || commit.durability() != Durability::Always {
+        return Ok(());
+    }
+    tokio::task::spawn_blocking(move || commit.commit(seq))
+        .await
+        .map_err(|e| VaporDBError::Internal(e.to_string()))?
+}
+
+/// 400 for a request the database refused, 500 for anything else.
+pub fn error_status(e: &VaporDBError) -> StatusCode {
+    if e.is_client_error() {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
 }
 
 pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, std::convert::Infallible> {
@@ -135,7 +345,7 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, std::convert
             result: None,
             error: Some(format!("VaporDB error: {:?}", rejection.0)),
         });
-        Ok(warp::reply::with_status(json, StatusCode::INTERNAL_SERVER_ERROR))
+        Ok(warp::reply::with_status(json, error_status(&rejection.0)))
     } else {
         let json = warp::reply::json(&Response {
             result: None,
@@ -1,3 +1,6 @@
 pub mod api;
+pub mod cdc;
+pub mod config;
+pub mod events;
 pub mod handler;
 pub mod server;
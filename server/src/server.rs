@@ -1,10 +1,12 @@
 use std::sync::{Arc, Mutex};
 use core::db::VaporDB;
 use crate::api::routes;
+use crate::config::ServerConfig;
 
 pub async fn run_server() -> Result<(), Box<dyn std::error::Error>> {
+    let config = ServerConfig::from_env()?;
     let db = Arc::new(Mutex::new(
-        VaporDB::new_with_persistence("vapordb.wal")?,
+        VaporDB::open("vapordb.wal", config.db_options())?,
     ));
 
     let api = routes(db);
@@ -1,17 +1,41 @@
+use core::clock::MockClock;
 use core::command::Command;
 use core::db::VaporDB;
+use core::options::VaporDBOptions;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
+use tempfile::TempDir;
+
+// Each test gets its own directory, as a MANIFEST must not be shared
+fn setup_db() -> (TempDir, Arc<Mutex<VaporDB>>) {
+    let dir = tempfile::tempdir().unwrap();
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        ..Default::default()
+    };
+    let wal_path = dir.path().join("test_adv.wal");
+    let db = VaporDB::open(wal_path.to_str().unwrap(), options).unwrap();
+    (dir, Arc::new(Mutex::new(db)))
+}
 
-fn setup_db() -> Arc<Mutex<VaporDB>> {
-    let db = VaporDB::new_with_persistence("test_adv.wal").unwrap();
-    Arc::new(Mutex::new(db))
+// Like `setup_db`, but time only moves when the returned clock is advanced
+fn setup_db_with_clock() -> (TempDir, Arc<Mutex<VaporDB>>, Arc<MockClock>) {
+    let dir = tempfile::tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let options = VaporDBOptions {
+        sst_dir: dir.path().join("sstables"),
+        clock: clock.clone(),
+        ..Default::default()
+    };
+    let wal_path = dir.path().join("test_adv.wal");
+    let db = VaporDB::open(wal_path.to_str().unwrap(), options).unwrap();
+    (dir, Arc::new(Mutex::new(db)), clock)
 }
 
 #[test]
 fn test_list_lrange_bounds() {
-    let db = setup_db();
+    let (_dir, db) = setup_db();
     let mut db = db.lock().unwrap();
 
     db.execute(Command::RPush("list".into(), "1".into())).unwrap();
@@ -29,7 +53,7 @@ fn test_list_lrange_bounds() {
 
 #[test]
 fn test_lrange_empty_and_out_of_bounds() {
-    let db = setup_db();
+    let (_dir, db) = setup_db();
     let mut db = db.lock().unwrap();
 
     let range = db.execute(Command::LRange("nosuch".into(), 0, 5)).unwrap();
@@ -43,32 +67,33 @@ fn test_lrange_empty_and_out_of_bounds() {
 
 #[test]
 fn test_ttl_update_behavior() {
-    let db = setup_db();
-    VaporDB::start_ttl_daemon(db.clone());
+    let (_dir, db, clock) = setup_db_with_clock();
 
     {
         let mut db = db.lock().unwrap();
         db.set_with_expiration("expire".into(), "short".into(), 1).unwrap();
-        thread::sleep(Duration::from_millis(500));
+        clock.advance(Duration::from_millis(500));
         db.set_with_expiration("expire".into(), "long".into(), 2).unwrap();
     }
 
-    thread::sleep(Duration::from_secs(1));
+    clock.advance(Duration::from_secs(1));
 
     {
         let mut db = db.lock().unwrap();
+        assert_eq!(db.clean_expired_keys().unwrap(), 0);
         let val = db.execute(Command::Get("expire".into())).unwrap();
         assert_eq!(val, Some("long".into()));
     }
 
-    thread::sleep(Duration::from_secs(2));
+    clock.advance(Duration::from_secs(1));
     let mut db = db.lock().unwrap();
+    assert_eq!(db.clean_expired_keys().unwrap(), 1);
     assert_eq!(db.execute(Command::Get("expire".into())).unwrap(), None);
 }
 
 #[test]
 fn test_concurrent_set_get_integrity() {
-    let db = setup_db();
+    let (_dir, db) = setup_db();
     let db_arc1 = db.clone();
     let db_arc2 = db.clone();
 
@@ -100,7 +125,7 @@ fn test_concurrent_set_get_integrity() {
 
 #[test]
 fn test_list_behavior_and_range() {
-    let db = setup_db();
+    let (_dir, db) = setup_db();
     let mut db = db.lock().unwrap();
 
     db.execute(Command::LPush("list".into(), "a".into())).unwrap();
@@ -118,7 +143,7 @@ fn test_list_behavior_and_range() {
 
 #[test]
 fn test_hash_multiple_fields() {
-    let db = setup_db();
+    let (_dir, db) = setup_db();
     let mut db = db.lock().unwrap();
 
     db.execute(Command::HSet("multi".into(), "f1".into(), "v1".into())).unwrap();
@@ -136,7 +161,7 @@ fn test_hash_multiple_fields() {
 
 #[test]
 fn test_set_uniqueness_and_membership() {
-    let db = setup_db();
+    let (_dir, db) = setup_db();
     let mut db = db.lock().unwrap();
 
     db.execute(Command::SAdd("set".into(), "a".into())).unwrap();
@@ -159,8 +184,7 @@ fn test_set_uniqueness_and_membership() {
 
 #[test]
 fn test_string_ttl_expiration_hard() {
-    let db = setup_db();
-    VaporDB::start_ttl_daemon(db.clone());
+    let (_dir, db, clock) = setup_db_with_clock();
 
     {
         let mut db = db.lock().unwrap();
@@ -168,10 +192,11 @@ fn test_string_ttl_expiration_hard() {
         assert_eq!(db.execute(Command::Get("ttlkey".into())).unwrap(), Some("will_expire".into()));
     }
 
-    thread::sleep(Duration::from_secs(2));
+    clock.advance(Duration::from_secs(1));
 
     {
         let mut db = db.lock().unwrap();
+        assert_eq!(db.clean_expired_keys().unwrap(), 1);
         let val = db.execute(Command::Get("ttlkey".into())).unwrap();
         assert_eq!(val, None);
     }
@@ -179,7 +204,7 @@ fn test_string_ttl_expiration_hard() {
 
 #[test]
 fn test_set_removal_of_nonexistent_element() {
-    let db = setup_db();
+    let (_dir, db) = setup_db();
     let mut db = db.lock().unwrap();
 
     db.execute(Command::SAdd("settest".into(), "x".into())).unwrap();
@@ -193,7 +218,7 @@ fn test_set_removal_of_nonexistent_element() {
 
 #[test]
 fn test_list_pop_from_empty() {
-    let db = setup_db();
+    let (_dir, db) = setup_db();
     let mut db = db.lock().unwrap();
 
     let left = db.execute(Command::LPop("emptylist".into())).unwrap();
@@ -205,15 +230,14 @@ fn test_list_pop_from_empty() {
 
 #[test]
 fn test_ttl_reset_after_expired() {
-    let db = setup_db();
-    VaporDB::start_ttl_daemon(db.clone());
+    let (_dir, db, clock) = setup_db_with_clock();
 
     {
         let mut db = db.lock().unwrap();
         db.set_with_expiration("resettl".into(), "one".into(), 1).unwrap();
     }
 
-    thread::sleep(Duration::from_secs(2));
+    clock.advance(Duration::from_secs(1));
 
     {
         let mut db = db.lock().unwrap();
@@ -221,10 +245,479 @@ fn test_ttl_reset_after_expired() {
         db.set_with_expiration("resettl".into(), "two".into(), 2).unwrap();
     }
 
-    thread::sleep(Duration::from_secs(1));
+    clock.advance(Duration::from_millis(1999));
 
     {
         let mut db = db.lock().unwrap();
         assert_eq!(db.execute(Command::Get("resettl".into())).unwrap(), Some("two".into()));
     }
+
+    clock.advance(Duration::from_millis(1));
+
+    {
+        let mut db = db.lock().unwrap();
+        assert_eq!(db.execute(Command::Get("resettl".into())).unwrap(), None);
+    }
+}
+
+#[test]
+fn test_changes_endpoint_pages_through_writes_with_a_cursor() {
+    let (_dir, db) = setup_db();
+    {
+        let mut db = db.lock().unwrap();
+        db.execute(Command::Set("a".into(), "1".into())).unwrap();
+        db.execute(Command::SAdd("s".into(), "x".into())).unwrap();
+        db.execute(Command::Del("a".into())).unwrap();
+    }
+    // The crate named `core` trips up `#[tokio::test]`, so the runtime is
+    // built by hand
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let api = server::api::routes(db.clone());
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/changes?from=0&limit=2")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        let records = body["records"].as_array().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["entry"]["Put"][0], "a");
+        assert_eq!(records[1]["entry"]["Put"][0], "s");
+
+        let cursor = body["cursor"].as_u64().unwrap();
+        let res = warp::test::request()
+            .method("GET")
+            .path(&format!("/changes?from={cursor}"))
+            .reply(&api)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["records"][0]["entry"]["Del"], "a");
+        assert_eq!(body["records"].as_array().unwrap().len(), 1);
+
+        // Caught up: an empty page that keeps the cursor where it was
+        let next = body["cursor"].as_u64().unwrap();
+        let res = warp::test::request()
+            .method("GET")
+            .path(&format!("/changes?from={next}&wait_ms=10"))
+            .reply(&api)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert!(body["records"].as_array().unwrap().is_empty());
+        assert_eq!(body["cursor"].as_u64().unwrap(), next);
+
+        // The WebSocket pushes the same records, one per message
+        let mut client = warp::test::ws()
+            .path(&format!("/changes/ws?from={cursor}"))
+            .handshake(api)
+            .await
+            .unwrap();
+        let message = client.recv().await.unwrap();
+        let record: serde_json::Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        assert_eq!(record["entry"]["Del"], "a");
+    });
+}
+
+#[test]
+fn test_events_websocket_pushes_the_subscribed_classes() {
+    let (_dir, db) = setup_db();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let api = server::api::routes(db.clone());
+        let mut client = warp::test::ws()
+            .path("/events/ws?classes=hash,expired")
+            .handshake(api.clone())
+            .await
+            .unwrap();
+
+        {
+            let mut db = db.lock().unwrap();
+            db.execute(Command::Set("s".into(), "v".into())).unwrap();
+            db.execute(Command::HSet("h".into(), "f".into(), "v".into())).unwrap();
+            // Events wait for the write to be synced, which EverySec would
+            // otherwise leave to its background tick
+            db.group_commit().sync().unwrap();
+        }
+        // An idle socket is pinged, so skip anything that is not the event
+        let message = loop {
+            let message = client.recv().await.unwrap();
+            if message.is_text() {
+                break message;
+            }
+        };
+        let event: serde_json::Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        assert_eq!(event, serde_json::json!({ "kind": "hset", "key": "h" }));
+
+        let unknown = warp::test::ws()
+            .path("/events/ws?classes=bogus")
+            .handshake(api)
+            .await;
+        assert!(unknown.is_err());
+    });
+}
+
+#[test]
+fn test_sorted_set_commands_over_http() {
+    let (_dir, db) = setup_db();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let api = server::api::routes(db.clone());
+        let cmd = |body: serde_json::Value| {
+            let api = api.clone();
+            async move {
+                let res = warp::test::request()
+                    .method("POST")
+                    .path("/cmd")
+                    .json(&body)
+                    .reply(&api)
+                    .await;
+                let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+                (res.status(), body["result"].clone())
+            }
+        };
+
+        // Scores are strings so that infinities make it through JSON
+        let (status, added) = cmd(serde_json::json!({
+            "cmd": "zadd",
+            "key": "jobs",
+            "members": [["-inf", "urgent"], ["5", "later"], ["1.5", "soon"]],
+        }))
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(added, "3");
+
+        let (_, range) = cmd(serde_json::json!({
+            "cmd": "zrange",
+            "key": "jobs",
+            "by": {"score": ["-inf", "(5"]},
+            "with_scores": true,
+        }))
+        .await;
+        assert_eq!(range, r#"[["urgent","-inf"],["soon","1.5"]]"#);
+
+        let (_, popped) = cmd(serde_json::json!({"cmd": "zpopmax", "key": "jobs"})).await;
+        assert_eq!(popped, r#"[["later","5"]]"#);
+
+        let (status, _) = cmd(serde_json::json!({
+            "cmd": "zadd",
+            "key": "jobs",
+            "members": [["1", "a"]],
+            "flags": {"nx": true, "xx": true},
+        }))
+        .await;
+        assert_eq!(status, 400);
+
+        // Malformed input is the client's mistake, not a server error
+        let (status, _) = cmd(serde_json::json!({
+            "cmd": "zadd",
+            "key": "jobs",
+            "members": [["lots", "a"]],
+        }))
+        .await;
+        assert_eq!(status, 400);
+        let (status, _) = cmd(serde_json::json!({
+            "cmd": "zrange",
+            "key": "jobs",
+            "by": {"index": [0, -1]},
+            "limit": [0, 1],
+        }))
+        .await;
+        assert_eq!(status, 400);
+    });
+}
+
+#[test]
+fn test_stream_commands_over_http() {
+    let (_dir, db) = setup_db();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let api = server::api::routes(db.clone());
+        let cmd = |body: serde_json::Value| {
+            let api = api.clone();
+            async move {
+                let res = warp::test::request()
+                    .method("POST")
+                    .path("/cmd")
+                    .json(&body)
+                    .reply(&api)
+                    .await;
+                let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+                (res.status(), body["result"].clone())
+            }
+        };
+
+        let (status, id) = cmd(serde_json::json!({
+            "cmd": "xadd",
+            "key": "orders",
+            "id": "5-1",
+            "fields": [["item", "tea"]],
+        }))
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(id, "5-1");
+        cmd(serde_json::json!({
+            "cmd": "xadd",
+            "key": "orders",
+            "id": "7-0",
+            "fields": [["item", "cake"]],
+        }))
+        .await;
+
+        let (_, len) = cmd(serde_json::json!({"cmd": "xlen", "key": "orders"})).await;
+        assert_eq!(len, "2");
+        let (_, range) = cmd(serde_json::json!({
+            "cmd": "xrange",
+            "key": "orders",
+            "start": "-",
+            "end": "+",
+            "rev": true,
+            "count": 1,
+        }))
+        .await;
+        let range: serde_json::Value = serde_json::from_str(range.as_str().unwrap()).unwrap();
+        assert_eq!(range[0]["id"], "7-0");
+
+        let (status, _) = cmd(serde_json::json!({
+            "cmd": "xgroupcreate",
+            "key": "orders",
+            "group": "kitchen",
+            "start": "0",
+        }))
+        .await;
+        assert_eq!(status, 200);
+        let (_, read) = cmd(serde_json::json!({
+            "cmd": "xreadgroup",
+            "key": "orders",
+            "group": "kitchen",
+            "consumer": "alice",
+            "count": 1,
+        }))
+        .await;
+        let read: serde_json::Value = serde_json::from_str(read.as_str().unwrap()).unwrap();
+        assert_eq!(read[0]["id"], "5-1");
+
+        let (_, claimed) = cmd(serde_json::json!({
+            "cmd": "xclaim",
+            "key": "orders",
+            "group": "kitchen",
+            "consumer": "bob",
+            "min_idle_ms": 0,
+            "ids": ["5-1"],
+        }))
+        .await;
+        let claimed: serde_json::Value = serde_json::from_str(claimed.as_str().unwrap()).unwrap();
+        assert_eq!(claimed[0]["id"], "5-1");
+        let (_, acked) = cmd(serde_json::json!({
+            "cmd": "xack",
+            "key": "orders",
+            "group": "kitchen",
+            "ids": ["5-1"],
+        }))
+        .await;
+        assert_eq!(acked, "1");
+        let (_, pending) = cmd(serde_json::json!({
+            "cmd": "xpending",
+            "key": "orders",
+            "group": "kitchen",
+        }))
+        .await;
+        assert_eq!(pending, "[]");
+
+        // Each of these is the client's mistake, not a server error
+        for body in [
+            serde_json::json!({"cmd": "xadd", "key": "orders", "id": "6-0", "fields": [["item", "pie"]]}),
+            serde_json::json!({"cmd": "xadd", "key": "orders", "id": "nine", "fields": [["item", "pie"]]}),
+            serde_json::json!({"cmd": "xadd", "key": "orders", "fields": []}),
+            serde_json::json!({"cmd": "xrange", "key": "orders", "start": "x", "end": "+"}),
+            serde_json::json!({"cmd": "xgroupcreate", "key": "orders", "group": "kitchen"}),
+            serde_json::json!({"cmd": "xgroupcreate", "key": "missing", "group": "kitchen"}),
+            serde_json::json!({"cmd": "xack", "key": "orders", "group": "bar", "ids": ["5-1"]}),
+            serde_json::json!({
+                "cmd": "xclaim",
+                "key": "missing",
+                "group": "kitchen",
+                "consumer": "bob",
+                "min_idle_ms": 0,
+                "ids": ["5-1"],
+            }),
+        ] {
+            let (status, _) = cmd(body.clone()).await;
+            assert_eq!(status, 400, "{body}");
+        }
+    });
+}
+
+#[test]
+fn test_concurrent_increments_over_http_are_not_lost() {
+    let (_dir, db) = setup_db();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let api = server::api::routes(db.clone());
+        let cmd = |body: serde_json::Value| {
+            let api = api.clone();
+            async move {
+                let res = warp::test::request()
+                    .method("POST")
+                    .path("/cmd")
+                    .json(&body)
+                    .reply(&api)
+                    .await;
+                let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+                (res.status(), body["result"].clone())
+            }
+        };
+
+        // Each read-modify-write happens under the database lock, so no
+        // increment overwrites another
+        let increments = (0..50).map(|i| {
+            let body = if i % 2 == 0 {
+                serde_json::json!({"cmd": "incr", "key": "hits"})
+            } else {
+                serde_json::json!({"cmd": "hincrby", "key": "stats", "field": "hits", "by": 2})
+            };
+            tokio::spawn(cmd(body))
+        });
+        for increment in futures_util::future::join_all(increments).await {
+            assert_eq!(increment.unwrap().0, 200);
+        }
+        let (_, hits) = cmd(serde_json::json!({"cmd": "get", "key": "hits"})).await;
+        assert_eq!(hits, "25");
+        let (_, hits) =
+            cmd(serde_json::json!({"cmd": "hget", "key": "stats", "field": "hits"})).await;
+        assert_eq!(hits, "50");
+
+        let (_, total) =
+            cmd(serde_json::json!({"cmd": "incrbyfloat", "key": "hits", "by": -0.5})).await;
+        assert_eq!(total, "24.5");
+        let (status, _) = cmd(serde_json::json!({"cmd": "incr", "key": "hits"})).await;
+        assert_eq!(status, 400);
+    });
+}
+
+#[test]
+fn test_binary_routes_serve_sorted_set_commands() {
+    let (_dir, db) = setup_db();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let app = server::api::app(db.clone());
+        let cmd = |body: serde_json::Value| {
+            let app = app.clone();
+            async move {
+                let res = warp::test::request()
+                    .method("POST")
+                    .path("/cmd")
+                    .json(&body)
+                    .reply(&app)
+                    .await;
+                let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+                (res.status(), body["result"].clone())
+            }
+        };
+
+        let (status, added) = cmd(serde_json::json!({
+            "cmd": "zadd",
+            "key": "board",
+            "members": [["10", "alice"], ["20", "bob"]],
+        }))
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(added, "2");
+        let (_, card) = cmd(serde_json::json!({"cmd": "zcard", "key": "board"})).await;
+        assert_eq!(card, "2");
+        let (_, score) =
+            cmd(serde_json::json!({"cmd": "zscore", "key": "board", "member": "bob"})).await;
+        assert_eq!(score, "20");
+    });
+}
+
+#[test]
+fn test_binary_routes_serve_counters() {
+    let (_dir, db) = setup_db();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let app = server::api::app(db.clone());
+        let cmd = |body: serde_json::Value| {
+            let app = app.clone();
+            async move {
+                let res = warp::test::request()
+                    .method("POST")
+                    .path("/cmd")
+                    .json(&body)
+                    .reply(&app)
+                    .await;
+                let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+                (res.status(), body["result"].clone())
+            }
+        };
+
+        let (_, hits) = cmd(serde_json::json!({"cmd": "incr", "key": "hits"})).await;
+        assert_eq!(hits, "1");
+        let (_, hits) = cmd(serde_json::json!({"cmd": "incrby", "key": "hits", "by": 9})).await;
+        assert_eq!(hits, "10");
+        let (_, hits) = cmd(serde_json::json!({"cmd": "decr", "key": "hits"})).await;
+        assert_eq!(hits, "9");
+        let (_, hits) =
+            cmd(serde_json::json!({"cmd": "incrbyfloat", "key": "hits", "by": 0.5})).await;
+        assert_eq!(hits, "9.5");
+        let (_, runs) = cmd(serde_json::json!({
+            "cmd": "hincrby", "key": "stats", "field": "runs", "by": 3,
+        }))
+        .await;
+        assert_eq!(runs, "3");
+        let (_, load) = cmd(serde_json::json!({
+            "cmd": "hincrbyfloat", "key": "stats", "field": "load", "by": 1.25,
+        }))
+        .await;
+        assert_eq!(load, "1.25");
+    });
+}
+
+#[test]
+fn test_binary_routes_serve_expire_at_with_conditions() {
+    let (_dir, db, _clock) = setup_db_with_clock();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let app = server::api::app(db.clone());
+        let cmd = |body: serde_json::Value| {
+            let app = app.clone();
+            async move {
+                let res = warp::test::request()
+                    .method("POST")
+                    .path("/cmd")
+                    .json(&body)
+                    .reply(&app)
+                    .await;
+                let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+                (res.status(), body["result"].clone())
+            }
+        };
+
+        // The clock stands at 1,000 seconds
+        cmd(serde_json::json!({"cmd": "set", "key": "session", "value": "v"})).await;
+        let (status, set) = cmd(serde_json::json!({
+            "cmd": "expireat", "key": "session", "timestamp": 1_010, "condition": "nx",
+        }))
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(set, "1");
+        let (_, set) = cmd(serde_json::json!({
+            "cmd": "expireat", "key": "session", "timestamp": 1_020, "condition": "nx",
+        }))
+        .await;
+        assert_eq!(set, "0");
+        let (_, set) = cmd(serde_json::json!({
+            "cmd": "pexpireat", "key": "session", "timestamp_ms": 1_005_000, "condition": "gt",
+        }))
+        .await;
+        assert_eq!(set, "0");
+        let (_, set) = cmd(serde_json::json!({
+            "cmd": "pexpireat", "key": "session", "timestamp_ms": 1_005_000, "condition": "lt",
+        }))
+        .await;
+        assert_eq!(set, "1");
+        let (_, ttl) = cmd(serde_json::json!({"cmd": "pttl", "key": "session"})).await;
+        assert_eq!(ttl, "5000");
+    });
 }